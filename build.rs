@@ -0,0 +1,30 @@
+//! Embeds information about the build into the binary, for `mix version --verbose`.
+
+use std::env;
+use std::process::Command;
+
+/// Runs the command and returns the first line of its output, if it succeeds.
+fn output(cmd: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(cmd).args(args).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    String::from_utf8(out.stdout)
+        .ok()?
+        .lines()
+        .next()
+        .map(|l| l.trim().to_owned())
+        .filter(|l| !l.is_empty())
+}
+
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_owned());
+    let git = output("git", &["describe", "--always", "--dirty"])
+        .unwrap_or_else(|| "unknown".to_owned());
+    let profile = env::var("PROFILE").unwrap_or_else(|_| "unknown".to_owned());
+    println!("cargo:rustc-env=MIX_BUILD_RUSTC={}", rustc);
+    println!("cargo:rustc-env=MIX_BUILD_GIT={}", git);
+    println!("cargo:rustc-env=MIX_BUILD_PROFILE={}", profile);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
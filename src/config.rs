@@ -1,56 +1,423 @@
 use std::collections::HashMap;
 use std::env;
+use std::fmt::{Formatter, Result as FmtResult};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use config::{Config, File};
-use failure::Error;
+use failure::{bail, Error};
 use log::{debug, trace};
+use serde::de::{Deserialize, Deserializer, Error as DeError, MapAccess, Visitor};
+use serde::de::value::MapAccessDeserializer;
 use serde_derive::Deserialize;
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
-struct CmdLine {
+use crate::redact;
+use crate::units::{DurationSpec, Fraction};
+
+#[derive(Clone, Debug, StructOpt)]
+crate enum Command {
+    /// Turn the mailboxes of a muttrc into storage.meta configuration.
+    #[structopt(name = "import-mutt")]
+    ImportMutt {
+        #[structopt(parse(from_os_str))]
+        muttrc: PathBuf,
+        /// Write the generated configuration here instead of the standard output.
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Convert a mailbox into another format.
+    #[structopt(name = "convert")]
+    Convert {
+        /// Convert a maildir into an mbox.
+        #[structopt(long = "to-mbox")]
+        to_mbox: bool,
+        /// Include the messages marked as trashed.
+        #[structopt(long = "include-trashed")]
+        include_trashed: bool,
+        /// Overwrite the target if it exists.
+        #[structopt(long = "force")]
+        force: bool,
+        /// Gzip the resulting mbox.
+        #[structopt(long = "compress")]
+        compress: bool,
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+        #[structopt(parse(from_os_str))]
+        target: PathBuf,
+    },
+    /// Send a command to the running instance and print the answer.
+    #[structopt(name = "query")]
+    Query {
+        /// Connect to this socket instead of the one from the configuration.
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
+        /// The command and its arguments, eg. `list` or `counts INBOX`.
+        #[structopt(raw(required = "true"))]
+        command: Vec<String>,
+    },
+    /// Print the version.
+    #[structopt(name = "version")]
+    Version {
+        /// Include the details of how the binary was built.
+        #[structopt(short = "v", long = "verbose")]
+        verbose: bool,
+    },
+    /// Check the configuration and the environment and report problems.
+    #[structopt(name = "doctor")]
+    Doctor {
+        /// Print the results as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+}
+
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(raw(after_help = "&**crate::error::EXIT_HELP"))]
+crate struct CmdLine {
+    /// The configuration file. If not given, it is looked for in $XDG_CONFIG_HOME/mix/config.toml,
+    /// ~/.config/mix/config.toml and /etc/mix/config.toml.
     #[structopt(parse(from_os_str))]
-    config: PathBuf,
+    config: Option<PathBuf>,
+    /// Rescan every mailbox from scratch, ignoring anything cached about it.
+    #[structopt(long = "full")]
+    full: bool,
+    /// Explain what the scan would do with this path instead of running.
+    #[structopt(long = "explain", parse(from_os_str))]
+    crate explain: Option<PathBuf>,
+    #[structopt(subcommand)]
+    crate cmd: Option<Command>,
+}
+
+/// Implements Deserialize of a plain enum from its name.
+///
+/// The config crate can't deserialize enums from plain strings, so this is done by hand.
+macro_rules! config_enum {
+    ($name: ident { $($str: expr => $variant: ident,)* }) => {
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                const VARIANTS: &[&str] = &[$($str),*];
+                match String::deserialize(deserializer)?.as_str() {
+                    $($str => Ok($name::$variant),)*
+                    other => Err(D::Error::unknown_variant(other, VARIANTS)),
+                }
+            }
+        }
+    }
+}
+
+/// An environment variable holding a path, if it's set to something.
+fn env_path(name: &str) -> Option<PathBuf> {
+    env::var_os(name)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
 }
 
 fn default_socket() -> PathBuf {
-    let home = env::var("HOME")
-        .unwrap_or_else(|_| String::new());
-    PathBuf::from(home).join("mix-socket")
+    match env_path("XDG_RUNTIME_DIR") {
+        Some(runtime) => runtime.join("mix.sock"),
+        None => env_path("HOME").unwrap_or_default().join("mix-socket"),
+    }
+}
+
+/// Where to look for the configuration if it's not given on the command line, in this order.
+fn default_config_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(config) = env_path("XDG_CONFIG_HOME") {
+        paths.push(config.join("mix").join("config.toml"));
+    }
+    if let Some(home) = env_path("HOME") {
+        paths.push(home.join(".config").join("mix").join("config.toml"));
+    }
+    paths.push(PathBuf::from("/etc/mix/config.toml"));
+    paths
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_error_fraction() -> Fraction {
+    Fraction(0.5)
+}
+
+fn default_watch_debounce() -> DurationSpec {
+    DurationSpec(Duration::from_millis(500))
+}
+
+fn default_poll_interval() -> DurationSpec {
+    DurationSpec(Duration::from_secs(0))
 }
 
-#[derive(Debug, Deserialize)]
+fn default_workers() -> usize {
+    4
+}
+
+fn default_shutdown_grace() -> DurationSpec {
+    DurationSpec(Duration::from_secs(5))
+}
+
+fn default_slow_callback() -> DurationSpec {
+    DurationSpec(Duration::from_secs(1))
+}
+
+#[derive(Clone, Debug, Deserialize)]
 crate struct StorageMeta {
+    crate name: Option<String>,
     crate shortcut: Option<char>,
     #[serde(default)]
     crate prio: u8,
+    /// Leave the mailbox out of the export files.
+    #[serde(default)]
+    crate hidden: bool,
+}
+
+/// A directory to look for mailboxes in.
+///
+/// Written either as a bare path or as a table with `path` and `max_depth`. TOML doesn't allow
+/// mixing the two in one array.
+#[derive(Clone, Debug)]
+crate struct SearchRoot {
+    crate path: PathBuf,
+    /// Overrides storage.max_depth for this root.
+    crate max_depth: Option<usize>,
+}
+
+impl<'de> Deserialize<'de> for SearchRoot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Table {
+            path: PathBuf,
+            #[serde(default)]
+            max_depth: Option<usize>,
+        }
+        struct V;
+        impl<'de> Visitor<'de> for V {
+            type Value = SearchRoot;
+            fn expecting(&self, fmt: &mut Formatter) -> FmtResult {
+                fmt.write_str("a path or a table with path and max_depth")
+            }
+            fn visit_str<E: DeError>(self, v: &str) -> Result<SearchRoot, E> {
+                Ok(SearchRoot {
+                    path: PathBuf::from(v),
+                    max_depth: None,
+                })
+            }
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<SearchRoot, A::Error> {
+                let table = Table::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(SearchRoot {
+                    path: table.path,
+                    max_depth: table.max_depth,
+                })
+            }
+        }
+        deserializer.deserialize_any(V)
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 crate struct Storage {
-    crate search: Vec<PathBuf>,
+    crate search: Vec<SearchRoot>,
+    /// How deep below the search roots to look, 1 being only their direct children. Unlimited
+    /// if not set.
+    #[serde(default)]
+    crate max_depth: Option<usize>,
+    /// Glob patterns of what not to look into.
+    ///
+    /// A pattern with a slash is matched against the path relative to the search root (eg.
+    /// `**/spam-training`), one without against the name at any depth (eg. `*.bak`).
+    #[serde(default)]
+    crate exclude: Vec<String>,
+    /// Look into directories starting with a dot too. The search roots themselves are always
+    /// looked into.
+    #[serde(default)]
+    crate scan_hidden: bool,
+    /// Names of hidden directories to look into even without scan_hidden (eg. `.mail`).
+    #[serde(default)]
+    crate allow_hidden: Vec<String>,
+    /// Name, shortcut, prio and hidden flag of mailboxes, by their path.
+    ///
+    /// The path is either absolute or relative to a search root. Applied before the lua
+    /// callbacks, which may override it.
     #[serde(default)]
     crate meta: HashMap<PathBuf, StorageMeta>,
+    /// Fraction of erroring entries above which a whole search root is considered failed.
+    #[serde(default = "default_error_fraction")]
+    crate max_error_fraction: Fraction,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+crate struct Log {
+    /// Replace mailbox paths, names and message contents in the logs by opaque tokens.
+    #[serde(default)]
+    crate redact: bool,
+}
+
+/// How lua scripts see each other's globals.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+crate enum ScriptIsolation {
+    /// All the scripts share one set of globals.
+    Shared,
+    /// Each script has its own globals (the standard library and our API are still shared).
+    PerFile,
+}
+
+impl Default for ScriptIsolation {
+    fn default() -> Self {
+        ScriptIsolation::Shared
+    }
+}
+
+config_enum!(ScriptIsolation {
+    "shared" => Shared,
+    "per-file" => PerFile,
+});
+
+/// What to do with a mailbox found inside another, already registered one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+crate enum NestedMailboxes {
+    /// Skip it silently.
+    Ignore,
+    /// Register it as a mailbox of its own.
+    Register,
+    /// Skip it with a warning.
+    Warn,
 }
 
-#[derive(Debug, Deserialize)]
+impl Default for NestedMailboxes {
+    fn default() -> Self {
+        NestedMailboxes::Warn
+    }
+}
+
+config_enum!(NestedMailboxes {
+    "ignore" => Ignore,
+    "register" => Register,
+    "warn" => Warn,
+});
+
+/// What to do when two mailboxes end up with the same shortcut.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+crate enum ShortcutConflicts {
+    /// Refuse the configuration.
+    Fail,
+    /// Keep it for the mailbox with the higher prio (the first found on a tie), with a warning.
+    Drop,
+}
+
+impl Default for ShortcutConflicts {
+    fn default() -> Self {
+        ShortcutConflicts::Fail
+    }
+}
+
+config_enum!(ShortcutConflicts {
+    "fail" => Fail,
+    "drop" => Drop,
+});
+
+/// Format of a file the mailboxes are exported into.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+crate enum ExportFormat {
+    /// A mutt `mailboxes` command.
+    MuttMailboxes,
+    /// One path per line.
+    PathList,
+}
+
+config_enum!(ExportFormat {
+    "mutt_mailboxes" => MuttMailboxes,
+    "path_list" => PathList,
+});
+
+#[derive(Clone, Debug, Deserialize)]
 crate struct Cfg {
     #[serde(default = "default_socket")]
     crate socket: PathBuf,
     crate storage: Storage,
     #[serde(default)]
     crate scripts: Vec<PathBuf>,
+    #[serde(default)]
+    crate script_isolation: ScriptIsolation,
+    /// A single lua callback running longer than this is warned about.
+    #[serde(default = "default_slow_callback")]
+    crate slow_callback: DurationSpec,
+    #[serde(default)]
+    crate nested_mailboxes: NestedMailboxes,
+    #[serde(default)]
+    crate shortcut_conflicts: ShortcutConflicts,
+    /// Files to write the list of mailboxes into after each scan, by their format.
+    #[serde(default)]
+    crate export: HashMap<ExportFormat, PathBuf>,
+    #[serde(default)]
+    crate require_all_roots: bool,
+    /// Watch the mailboxes for changes and rescan them.
+    #[serde(default = "default_true")]
+    crate watch: bool,
+    /// How long to wait for more changes of a mailbox before rescanning it.
+    #[serde(default = "default_watch_debounce")]
+    crate watch_debounce: DurationSpec,
+    /// How often to rescan each mailbox even without noticing a change. Zero means never.
+    ///
+    /// Scripts may set a different interval for individual mailboxes.
+    #[serde(default = "default_poll_interval")]
+    crate poll_interval: DurationSpec,
+    /// How many threads rescan mailboxes in parallel.
+    #[serde(default = "default_workers")]
+    crate workers: usize,
+    /// How long the running rescans may take to finish on shutdown before they are cancelled.
+    #[serde(default = "default_shutdown_grace")]
+    crate shutdown_grace: DurationSpec,
+    /// Match mailbox names ignoring case and diacritics.
+    #[serde(default = "default_true")]
+    crate normalize_names: bool,
+    #[serde(default)]
+    crate log: Log,
+    #[serde(skip)]
+    crate full_rescan: bool,
 }
 
-crate fn load() -> Result<Cfg, Error> {
-    trace!("Loading");
+crate fn cmd_line() -> CmdLine {
     let cmd_line = CmdLine::from_args();
     debug!("Command line: {:?}", cmd_line);
+    cmd_line
+}
+
+crate fn load(cmd_line: &CmdLine) -> Result<Cfg, Error> {
+    trace!("Loading");
+    let path = match cmd_line.config {
+        Some(ref path) => path.clone(),
+        None => {
+            let candidates = default_config_paths();
+            match candidates.iter().find(|path| path.is_file()) {
+                Some(path) => path.clone(),
+                None => {
+                    let tried = candidates
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>();
+                    bail!("No configuration file given and none found in {}", tried.join(", "));
+                }
+            }
+        }
+    };
 
     let mut cfg = Config::new();
-    cfg.merge(File::from(cmd_line.config))?;
-    let cfg = cfg.try_into()?;
-    debug!("Configuration: {:?}", cfg);
+    cfg.merge(File::from(path.as_path()))?;
+    let mut cfg: Cfg = cfg.try_into()?;
+    if !cfg!(feature = "lua") && !cfg.scripts.is_empty() {
+        bail!("Lua scripts are configured, but mix was built without the lua feature");
+    }
+    cfg.full_rescan = cmd_line.full;
+    redact::configure(&cfg);
+    debug!("Configuration from {}: {:?}", path.display(), cfg);
     Ok(cfg)
 }
+
+/// Builds the configuration from a TOML snippet instead of a file.
+#[cfg(test)]
+crate fn parse(toml: &str) -> Cfg {
+    let mut cfg = Config::new();
+    cfg.merge(File::from_str(toml, config::FileFormat::Toml)).unwrap();
+    cfg.try_into().unwrap()
+}
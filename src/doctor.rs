@@ -0,0 +1,246 @@
+//! The `doctor` subcommand, checking the environment mix is going to run in.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{self, OpenOptions};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process;
+
+use failure::{bail, Error};
+use serde_derive::Serialize;
+
+use crate::config::{self, Cfg, CmdLine};
+use crate::mailbox;
+
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Display for Status {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let s = match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        fmt.write_str(s)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    check: String,
+    status: Status,
+    message: String,
+    remedy: Option<&'static str>,
+}
+
+impl Check {
+    fn pass<C: Into<String>, M: Into<String>>(check: C, message: M) -> Self {
+        Check {
+            check: check.into(),
+            status: Status::Pass,
+            message: message.into(),
+            remedy: None,
+        }
+    }
+    fn problem<C, M>(check: C, status: Status, message: M, remedy: &'static str) -> Self
+    where
+        C: Into<String>,
+        M: Into<String>,
+    {
+        Check {
+            check: check.into(),
+            status,
+            message: message.into(),
+            remedy: Some(remedy),
+        }
+    }
+}
+
+/// Formats the error with all its causes on one line.
+fn chain(e: &Error) -> String {
+    e.iter_chain()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+fn check_root(cfg: &Cfg, root: &Path) -> Check {
+    let name = format!("search root {}", root.display());
+    // A missing root is only fatal if the daemon would refuse to run without it.
+    let status = if cfg.require_all_roots {
+        Status::Fail
+    } else {
+        Status::Warn
+    };
+    match fs::read_dir(root) {
+        Ok(_) => Check::pass(name, "exists and is readable"),
+        Err(e) => {
+            Check::problem(name, status, e.to_string(), "Create the directory, fix its \
+                           permissions or remove it from storage.search")
+        }
+    }
+}
+
+fn check_socket_dir(cfg: &Cfg) -> Check {
+    let name = "socket directory";
+    let dir = match cfg.socket.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    // The only reliable way to know if we can create the socket there is to try creating
+    // something.
+    let probe = dir.join(format!(".mix-doctor-{}", process::id()));
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe);
+    match result {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Check::pass(name, format!("{} is writable", dir.display()))
+        }
+        Err(e) => {
+            Check::problem(name, Status::Fail, format!("{}: {}", dir.display(), e),
+                           "Create the directory or point the socket option elsewhere")
+        }
+    }
+}
+
+fn check_socket(cfg: &Cfg) -> Check {
+    let name = "socket";
+    let path = &cfg.socket;
+    if fs::symlink_metadata(path).is_err() {
+        return Check::pass(name, format!("no instance on {}", path.display()));
+    }
+    // The same test the daemon does on startup
+    match UnixStream::connect(path) {
+        Ok(_) => {
+            Check::problem(name, Status::Warn,
+                           format!("another instance is listening on {}", path.display()),
+                           "Stop it before starting a new one or point the socket option \
+                           elsewhere")
+        }
+        Err(_) => Check::pass(name, format!("stale {} will be replaced", path.display())),
+    }
+}
+
+fn checks(cmd_line: &CmdLine) -> Vec<Check> {
+    let cfg = match config::load(cmd_line) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            // Nothing else can be checked without a config.
+            let check = Check::problem("config", Status::Fail, chain(&e),
+                                       "Fix the configuration file");
+            return vec![check];
+        }
+    };
+    let mut result = vec![Check::pass("config", "loaded")];
+
+    let scripts = match mailbox::check_scripts(&cfg) {
+        Ok(()) => Check::pass("scripts", format!("{} loaded", cfg.scripts.len())),
+        Err(e) => Check::problem("scripts", Status::Fail, chain(&e), "Fix the lua scripts"),
+    };
+    result.push(scripts);
+
+    for root in &cfg.storage.search {
+        result.push(check_root(&cfg, &root.path));
+    }
+    if cfg.storage.search.is_empty() {
+        result.push(Check::problem("search roots", Status::Warn, "none configured",
+                                   "Add directories to storage.search"));
+    }
+
+    result.push(check_socket_dir(&cfg));
+    result.push(check_socket(&cfg));
+
+    result
+}
+
+/// Runs the checks and prints their results.
+///
+/// Fails if any of the checks failed; warnings alone are not an error.
+crate fn doctor(cmd_line: &CmdLine, json: bool) -> Result<(), Error> {
+    let checks = checks(cmd_line);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        for check in &checks {
+            println!("{} {}: {}", check.status, check.check, check.message);
+            if let Some(remedy) = check.remedy {
+                println!("     {}", remedy);
+            }
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+    use crate::fixtures::TempDir;
+
+    fn cfg(socket: &Path, extra: &str) -> Cfg {
+        let socket = socket.display().to_string();
+        config::parse(&format!("socket = {:?}\n{}\n[storage]\nsearch = []\n", socket, extra))
+    }
+
+    #[test]
+    fn root() {
+        let dir = TempDir::new();
+        let cfg = cfg(&dir.path().join("sock"), "");
+        assert_eq!(Status::Pass, check_root(&cfg, dir.path()).status);
+        let missing = dir.path().join("missing");
+        let check = check_root(&cfg, &missing);
+        assert_eq!(Status::Warn, check.status);
+        assert!(check.remedy.is_some());
+        let strict = self::cfg(&dir.path().join("sock"), "require_all_roots = true");
+        assert_eq!(Status::Fail, check_root(&strict, &missing).status);
+    }
+
+    #[test]
+    fn socket_dir() {
+        let dir = TempDir::new();
+        let check = check_socket_dir(&cfg(&dir.path().join("sock"), ""));
+        assert_eq!(Status::Pass, check.status);
+        // The probe is cleaned up
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+        let check = check_socket_dir(&cfg(&dir.path().join("missing").join("sock"), ""));
+        assert_eq!(Status::Fail, check.status);
+    }
+
+    #[test]
+    fn socket() {
+        let dir = TempDir::new();
+        let path = dir.path().join("sock");
+        let cfg = cfg(&path, "");
+        let check = check_socket(&cfg);
+        assert_eq!((Status::Pass, None), (check.status, check.remedy));
+        assert!(check.message.starts_with("no instance"), "{}", check.message);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let check = check_socket(&cfg);
+        assert_eq!(Status::Warn, check.status);
+        assert!(check.message.starts_with("another instance"), "{}", check.message);
+
+        // The socket stays behind, but nobody answers
+        drop(listener);
+        let check = check_socket(&cfg);
+        assert_eq!(Status::Pass, check.status);
+        assert!(check.message.starts_with("stale"), "{}", check.message);
+    }
+}
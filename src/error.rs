@@ -0,0 +1,117 @@
+//! Classification of top-level failures into process exit codes.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use failure::{Context, Error};
+use once_cell::sync_lazy;
+use once_cell::sync::Lazy;
+
+/// What kind of failure made the program give up.
+///
+/// Errors are tagged with this as a context somewhere along their chain.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+crate enum Kind {
+    Config,
+    Script,
+    FailedRoots,
+    Bind,
+}
+
+impl Kind {
+    /// All the kinds, in the order of their exit codes.
+    const ALL: &'static [Kind] = &[Kind::Config, Kind::Script, Kind::FailedRoots, Kind::Bind];
+
+    fn exit_code(self) -> i32 {
+        match self {
+            Kind::Config => 2,
+            Kind::Script => 3,
+            Kind::FailedRoots => 4,
+            Kind::Bind => 5,
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let msg = match self {
+            Kind::Config => "Failed to load configuration",
+            Kind::Script => "Failed to load lua scripts",
+            Kind::FailedRoots => "Some search roots failed",
+            Kind::Bind => "Failed to set up the socket",
+        };
+        fmt.write_str(msg)
+    }
+}
+
+crate const EXIT_SUCCESS: i32 = 0;
+crate const EXIT_OTHER: i32 = 1;
+crate const EXIT_INTERNAL: i32 = 70;
+
+crate static EXIT_HELP: Lazy<String> = sync_lazy! {
+    let line = |code: i32, desc: &dyn Display| format!("\n    {:>3}  {}", code, desc);
+    let mut help = "EXIT CODES:".to_owned();
+    help += &line(EXIT_SUCCESS, &"Success");
+    help += &line(EXIT_OTHER, &"Other error");
+    for kind in Kind::ALL {
+        help += &line(kind.exit_code(), kind);
+    }
+    help += &line(EXIT_INTERNAL, &"Internal error");
+    help
+};
+
+crate fn exit_code(error: &Error) -> i32 {
+    error
+        .iter_chain()
+        .filter_map(|e| e.downcast_ref::<Context<Kind>>())
+        .map(|ctx| ctx.get_context().exit_code())
+        .next()
+        .unwrap_or(EXIT_OTHER)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use failure::{format_err, ResultExt};
+
+    use super::*;
+
+    fn failing(kind: Kind) -> Result<(), Error> {
+        Err(format_err!("Broken")).context(kind)?;
+        Ok(())
+    }
+
+    #[test]
+    fn exit_codes() {
+        let codes = Kind::ALL.iter().map(|kind| kind.exit_code()).collect::<HashSet<_>>();
+        assert_eq!(Kind::ALL.len(), codes.len(), "Exit codes are unique");
+        for reserved in &[EXIT_SUCCESS, EXIT_OTHER, EXIT_INTERNAL] {
+            assert!(!codes.contains(reserved));
+        }
+        for kind in Kind::ALL {
+            assert_eq!(kind.exit_code(), exit_code(&failing(*kind).unwrap_err()));
+        }
+    }
+
+    #[test]
+    fn exit_code_chain() {
+        assert_eq!(EXIT_OTHER, exit_code(&format_err!("No kind at all")));
+        // The kind may be anywhere in the chain, the outermost one wins
+        let err = failing(Kind::Script)
+            .context("Loading things")
+            .context(Kind::Config)
+            .unwrap_err();
+        assert_eq!(2, exit_code(&err.into()));
+        let err = failing(Kind::Bind).context("Starting").unwrap_err();
+        assert_eq!(5, exit_code(&err.into()));
+    }
+
+    #[test]
+    fn help() {
+        for kind in Kind::ALL {
+            let line = format!("{:>3}  {}", kind.exit_code(), kind);
+            assert!(EXIT_HELP.contains(&line), "{} not in {}", line, *EXIT_HELP);
+        }
+        assert_eq!(Kind::ALL.len() + 4, EXIT_HELP.lines().count());
+    }
+}
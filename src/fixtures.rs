@@ -0,0 +1,481 @@
+//! Generated mailboxes for the tests and benchmarks.
+//!
+//! Everything is generated from a seed, without any clock or randomness from the system, so the
+//! same seed writes the same files on every run. Each builder returns a manifest of what it wrote,
+//! to assert against.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use flate2::Compression as GzLevel;
+use flate2::write::GzEncoder;
+
+/// A directory removed again when dropped.
+crate struct TempDir(PathBuf);
+
+impl TempDir {
+    crate fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let idx = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("mix-test-{}-{}", process::id(), idx));
+        // Leftovers of a crashed run with the same pid
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    crate fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes a file, creating the directories it is in.
+crate fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, content: C) {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    fs::write(path, content).unwrap();
+}
+
+/// A small deterministic generator (xorshift64*), good enough for test data.
+crate struct Rng(u64);
+
+impl Rng {
+    crate fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    crate fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number in `0..n`.
+    crate fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// True with the given probability.
+    crate fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+/// What a builder wrote.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+crate struct Manifest {
+    crate path: PathBuf,
+    crate messages: usize,
+    crate unseen: usize,
+    /// The message files, for maildirs and MH folders.
+    crate files: Vec<PathBuf>,
+    /// Where the messages start, for mboxes (in the decompressed data).
+    crate offsets: Vec<u64>,
+}
+
+/// A maildir with a mix of flags.
+#[derive(Clone, Debug)]
+crate struct MaildirSpec {
+    crate messages: usize,
+    /// Fraction of the messages left in `new`, never looked at.
+    crate new: f64,
+    /// Fraction of the ones in `cur` marked as seen.
+    crate seen: f64,
+    /// Extra files with the same unique name as another message, only with other flags.
+    crate collisions: usize,
+    /// Messages with names that are not valid UTF-8.
+    crate non_utf8: usize,
+    /// Put dot files, a directory and a file in `tmp` there, none of them messages.
+    crate junk: bool,
+}
+
+impl Default for MaildirSpec {
+    fn default() -> Self {
+        MaildirSpec {
+            messages: 10,
+            new: 0.2,
+            seen: 0.5,
+            collisions: 0,
+            non_utf8: 0,
+            junk: false,
+        }
+    }
+}
+
+fn message(rng: &mut Rng, idx: usize, eol: &str) -> String {
+    let mut msg = format!("From: Sender {} <sender{}@example.com>{}", idx, idx, eol);
+    msg += &format!("Subject: Message {} ({:x}){}", idx, rng.next(), eol);
+    msg += &format!("Message-ID: <{}.{:x}@example.com>{}{}", idx, rng.next(), eol, eol);
+    for _ in 0..1 + rng.below(5) {
+        msg += &format!("Line {:x}{}", rng.next(), eol);
+    }
+    msg
+}
+
+impl MaildirSpec {
+    crate fn build(&self, path: &Path, rng: &mut Rng) -> Manifest {
+        for sub in &["cur", "new", "tmp"] {
+            fs::create_dir_all(path.join(sub)).unwrap();
+        }
+        let mut manifest = Manifest {
+            path: path.to_owned(),
+            ..Manifest::default()
+        };
+        let add = |manifest: &mut Manifest, file: PathBuf, content: &str, seen: bool| {
+            write(&file, content);
+            manifest.messages += 1;
+            if !seen {
+                manifest.unseen += 1;
+            }
+            manifest.files.push(file);
+        };
+        let mut cur = Vec::new();
+        for idx in 0..self.messages + self.non_utf8 {
+            let content = message(rng, idx, "\n");
+            let mut unique = format!("{}.M{}P{}.mixtest", 1_500_000_000 + idx, rng.below(1000), idx)
+                .into_bytes();
+            if idx >= self.messages {
+                unique.extend_from_slice(b"\xFF\xFE");
+            }
+            if rng.chance(self.new) {
+                let file = path.join("new").join(OsStr::from_bytes(&unique));
+                add(&mut manifest, file, &content, false);
+                continue;
+            }
+            let seen = rng.chance(self.seen);
+            let mut flags = String::new();
+            for (flag, probability) in &[('F', 0.1), ('P', 0.1), ('R', 0.2)] {
+                if rng.chance(*probability) {
+                    flags.push(*flag);
+                }
+            }
+            if seen {
+                flags.push('S');
+            }
+            if rng.chance(0.1) {
+                flags.push('T');
+            }
+            let mut name = unique.clone();
+            name.extend_from_slice(format!(":2,{}", flags).as_bytes());
+            let file = path.join("cur").join(OsStr::from_bytes(&name));
+            add(&mut manifest, file, &content, seen);
+            cur.push(unique);
+        }
+        for _ in 0..self.collisions {
+            if cur.is_empty() {
+                break;
+            }
+            let mut name = cur[rng.below(cur.len())].clone();
+            name.extend_from_slice(b":2,FS");
+            let file = path.join("cur").join(OsStr::from_bytes(&name));
+            if !file.exists() {
+                add(&mut manifest, file, "Subject: collision\n\n", true);
+            }
+        }
+        if self.junk {
+            write(path.join("cur").join(".hidden"), "not a message");
+            write(path.join("new").join(".lock"), "");
+            write(path.join("tmp").join("1500000000.being-delivered.mixtest"), "half");
+            fs::create_dir_all(path.join("cur").join("subdir")).unwrap();
+        }
+        manifest
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+crate enum Compression {
+    Plain,
+    Gzip,
+}
+
+/// An mbox full of the things that make splitting it hard.
+#[derive(Clone, Debug)]
+crate struct MboxSpec {
+    crate messages: usize,
+    crate compression: Compression,
+    /// Quote the `From ` lines in the bodies (`>From `). Otherwise they are left as they are, but
+    /// never right after an empty line, so they are still not separators.
+    crate quoting: bool,
+    crate crlf: bool,
+    /// Fraction of the messages with a `Status: RO` header.
+    crate read: f64,
+    /// Number of bodies with a region of binary garbage.
+    crate corrupt: usize,
+}
+
+impl Default for MboxSpec {
+    fn default() -> Self {
+        MboxSpec {
+            messages: 10,
+            compression: Compression::Plain,
+            quoting: true,
+            crlf: false,
+            read: 0.5,
+            corrupt: 0,
+        }
+    }
+}
+
+impl MboxSpec {
+    crate fn build(&self, path: &Path, rng: &mut Rng) -> Manifest {
+        let eol = if self.crlf { "\r\n" } else { "\n" };
+        let mut data = Vec::new();
+        let mut manifest = Manifest {
+            path: path.to_owned(),
+            ..Manifest::default()
+        };
+        let corrupt = (0..self.corrupt)
+            .map(|_| rng.below(self.messages.max(1)))
+            .collect::<Vec<_>>();
+        for idx in 0..self.messages {
+            if idx > 0 {
+                data.extend_from_slice(eol.as_bytes());
+            }
+            manifest.offsets.push(data.len() as u64);
+            manifest.messages += 1;
+            let separator = format!("From sender{}@example.com Mon Jan  1 12:{:02}:00 2018{}",
+                                    idx, idx % 60, eol);
+            data.extend_from_slice(separator.as_bytes());
+            if rng.chance(self.read) {
+                data.extend_from_slice(format!("Status: RO{}", eol).as_bytes());
+            } else {
+                manifest.unseen += 1;
+            }
+            data.extend_from_slice(message(rng, idx, eol).as_bytes());
+            let quote = if self.quoting { ">" } else { "" };
+            let from = format!("{}From someone@example.com Mon Jan  1 00:00:00 2018{}", quote, eol);
+            data.extend_from_slice(from.as_bytes());
+            for _ in 0..corrupt.iter().filter(|c| **c == idx).count() {
+                for _ in 0..1 + rng.below(4) {
+                    // Starting with a high byte, the line is never empty nor a separator
+                    let line = (0..1 + rng.below(80))
+                        .map(|_| 0x80 + rng.below(0x80) as u8)
+                        .collect::<Vec<_>>();
+                    data.extend_from_slice(&line);
+                    data.extend_from_slice(eol.as_bytes());
+                }
+            }
+        }
+        match self.compression {
+            Compression::Plain => write(path, &data),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+                encoder.write_all(&data).unwrap();
+                write(path, encoder.finish().unwrap());
+            }
+        }
+        manifest
+    }
+}
+
+/// An MH folder with some of the messages in the unseen sequence.
+#[derive(Clone, Debug)]
+crate struct MhSpec {
+    crate messages: usize,
+    crate unseen: f64,
+    /// Put a deleted message (`,5`), a numbered directory and a cache file there.
+    crate junk: bool,
+}
+
+impl Default for MhSpec {
+    fn default() -> Self {
+        MhSpec {
+            messages: 10,
+            unseen: 0.3,
+            junk: false,
+        }
+    }
+}
+
+impl MhSpec {
+    crate fn build(&self, path: &Path, rng: &mut Rng) -> Manifest {
+        fs::create_dir_all(path).unwrap();
+        let mut manifest = Manifest {
+            path: path.to_owned(),
+            ..Manifest::default()
+        };
+        let mut number = 0;
+        let mut unseen = Vec::new();
+        for idx in 0..self.messages {
+            // Deleted and packed messages leave gaps in the numbering
+            number += 1 + rng.below(3);
+            let file = path.join(number.to_string());
+            write(&file, message(rng, idx, "\n"));
+            manifest.messages += 1;
+            manifest.files.push(file);
+            if rng.chance(self.unseen) {
+                manifest.unseen += 1;
+                unseen.push(number);
+            }
+        }
+        // Written as ranges, the way nmh does
+        let mut ranges = Vec::new();
+        let mut idx = 0;
+        while idx < unseen.len() {
+            let start = unseen[idx];
+            while idx + 1 < unseen.len() && unseen[idx + 1] == unseen[idx] + 1 {
+                idx += 1;
+            }
+            if unseen[idx] == start {
+                ranges.push(start.to_string());
+            } else {
+                ranges.push(format!("{}-{}", start, unseen[idx]));
+            }
+            idx += 1;
+        }
+        write(path.join(".mh_sequences"), format!("cur: 1\nunseen: {}\n", ranges.join(" ")));
+        if self.junk {
+            write(path.join(",5"), "deleted");
+            write(path.join(".xmhcache"), "");
+            fs::create_dir_all(path.join((number + 1).to_string())).unwrap();
+        }
+        manifest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// All the files under the path, with their content.
+    fn snapshot(path: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut result = walkdir::WalkDir::new(path)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .map(|entry| {
+                let relative = entry.path().strip_prefix(path).unwrap().to_owned();
+                let content = if entry.file_type().is_file() {
+                    fs::read(entry.path()).unwrap()
+                } else {
+                    Vec::new()
+                };
+                (relative, content)
+            })
+            .collect::<Vec<_>>();
+        result.sort();
+        result
+    }
+
+    fn build_all(path: &Path, seed: u64) -> Vec<Manifest> {
+        let mut rng = Rng::new(seed);
+        let maildir = MaildirSpec {
+            collisions: 3,
+            non_utf8: 2,
+            junk: true,
+            ..MaildirSpec::default()
+        };
+        let mbox = MboxSpec {
+            crlf: true,
+            corrupt: 2,
+            ..MboxSpec::default()
+        };
+        let gz = MboxSpec {
+            compression: Compression::Gzip,
+            quoting: false,
+            ..MboxSpec::default()
+        };
+        let mh = MhSpec {
+            junk: true,
+            ..MhSpec::default()
+        };
+        vec![
+            maildir.build(&path.join("INBOX"), &mut rng),
+            mbox.build(&path.join("box"), &mut rng),
+            gz.build(&path.join("box.gz"), &mut rng),
+            mh.build(&path.join("mh"), &mut rng),
+        ]
+    }
+
+    fn strip(manifests: Vec<Manifest>, base: &Path) -> Vec<Manifest> {
+        manifests
+            .into_iter()
+            .map(|mut manifest| {
+                manifest.path = manifest.path.strip_prefix(base).unwrap().to_owned();
+                for file in &mut manifest.files {
+                    *file = file.strip_prefix(base).unwrap().to_owned();
+                }
+                manifest
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deterministic() {
+        let first = TempDir::new();
+        let second = TempDir::new();
+        let first_manifests = strip(build_all(first.path(), 42), first.path());
+        let second_manifests = strip(build_all(second.path(), 42), second.path());
+        assert_eq!(first_manifests, second_manifests);
+        assert_eq!(snapshot(first.path()), snapshot(second.path()));
+    }
+
+    #[test]
+    fn seeds_differ() {
+        let first = TempDir::new();
+        let second = TempDir::new();
+        build_all(first.path(), 1);
+        build_all(second.path(), 2);
+        assert_ne!(snapshot(first.path()), snapshot(second.path()));
+    }
+
+    #[test]
+    fn manifest_counts() {
+        let dir = TempDir::new();
+        let manifests = build_all(dir.path(), 7);
+        let maildir = &manifests[0];
+        // The requested ones, the non-UTF-8 ones and the collisions not hitting one another
+        assert!(maildir.messages >= 12 && maildir.messages <= 15);
+        assert_eq!(maildir.messages, maildir.files.len());
+        for file in &maildir.files {
+            assert!(file.is_file());
+        }
+        let unseen = maildir
+            .files
+            .iter()
+            .filter(|file| {
+                let name = file.file_name().unwrap().to_string_lossy();
+                match name.rfind(":2,") {
+                    Some(pos) => !name[pos + 3..].contains('S'),
+                    None => true,
+                }
+            })
+            .count();
+        assert_eq!(maildir.unseen, unseen);
+        let mbox = &manifests[1];
+        assert_eq!(10, mbox.messages);
+        assert_eq!(10, mbox.offsets.len());
+        let data = fs::read(&mbox.path).unwrap();
+        for offset in &mbox.offsets {
+            assert!(data[*offset as usize..].starts_with(b"From "));
+        }
+        let mh = &manifests[3];
+        assert_eq!(10, mh.messages);
+    }
+
+    #[test]
+    fn rng_range() {
+        let mut rng = Rng::new(0);
+        for _ in 0..1000 {
+            assert!(rng.below(7) < 7);
+        }
+        assert!(!rng.chance(0.0));
+        assert!(rng.chance(1.0));
+    }
+}
@@ -0,0 +1,261 @@
+//! Shell-like glob patterns for paths.
+//!
+//! `*` matches anything except a path separator, `**` matches anything including separators (and
+//! `**/` may match nothing at all), `?` matches a single character other than a separator and
+//! `[...]` is a character class (`[!...]` negates it, a `]` right at the start is part of it).
+//! Everything else matches itself. The pattern must match the whole path and matching is case
+//! sensitive. Trailing slashes don't matter, neither on the pattern nor on the path, as the paths
+//! don't tell directories apart anyway.
+//!
+//! This is the only place deciding the dialect. Everything matching paths against user patterns
+//! should get its matcher through `Glob::cached`, so they all behave the same and a pattern used
+//! all over the place is compiled only once.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use failure::{bail, Error};
+use once_cell::sync_lazy;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use regex::bytes::Regex;
+
+/// How many compiled patterns are kept around.
+const CACHE_SIZE: usize = 256;
+
+struct Cached {
+    glob: Arc<Glob>,
+    last_used: AtomicUsize,
+}
+
+// Lookups are much more common than inserts, so the lookups only read-lock and bump the use
+// "timestamp" atomically. The least recently used entry is found by a linear scan on insert.
+static CACHE: Lazy<RwLock<HashMap<String, Cached>>> = sync_lazy!(RwLock::default());
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug)]
+crate struct Glob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Glob {
+    fn new(pattern: &str) -> Result<Self, Error> {
+        // The wildcards work on bytes, so they match non-UTF-8 paths too
+        let mut re = String::from("^");
+        let trimmed = pattern.trim_end_matches('/');
+        let trimmed = if trimmed.is_empty() { &pattern[..pattern.len().min(1)] } else { trimmed };
+        let mut chars = trimmed.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        re.push_str("(?:(?s-u:.)*/)?");
+                    } else {
+                        re.push_str("(?s-u:.)*");
+                    }
+                }
+                '*' => re.push_str("(?-u:[^/])*"),
+                '?' => re.push_str("[^/]"),
+                '[' => {
+                    re.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        re.push('^');
+                    }
+                    let mut first = true;
+                    loop {
+                        match chars.next() {
+                            Some(']') if !first => break,
+                            // Whatever the regex would take as nesting, set operations or negation
+                            Some(c @ '\\') | Some(c @ '[') | Some(c @ ']') | Some(c @ '&')
+                            | Some(c @ '~') | Some(c @ '^') => {
+                                re.push('\\');
+                                re.push(c);
+                            }
+                            Some(c) => re.push(c),
+                            None => bail!("Unclosed character class in pattern {}", pattern),
+                        }
+                        first = false;
+                    }
+                    re.push(']');
+                }
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+        Ok(Glob {
+            pattern: pattern.to_owned(),
+            regex: Regex::new(&re)?,
+        })
+    }
+
+    /// Returns a compiled pattern, compiling it only if it isn't in the cache already.
+    crate fn cached(pattern: &str) -> Result<Arc<Glob>, Error> {
+        let now = CLOCK.fetch_add(1, Ordering::Relaxed);
+        if let Some(cached) = CACHE.read().get(pattern) {
+            cached.last_used.store(now, Ordering::Relaxed);
+            return Ok(Arc::clone(&cached.glob));
+        }
+
+        // Compile outside of the lock. If someone else compiled it in the meantime, no harm done.
+        let glob = Arc::new(Glob::new(pattern)?);
+        let mut cache = CACHE.write();
+        if cache.len() >= CACHE_SIZE && !cache.contains_key(pattern) {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used.load(Ordering::Relaxed))
+                .map(|(pattern, _)| pattern.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+        let cached = Cached {
+            glob: Arc::clone(&glob),
+            last_used: AtomicUsize::new(now),
+        };
+        cache.insert(pattern.to_owned(), cached);
+        Ok(glob)
+    }
+
+    crate fn is_match<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref().as_os_str().as_bytes();
+        let end = path.iter().rposition(|&b| b != b'/').map_or(path.len().min(1), |pos| pos + 1);
+        self.regex.is_match(&path[..end])
+    }
+
+    /// If the pattern has a slash in it, apart from the insignificant trailing one.
+    crate fn has_separator(&self) -> bool {
+        self.pattern.trim_end_matches('/').contains('/')
+    }
+}
+
+impl Display for Glob {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str(&self.pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    /// Pattern, paths it matches and paths it doesn't.
+    const CASES: &[(&str, &[&str], &[&str])] = &[
+        ("abc", &["abc", "abc/"], &["ab", "abcd", "xabc", "ABC", "a/bc", "dir/abc"]),
+        ("*", &["", "abc", ".hidden", "abc/"], &["a/b", "/abc"]),
+        ("*.mbox", &["in.mbox", ".mbox", "a b.mbox"], &["dir/in.mbox", "in.mbox.gz", "in_mbox"]),
+        ("a*b*c", &["abc", "aXbYc", "abbc"], &["ab/c", "a/bc", "acb"]),
+        ("dir/*", &["dir/a", "dir/a/"], &["dir", "dir/", "dir/a/b", "x/dir/a"]),
+        ("**", &["", "a", "a/b/c", "/abs/path"], &[]),
+        ("**/spam", &["spam", "a/spam", "a/b/spam"], &["spam/a", "aspam", "a/xspam"]),
+        ("a/**/b", &["a/b", "a/x/b", "a/x/y/b"], &["a/xb", "ab", "b", "a/b/c"]),
+        ("a/**", &["a/b", "a/b/c"], &["a", "a/", "ab", "b/a/c"]),
+        ("a**b", &["ab", "aXb", "a/x/b"], &["a/x/c"]),
+        ("?", &["a", "é", "?"], &["", "ab", "/"]),
+        ("a?c", &["abc", "a.c", "a?c"], &["ac", "abbc", "a/c"]),
+        ("[abc]", &["a", "b", "c"], &["d", "ab", "", "["]),
+        ("[a-c]x", &["ax", "bx", "cx"], &["dx", "-x"]),
+        ("[!a-c]", &["d", "z", "-"], &["a", "b", "c"]),
+        ("[a-]", &["a", "-"], &["b"]),
+        ("[]a]", &["]", "a"], &["b"]),
+        ("[!]]", &["a"], &["]"]),
+        ("[[]", &["["], &["a"]),
+        ("[a&&b]", &["a", "&", "b"], &["c"]),
+        ("[a~~b]", &["a", "~", "b"], &["c"]),
+        ("[^x]", &["^", "x"], &["a"]),
+        ("[\\d]", &["\\", "d"], &["1"]),
+        ("a.c", &["a.c"], &["abc"]),
+        ("a+", &["a+"], &["a", "aa"]),
+        ("(a|b)", &["(a|b)"], &["a", "b"]),
+        ("a{1,2}", &["a{1,2}"], &["a", "aa"]),
+        ("^a$", &["^a$"], &["a"]),
+        ("\\d", &["\\d"], &["1", "d"]),
+        ("dir/", &["dir", "dir/", "dir//"], &["dir/a", "di"]),
+        ("/", &["/", "//"], &["", "a"]),
+        ("/abs/*", &["/abs/x"], &["abs/x", "/abs/x/y"]),
+        ("", &[""], &["a", "/"]),
+    ];
+
+    #[test]
+    fn patterns() {
+        for &(pattern, matching, other) in CASES {
+            let glob = Glob::new(pattern).unwrap();
+            let cached = Glob::cached(pattern).unwrap();
+            for path in matching {
+                assert!(glob.is_match(path), "{} should match {:?}", pattern, path);
+                assert!(cached.is_match(path), "cached {} should match {:?}", pattern, path);
+            }
+            for path in other {
+                assert!(!glob.is_match(path), "{} shouldn't match {:?}", pattern, path);
+                assert!(!cached.is_match(path), "cached {} shouldn't match {:?}", pattern, path);
+            }
+        }
+    }
+
+    #[test]
+    fn non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"dir/\xff\xfe.mbox"));
+        assert!(Glob::new("dir/*.mbox").unwrap().is_match(path));
+        assert!(Glob::new("**.mbox").unwrap().is_match(path));
+        assert!(!Glob::new("*.mbox").unwrap().is_match(path));
+    }
+
+    #[test]
+    fn invalid() {
+        for pattern in &["[abc", "[!", "a/[]", "[z-a]"] {
+            assert!(Glob::cached(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn separator() {
+        let cases = &[("a", false), ("a/", false), ("a/b", true), ("**/a", true), ("/", false)];
+        for &(pattern, expected) in cases {
+            assert_eq!(expected, Glob::new(pattern).unwrap().has_separator(), "{}", pattern);
+        }
+    }
+
+    /// The same pattern gets the same matcher, until it is pushed out as the least recently used.
+    #[test]
+    fn cache_eviction() {
+        // Other tests use the cache too, so the patterns are unique and only what is about our
+        // own patterns is checked.
+        let pattern = |i: usize| format!("glob-test-eviction-{}", i);
+        let first = Glob::cached(&pattern(0)).unwrap();
+        assert!(Arc::ptr_eq(&first, &Glob::cached(&pattern(0)).unwrap()));
+        let others = (1..CACHE_SIZE)
+            .map(|i| Glob::cached(&pattern(i)).unwrap())
+            .collect::<Vec<_>>();
+        // Make the first one recently used, so pattern 1 is the oldest of ours
+        Glob::cached(&pattern(0)).unwrap();
+        Glob::cached(&pattern(CACHE_SIZE)).unwrap();
+
+        let cache = CACHE.read();
+        assert!(cache.len() <= CACHE_SIZE);
+        assert!(!cache.contains_key(&pattern(1)));
+        assert!(Arc::ptr_eq(&first, &cache[&pattern(0)].glob));
+        assert!(cache.contains_key(&pattern(CACHE_SIZE)));
+        let ours = cache.keys().filter(|key| key.starts_with("glob-test-eviction-")).count();
+        assert!(ours <= CACHE_SIZE);
+        drop(cache);
+
+        // The evicted ones are still usable by whoever holds them and get compiled anew on demand
+        assert!(others[0].is_match(pattern(1)));
+        let again = Glob::cached(&pattern(1)).unwrap();
+        assert!(!Arc::ptr_eq(&others[0], &again));
+        let unique = others.iter().map(|glob| glob.to_string()).collect::<HashSet<_>>();
+        assert_eq!(CACHE_SIZE - 1, unique.len());
+    }
+}
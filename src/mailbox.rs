@@ -1,69 +1,300 @@
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::os::unix::ffi::OsStrExt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, SystemTime};
 
-use failure::{Error, ResultExt};
+use failure::{bail, format_err, Error, ResultExt};
 use flate2::read::GzDecoder;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use once_cell::sync_lazy;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
-use rlua::{Lua, Function, UserData, UserDataMethods, Table};
+use serde_json::{json, Value};
 use walkdir::{DirEntry, WalkDir};
 
+mod builder;
+mod cancel;
+mod convert;
+mod cutoff;
+mod explain;
+mod export;
+#[cfg(feature = "lua")]
+mod filter;
 mod mbox;
 mod mdir;
+mod meta;
+mod mh;
+mod normalize;
+mod poll;
+mod report;
+#[cfg(feature = "lua")]
+mod script;
+#[cfg(not(feature = "lua"))]
+#[path = "mailbox/no_script.rs"]
+mod script;
 mod task;
+#[cfg(test)]
+mod tests;
+mod watch;
+mod workers;
 
-use crate::config::Cfg;
-use self::mbox::Mbox;
+use crate::config::{Cfg, NestedMailboxes, ShortcutConflicts};
+use crate::error::Kind;
+use crate::path_key::PathKey;
+use crate::path_trie::PathTrie;
+use crate::redact;
+use self::cancel::Token;
+use self::cutoff::Context;
+use self::mbox::{Format as MboxFormat, Mbox};
 use self::mdir::Mdir;
-use self::task::{Queue, Task};
+use self::meta::MetaIndex;
+use self::mh::Mh;
+use self::script::Scripts;
+use self::task::{Queue, RescanMode, Task};
+
+crate use self::builder::MailboxBuilder;
+crate use self::convert::{to_mbox as convert_to_mbox, Options as ConvertOptions};
+crate use self::explain::explain;
+crate use self::poll::{poll, Poll};
+crate use self::report::{RootReport, ScanReport};
+crate use self::watch::{watch, Watch};
+crate use self::workers::{Handle, Pool};
 
 crate static MAILBOXES: Lazy<Mutex<HashMap<String, Arc<Mailbox>>>> = sync_lazy!(Mutex::default());
 
+/// The report of the most recent scan.
+crate static LAST_SCAN: Lazy<Mutex<ScanReport>> = sync_lazy!(Mutex::default());
+
+/// The scripts of the most recent scan, running the content hooks.
+///
+/// Lua can't run in multiple threads at once, so the lock also keeps the hooks from overlapping.
+static HOOKS: Lazy<Mutex<Option<Scripts>>> = sync_lazy!(Mutex::default());
+
+/// Why a mailbox isn't registered when the nesting policy refuses it.
+const NESTED: &str = "nested in another mailbox";
+/// Why a symlink isn't followed when it points to a directory it is in.
+const LOOP: &str = "symlink loop";
+/// Why a mailbox isn't registered when a script doesn't want it.
+const IGNORED: &str = "ignored by a script";
+
+/// Time spent in a lua callback, over all its calls in the current scripts instance.
+#[derive(Clone, Debug, Default)]
+crate struct CallbackStats {
+    crate description: String,
+    crate calls: usize,
+    crate total: Duration,
+}
+
+/// How long the callbacks of the current scripts took, since they were loaded by the last scan.
+crate fn lua_stats() -> Vec<CallbackStats> {
+    HOOKS
+        .lock()
+        .as_ref()
+        .map(|scripts| scripts.stats())
+        .unwrap_or_default()
+}
+
+/// Finds a registered mailbox by its name.
+///
+/// The exact name always wins. If there's no such mailbox and normalization is on, a mailbox whose
+/// name normalizes to the same thing is returned (if there are multiple, which one is
+/// unspecified; the scan warns about these).
+crate fn lookup(name: &str, normalize: bool) -> Option<Arc<Mailbox>> {
+    let mailboxes = MAILBOXES.lock();
+    if let Some(mbox) = mailboxes.get(name) {
+        return Some(Arc::clone(mbox));
+    }
+    if normalize {
+        let name = normalize::normalize(name);
+        mailboxes
+            .iter()
+            .find(|(candidate, _)| normalize::normalize(candidate) == name)
+            .map(|(_, mbox)| Arc::clone(mbox))
+    } else {
+        None
+    }
+}
+
+/// All the registered mailboxes, sorted by their names.
+crate fn all() -> Vec<Arc<Mailbox>> {
+    let mut result = MAILBOXES
+        .lock()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    result
+}
+
+fn check_normalized_names() {
+    for (normalized, names) in normalize::ambiguous(MAILBOXES.lock().keys()) {
+        warn!("Mailboxes {} are ambiguous, they all match as {}", names.join(", "), normalized);
+    }
+}
+
 const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+#[cfg(feature = "xz")]
+const XZ_MAGIC: &[u8] = &[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00];
+#[cfg(feature = "bzip2")]
+const BZIP2_MAGIC: &[u8] = b"BZh";
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
 const MBOX_MAGIC: &[u8] = b"From ";
 const MDIR_SUBDIRS: &[&str] = &["cur", "new", "tmp"];
 
-const CONFIG_CBACKS: &str = "config-cbacks";
+/// The compressions we know how to read, by the magic at the start of the file.
+const COMPRESSIONS: &[(&[u8], Type)] = &[
+    (GZIP_MAGIC, Type::Gzip),
+    #[cfg(feature = "xz")]
+    (XZ_MAGIC, Type::Xz),
+    #[cfg(feature = "bzip2")]
+    (BZIP2_MAGIC, Type::Bzip2),
+    #[cfg(feature = "zstd")]
+    (ZSTD_MAGIC, Type::Zstd),
+];
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 enum Type {
     Plain,
+    Mmdf,
     Gzip,
+    #[cfg(feature = "xz")]
+    Xz,
+    #[cfg(feature = "bzip2")]
+    Bzip2,
+    #[cfg(feature = "zstd")]
+    Zstd,
     Dir,
+    Mh,
+}
+
+/// A name for a temporary file next to the path, unique among processes and threads.
+///
+/// The file is meant to be renamed over the path once complete. Whatever a killed run left behind
+/// doesn't get in the way of the next one.
+fn tmp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    let unique = COUNTER.fetch_add(1, AtomicOrdering::Relaxed);
+    name.push(format!(".tmp-{}-{}", process::id(), unique));
+    path.with_file_name(name)
+}
+
+/// Reads the beginning of a file into the buffer, as much as there's of it.
+///
+/// Unlike read_exact, a file shorter than the buffer is fine, the length read is returned.
+fn read_start<R: Read + ?Sized>(input: &mut R, buf: &mut [u8]) -> Result<usize, IoError> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
 }
 
 impl Type {
+    fn from_name(name: &str) -> Option<Self> {
+        let all = [
+            Type::Plain,
+            Type::Mmdf,
+            Type::Gzip,
+            #[cfg(feature = "xz")]
+            Type::Xz,
+            #[cfg(feature = "bzip2")]
+            Type::Bzip2,
+            #[cfg(feature = "zstd")]
+            Type::Zstd,
+            Type::Dir,
+            Type::Mh,
+        ];
+        all.iter().find(|tp| tp.name() == name).cloned()
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Type::Plain => "mbox",
+            Type::Mmdf => "mmdf",
+            Type::Gzip => "mbox-gz",
+            #[cfg(feature = "xz")]
+            Type::Xz => "mbox-xz",
+            #[cfg(feature = "bzip2")]
+            Type::Bzip2 => "mbox-bz2",
+            #[cfg(feature = "zstd")]
+            Type::Zstd => "mbox-zst",
+            Type::Dir => "maildir",
+            Type::Mh => "mh",
+        }
+    }
+
+    /// Wraps the file into the decompression this type needs.
+    fn decompress(&self, f: File) -> Result<Box<dyn Read>, Error> {
+        match self {
+            Type::Plain | Type::Mmdf => Ok(Box::new(f)),
+            Type::Gzip => Ok(Box::new(GzDecoder::new(f))),
+            #[cfg(feature = "xz")]
+            Type::Xz => Ok(Box::new(xz2::read::XzDecoder::new(f))),
+            #[cfg(feature = "bzip2")]
+            Type::Bzip2 => Ok(Box::new(bzip2_crate::read::BzDecoder::new(f))),
+            #[cfg(feature = "zstd")]
+            Type::Zstd => Ok(Box::new(zstd_crate::stream::read::Decoder::new(f)?)),
+            Type::Dir => bail!("A maildir is not a file"),
+            Type::Mh => bail!("An MH folder is not a file"),
+        }
+    }
+
     fn guess(entry: &DirEntry) -> Result<Option<Self>, Error> {
         if entry.file_type().is_file() {
             // It is a file. So try opening it and look inside.
             let mut f = File::open(entry.path())?;
-            let mut beginning = [0u8; 5];
-            f.read_exact(&mut beginning)?;
+            let mut beginning = [0u8; 6];
+            let len = read_start(&mut f, &mut beginning)?;
+            let beginning = &beginning[..len];
             trace!("{:?} starts with {:?}", entry.path(), beginning);
-            if beginning == MBOX_MAGIC {
+            if beginning.starts_with(MBOX_MAGIC) {
                 return Ok(Some(Type::Plain));
             }
+            if mbox::starts_mmdf(beginning) {
+                return Ok(Some(Type::Mmdf));
+            }
 
-            // OK, if it's not a mailbox, it still can be a gzipped mailbox. Look if it starts with
-            // gzip magic.
+            // OK, if it's not a mailbox, it still can be a compressed mailbox. Look if it starts
+            // with a magic of one of them.
             //
-            // We check 2 bytes only, but the gzip header is longer than that ‒ so the read for 5
-            // bytes must not have failed.
-            if &beginning[..2] == GZIP_MAGIC {
-                // Try to read decompressed beginning of the file
+            // The magics are short, but every compressed header is longer than that ‒ a file cut
+            // off right after one is just not going to decompress.
+            for (magic, tp) in COMPRESSIONS {
+                if !beginning.starts_with(magic) {
+                    continue;
+                }
+                // Try to read decompressed beginning of the file. Something that only looks like
+                // a compressed file is just not a mailbox, not an error.
                 f.seek(SeekFrom::Start(0))?;
-                let mut gz = GzDecoder::new(f);
-                gz.read_exact(&mut beginning)?;
-
-                if beginning == MBOX_MAGIC {
-                    return Ok(Some(Type::Gzip));
+                // A compressed MMDF keeps the compression's type, the parser tells them apart.
+                let mut decoded = [0u8; 6];
+                let is_mbox = tp
+                    .decompress(f)
+                    .and_then(|mut d| read_start(&mut d, &mut decoded).map_err(Error::from))
+                    .map(|len| {
+                        let decoded = &decoded[..len];
+                        decoded.starts_with(MBOX_MAGIC) || mbox::starts_mmdf(decoded)
+                    });
+                match is_mbox {
+                    Ok(true) => return Ok(Some(tp.clone())),
+                    Ok(false) => (),
+                    Err(e) => {
+                        trace!("{:?} doesn't decompress as {}: {}", entry.path(), tp.name(), e)
+                    }
                 }
+                return Ok(None);
             }
         } else if entry.file_type().is_dir() {
             // Not every dir is a maildir ‒ maildirs have specific subdirs in them.
@@ -73,6 +304,14 @@ impl Type {
             if is_mdir {
                 return Ok(Some(Type::Dir));
             }
+            // Plenty of directories are full of numbered files, so only trust the files MH itself
+            // keeps there.
+            let is_mh = mh::MARKERS
+                .iter()
+                .any(|marker| entry.path().join(marker).is_file());
+            if is_mh {
+                return Ok(Some(Type::Mh));
+            }
         }
         Ok(None)
     }
@@ -80,68 +319,201 @@ impl Type {
 
 #[derive(Clone, Debug)]
 enum Cache {
+    /// Not read even once yet.
+    Unscanned,
     Mbox(Mbox),
     Mdir(Mdir),
+    Mh(Mh),
 }
 
-#[derive(Clone, Debug)]
+impl Cache {
+    /// Number of messages and of the unseen ones, if known.
+    fn counts(&self) -> (usize, Option<usize>) {
+        match self {
+            Cache::Unscanned => (0, None),
+            Cache::Mbox(mbox) => (mbox.message_count(), None),
+            Cache::Mdir(mdir) => (mdir.total(), Some(mdir.unseen())),
+            Cache::Mh(mh) => (mh.total(), Some(mh.unseen())),
+        }
+    }
+}
+
+/// What the files of a mailbox looked like when it was read.
+///
+/// If none of them changed, reading the mailbox again would find the same. Adding, removing or
+/// renaming a message file changes the modification time of its directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Stamp(Vec<Option<(u64, SystemTime)>>);
+
+impl Stamp {
+    fn of(mbox: &Mailbox) -> Result<Self, Error> {
+        let paths = match mbox.tp {
+            Type::Dir => vec![mbox.path.join("cur"), mbox.path.join("new")],
+            Type::Mh => vec![mbox.path.clone(), mbox.path.join(mh::SEQUENCES)],
+            _ => vec![mbox.path.clone()],
+        };
+        paths
+            .iter()
+            .map(|path| match fs::metadata(path) {
+                Ok(meta) => Ok(Some((meta.len(), meta.modified()?))),
+                // Reading the mailbox will tell if it's a problem
+                Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect::<Result<_, _>>()
+            .map(Stamp)
+            .with_context(|_| format!("Failed to look at {}", mbox.path.display()))
+            .map_err(Error::from)
+    }
+}
+
+#[derive(Debug)]
 crate struct Mailbox {
     path: PathBuf,
+    /// The identity of the path, computed once as it needs the filesystem.
+    key: PathKey,
     name: String,
     tp: Type,
-    cache: Cache,
+    cache: Mutex<Cache>,
+    /// How the files looked when the cache was read from them.
+    stamp: Mutex<Option<Stamp>>,
     prio: usize,
     shortcut: Option<char>,
+    /// Tracked as any other, but left out of the lists made for other programs.
+    hidden: bool,
+    /// Overrides the global poll_interval for this mailbox.
+    poll_interval: Option<Duration>,
+    mbox_format: MboxFormat,
+}
+
+impl Clone for Mailbox {
+    fn clone(&self) -> Self {
+        Mailbox {
+            path: self.path.clone(),
+            key: self.key.clone(),
+            name: self.name.clone(),
+            tp: self.tp.clone(),
+            cache: Mutex::new(self.cache.lock().clone()),
+            stamp: Mutex::new(self.stamp.lock().clone()),
+            prio: self.prio,
+            shortcut: self.shortcut,
+            hidden: self.hidden,
+            poll_interval: self.poll_interval,
+            mbox_format: self.mbox_format,
+        }
+    }
 }
 
 impl Mailbox {
     fn detect(entry: &DirEntry) -> Result<Option<Self>, Error> {
-        if let Some(mt) = Type::guess(entry)? {
-            let name = entry
-                .path()
-                .file_name()
-                .map(|s| s.to_string_lossy().into_owned())
-                .unwrap_or_else(|| "<???>".to_owned());
-            let cache = match mt {
-                Type::Gzip | Type::Plain => Cache::Mbox(Mbox::default()),
-                Type::Dir => Cache::Mdir(Mdir::default()),
-            };
-            Ok(Some(Mailbox {
-                path: entry.path().to_owned(),
-                name,
-                tp: mt,
-                cache,
-                prio: 0,
-                shortcut: None,
-            }))
-        } else {
-            Ok(None)
+        match Type::guess(entry)? {
+            Some(tp) => MailboxBuilder::new(entry.path()).with_kind(tp.name()).build().map(Some),
+            None => Ok(None),
         }
     }
     crate fn name(&self) -> &str {
         &self.name
     }
-}
-
-impl UserData for Mailbox {
-    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("name", |_, this, ()| Ok(this.name().to_owned()));
-        methods.add_method("path", |lua: &_, this, ()| {
-            let s = lua.create_string(this.path.as_os_str().as_bytes())?;
-            Ok(s)
-        });
-        methods.add_method_mut("set_name", |_, this, name| {
-            this.name = name;
-            Ok(())
-        });
-        methods.add_method_mut("set_prio", |_, this, prio| {
-            this.prio = prio;
-            Ok(())
-        });
-        methods.add_method_mut("set_shortcut", |_, this, sc: String| {
-            this.shortcut = sc.chars().nth(0);
-            Ok(())
-        });
+    crate fn path(&self) -> &Path {
+        &self.path
+    }
+    /// The kind of the mailbox, as used in filters (eg. `mbox-gz`).
+    crate fn kind(&self) -> &'static str {
+        self.tp.name()
+    }
+    crate fn prio(&self) -> usize {
+        self.prio
+    }
+    crate fn shortcut(&self) -> Option<char> {
+        self.shortcut
+    }
+    crate fn hidden(&self) -> bool {
+        self.hidden
+    }
+    /// How often the mailbox is rescanned regardless of changes. Zero means never.
+    fn poll_interval(&self, cfg: &Cfg) -> Duration {
+        self.poll_interval.unwrap_or(cfg.poll_interval.0)
+    }
+    /// Number of messages and of the unseen ones (if known) from the last rescan.
+    crate fn counts(&self) -> (usize, Option<usize>) {
+        self.cache.lock().counts()
+    }
+    /// The same as counts, but nothing at all if the mailbox was not read yet.
+    fn scanned_counts(&self) -> Option<(usize, Option<usize>)> {
+        match *self.cache.lock() {
+            Cache::Unscanned => None,
+            ref cache => Some(cache.counts()),
+        }
+    }
+    /// Whether the other mailbox was configured the same way, so it can replace this one.
+    fn same_config(&self, other: &Mailbox) -> bool {
+        self.path == other.path
+            && self.name == other.name
+            && self.tp == other.tp
+            && self.prio == other.prio
+            && self.shortcut == other.shortcut
+            && self.hidden == other.hidden
+            && self.poll_interval == other.poll_interval
+            && self.mbox_format == other.mbox_format
+    }
+    /// Opens the mbox for reading, decompressing it on the fly if needed.
+    ///
+    /// The decompression is streamed, a compressed mailbox is never held in memory as a whole.
+    fn open_mbox(&self) -> Result<Box<dyn BufRead>, Error> {
+        let f = File::open(&self.path)?;
+        let decompressed = self
+            .tp
+            .decompress(f)
+            .with_context(|_| format!("Can't read {} as an mbox", self.path.display()))?;
+        Ok(Box::new(BufReader::new(decompressed)))
+    }
+    /// Takes over the cache of another mailbox at the same path.
+    fn take_cache(&self, from: &Mailbox) {
+        *self.cache.lock() = from.cache.lock().clone();
+        *self.stamp.lock() = from.stamp.lock().clone();
+    }
+    /// Reads the mailbox and replaces its cache with what was found.
+    ///
+    /// An incremental rescan skips the reading if none of the files changed since the last one.
+    /// A full one always reads everything.
+    ///
+    /// Returns the counts from before, unless this was the first read.
+    fn rescan(&self, mode: RescanMode, cancel: &Token)
+        -> Result<Option<(usize, Option<usize>)>, Error>
+    {
+        // Taken before reading, so a change in the middle of it shows up the next time
+        let stamp = Stamp::of(self)?;
+        let before = self.scanned_counts();
+        if mode == RescanMode::Incremental && before.is_some()
+            && self.stamp.lock().as_ref() == Some(&stamp)
+        {
+            trace!("Mailbox {} didn't change since the last read", self.name);
+            return Ok(before);
+        }
+        let cache = match self.tp {
+            Type::Dir => {
+                let mdir = Mdir::scan(&self.path, cancel)?;
+                for entry in mdir.entries() {
+                    trace!("Message in {}: {:?}", self.name, entry);
+                }
+                Cache::Mdir(mdir)
+            }
+            Type::Mh => {
+                let mh = Mh::scan(&self.path, cancel)?;
+                trace!("Messages in {}: {:?}", self.name, mh.messages().collect::<Vec<_>>());
+                Cache::Mh(mh)
+            }
+            _ => {
+                let mbox = Mbox::parse(self.open_mbox()?, self.mbox_format, cancel)?;
+                for msg in mbox.messages() {
+                    trace!("Message in {} at {}+{}: {:?}", self.name, msg.offset, msg.length, msg);
+                }
+                Cache::Mbox(mbox)
+            }
+        };
+        *self.cache.lock() = cache;
+        *self.stamp.lock() = Some(stamp);
+        Ok(before)
     }
 }
 
@@ -149,109 +521,517 @@ impl UserData for Mailbox {
 crate enum Notification {
     MailboxAppeared(Arc<Mailbox>),
     MailboxContent(Arc<Mailbox>),
+    MailboxVanished(Arc<Mailbox>),
+    Error(String),
+}
+
+/// How many notifications may wait for a subscriber before it is considered dead.
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+static SUBSCRIBERS: Lazy<Mutex<Vec<SyncSender<String>>>> = sync_lazy!(Mutex::default());
+
+/// Starts receiving all future notifications, serialized as JSON.
+///
+/// If the receiver doesn't keep up (or is dropped), it gets disconnected.
+crate fn subscribe() -> Receiver<String> {
+    let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+    SUBSCRIBERS.lock().push(sender);
+    receiver
 }
 
 impl Notification {
+    fn to_json(&self) -> Value {
+        match self {
+            Notification::MailboxAppeared(mbox) => json!({
+                "event": "mailbox-appeared",
+                "name": mbox.name(),
+                "path": mbox.path().to_string_lossy(),
+                "kind": mbox.kind(),
+                "prio": mbox.prio(),
+                "shortcut": mbox.shortcut(),
+            }),
+            Notification::MailboxContent(mbox) => {
+                let (messages, unseen) = mbox.counts();
+                json!({
+                    "event": "mailbox-content",
+                    "name": mbox.name(),
+                    "messages": messages,
+                    "unseen": unseen,
+                })
+            }
+            Notification::MailboxVanished(mbox) => json!({
+                "event": "mailbox-vanished",
+                "name": mbox.name(),
+                "path": mbox.path().to_string_lossy(),
+            }),
+            Notification::Error(msg) => json!({
+                "event": "error",
+                "message": msg,
+            }),
+        }
+    }
+
     crate fn send(notification: Notification) {
         info!("{:?}", notification);
+        let mut subscribers = SUBSCRIBERS.lock();
+        if subscribers.is_empty() {
+            return;
+        }
+        let serialized = notification.to_json().to_string();
+        // Never block here, this is called from the scanning threads. Whoever can't take it is
+        // dropped, the others still get it.
+        subscribers.retain(|sub| match sub.try_send(serialized.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Dropping a subscriber that doesn't keep up");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// Inserts the mailbox into the found ones, renaming it first if the name is already taken.
+///
+/// The new name is prefixed by the parent directory, and if that's taken too, suffixed by a
+/// number. Renaming the mailbox in a config callback is the way to get a better name.
+fn register(mailboxes: &mut HashMap<String, Arc<Mailbox>>, mut mbox: Mailbox) -> Arc<Mailbox> {
+    if let Some(existing) = mailboxes.get(&mbox.name) {
+        let parent = mbox
+            .path
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|p| p.to_string_lossy().into_owned());
+        let prefixed = match parent {
+            Some(parent) => format!("{}/{}", parent, mbox.name),
+            None => mbox.name.clone(),
+        };
+        let mut name = prefixed.clone();
+        let mut idx = 2;
+        while mailboxes.contains_key(&name) {
+            name = format!("{}~{}", prefixed, idx);
+            idx += 1;
+        }
+        // Before it shows up in the log
+        redact::mailbox(&name, &mbox.path);
+        warn!("Mailboxes {} and {} are both named {}, registering the latter as {}",
+              existing.path.display(), mbox.path.display(), mbox.name, name);
+        mbox.name = name;
     }
+    redact::mailbox(&mbox.name, &mbox.path);
+    let mbox = Arc::new(mbox);
+    mailboxes.insert(mbox.name.clone(), Arc::clone(&mbox));
+    mbox
 }
 
-fn scan_cutoff(dedup: &HashSet<PathBuf>, entry: &DirEntry) -> bool {
-    let path = entry.path();
-    // Direct duplicate
-    if dedup.contains(path) {
-        return true;
+/// Runs the post-scan callbacks of the scripts, once the queued rescans are done.
+///
+/// They run in the lua instance of the most recent scan, the same one that configured the
+/// mailboxes. The mailboxes know their counts by then. A mailbox the callbacks change is replaced,
+/// like on a reload, and one they ignore is removed. Returns if any was.
+crate fn post_scan(cfg: &Cfg, pool: &Handle) -> Result<bool, Error> {
+    let wanted = match *HOOKS.lock() {
+        Some(ref scripts) => scripts.has_post_scan()?,
+        None => false,
+    };
+    if !wanted {
+        return Ok(false);
+    }
+    // Without holding the scripts, the rescans run the content hooks
+    pool.wait_idle();
+    let current = MAILBOXES.lock().values().cloned().collect::<Vec<_>>();
+    let hooks = HOOKS.lock();
+    let scripts = match *hooks {
+        Some(ref scripts) => scripts,
+        None => return Ok(false),
+    };
+    let mut changed = Vec::new();
+    for old in current {
+        let new = scripts.post_scan((*old).clone(), cfg.normalize_names)
+            .with_context(|_| format!("Failed to post-configure mbox {}", old.path.display()))?;
+        if !new.as_ref().map_or(false, |new| old.same_config(new)) {
+            changed.push((old, new));
+        }
+    }
+    scripts.log_stats();
+    drop(hooks);
+    if changed.is_empty() {
+        return Ok(false);
     }
 
-    // A subdirectory owned by some already scanned maildir (eg. "cur", "new" or "tmp")
-    if let (Some(parent), Some(last)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) {
-        entry.file_type().is_dir() && MDIR_SUBDIRS.contains(&last) && dedup.contains(parent)
-    } else {
-        false
+    let mut mailboxes = MAILBOXES.lock();
+    let mut replaced = Vec::new();
+    for (old, new) in changed {
+        // A reload might have replaced it in the meantime
+        if !mailboxes.get(&old.name).map_or(false, |mbox| Arc::ptr_eq(mbox, &old)) {
+            continue;
+        }
+        mailboxes.remove(&old.name);
+        let new = new.map(|new| register(&mut mailboxes, new));
+        replaced.push((old, new));
+    }
+    drop(mailboxes);
+    debug!("Post-scan callbacks changed {} mailboxes", replaced.len());
+    for (old, new) in replaced {
+        Notification::send(Notification::MailboxVanished(old));
+        if let Some(new) = new {
+            Notification::send(Notification::MailboxAppeared(new));
+        }
     }
+    if cfg.normalize_names {
+        check_normalized_names();
+    }
+    export::export(cfg);
+    Ok(true)
 }
 
-fn lua_load<P: AsRef<Path>>(lua: &Lua, script: P) -> Result<(), Error> {
-    debug!("Running lua script from {}", script.as_ref().display());
-    let mut f = File::open(&script)?;
-    // TODO: Once lua supports non-utf8 stuff, use Vec<u8>
-    let mut code = Vec::new();
-    f.read_to_end(&mut code)?;
-    lua.exec(&code, Some(&script.as_ref().to_string_lossy())).map_err(Error::from)
+/// Runs the content hooks if a rescan changed the counts of the mailbox.
+///
+/// The first read of a mailbox is not a change, nothing is known to compare with.
+fn content_changed(mbox: &Arc<Mailbox>, before: Option<(usize, Option<usize>)>) {
+    let after = mbox.counts();
+    let before = match before {
+        Some(before) if before != after => before,
+        _ => return,
+    };
+    let new = after.0.saturating_sub(before.0);
+    if let Some(ref scripts) = *HOOKS.lock() {
+        if let Err(e) = scripts.on_content(mbox, new, after.0) {
+            error!("Failed to run content hooks on mailbox {}: {}", mbox.name(), e);
+        }
+    }
 }
 
-fn configure_mbox(lua: &Lua, mbox: Mailbox) -> Result<Mailbox, Error> {
-    let cbacks = lua.named_registry_value::<Table>(CONFIG_CBACKS)?;
-    let handle = lua.create_userdata(mbox)?;
+/// Loads the configured scripts, to see if they work.
+crate fn check_scripts(cfg: &Cfg) -> Result<(), Error> {
+    Scripts::load(cfg).map(|_| ())
+}
 
-    for cback in cbacks.sequence_values::<Function>() {
-        let cback = cback?;
-        cback.call(handle.clone())?;
+/// Checks if a freshly found mailbox may be registered, considering the nesting policy.
+fn allow_nested(cfg: &Cfg, dedup: &PathTrie<Arc<Mailbox>>, path: &PathKey) -> bool {
+    let outer = match dedup.longest_prefix(path) {
+        Some((outer, _)) if outer != path.as_path() => outer,
+        _ => return true,
+    };
+    match cfg.nested_mailboxes {
+        NestedMailboxes::Register => true,
+        NestedMailboxes::Ignore => {
+            debug!("Ignoring mailbox {} nested in {}", path, outer.display());
+            false
+        }
+        NestedMailboxes::Warn => {
+            warn!("Skipping mailbox {} nested in mailbox {}", path, outer.display());
+            false
+        }
     }
+}
 
-    let result = handle.borrow::<Mailbox>()?.clone();
-    Ok(result)
+/// The maildir the entry is a Maildir++ subfolder of.
+///
+/// In that layout (used by Dovecot and Courier), all the folders of an account are maildirs right
+/// inside the main one, named like `.Lists.rust`. They are not hidden nor nested mailboxes, they
+/// are the way to have folders at all.
+fn maildirpp_parent<'a>(dedup: &'a PathTrie<Arc<Mailbox>>, entry: &DirEntry)
+    -> Option<&'a Arc<Mailbox>>
+{
+    let name = entry.file_name().to_str()?;
+    if !entry.file_type().is_dir() || !name.starts_with('.') || name.trim_matches('.').is_empty() {
+        return None;
+    }
+    let parent = dedup.get(PathKey::new(entry.path().parent()?))?;
+    let is_mdir = MDIR_SUBDIRS
+        .iter()
+        .all(|sub| entry.path().join(sub).is_dir());
+    if parent.tp == Type::Dir && is_mdir {
+        Some(parent)
+    } else {
+        None
+    }
 }
 
-crate fn initial_scan(cfg: &Cfg) -> Result<Queue, Error> {
-    let lua = Lua::new();
+/// The hierarchical name of a Maildir++ subfolder (`.Lists.rust` in `INBOX` is `INBOX/Lists/rust`).
+fn maildirpp_name(parent: &Mailbox, entry: &DirEntry) -> String {
+    let folder = entry.file_name().to_string_lossy();
+    folder
+        .split('.')
+        .filter(|part| !part.is_empty())
+        .fold(parent.name.clone(), |name, part| format!("{}/{}", name, part))
+}
 
-    trace!("Preparing configuration lua instance");
-    // Set up functions the scripts can call
-    lua.set_named_registry_value(CONFIG_CBACKS, lua.create_table()?)?;
-    // This'll allow them to register config callbacks
-    lua.globals().set("register_config", lua.create_function(|lua, c: Function| {
-        let cbacks = lua.named_registry_value::<Table>(CONFIG_CBACKS)?;
-        let len = cbacks.raw_len();
-        cbacks.raw_set(len + 1, c)
-    })?)?;
+/// The mailboxes found by a scan, not registered yet.
+struct Found {
+    mailboxes: HashMap<String, Arc<Mailbox>>,
+    /// The same mailboxes, in the order they were found.
+    order: Vec<Arc<Mailbox>>,
+    report: ScanReport,
+    /// What configured them, to become the hooks with them.
+    scripts: Scripts,
+}
 
-    for script in &cfg.scripts {
-        lua_load(&lua, script)
-            .with_context(|_| format!("Failed to load lua script {}", script.display()))?;
-    }
+impl Found {
+    /// Makes sure no two mailboxes share a shortcut.
+    fn check_shortcuts(&mut self, policy: ShortcutConflicts) -> Result<(), Error> {
+        let mut owners = HashMap::new();
+        let mut dropped = Vec::new();
+        for mbox in &self.order {
+            let sc = match mbox.shortcut {
+                Some(sc) => sc,
+                None => continue,
+            };
+            let owner = match owners.get(&sc) {
+                Some(owner) => Arc::clone(owner),
+                None => {
+                    owners.insert(sc, Arc::clone(mbox));
+                    continue;
+                }
+            };
+            if policy == ShortcutConflicts::Fail {
+                let err = format_err!("Mailboxes {} and {} both have the shortcut {}",
+                                      owner.name, mbox.name, sc);
+                return Err(err.context(Kind::Config).into());
+            }
+            let (keep, lose) = if mbox.prio > owner.prio {
+                (Arc::clone(mbox), owner)
+            } else {
+                (owner, Arc::clone(mbox))
+            };
+            warn!("Shortcut {} of mailbox {} is taken by {}, dropping it", sc, lose.name,
+                  keep.name);
+            owners.insert(sc, keep);
+            dropped.push(lose);
+        }
 
-    let mut dedup = HashSet::new();
-    let mut queue = Queue::new();
+        // Already shared all around, so they are replaced by copies without the shortcut
+        for lose in dropped {
+            let mut mbox = (*lose).clone();
+            mbox.shortcut = None;
+            let mbox = Arc::new(mbox);
+            self.mailboxes.insert(mbox.name.clone(), Arc::clone(&mbox));
+            if let Some(slot) = self.order.iter_mut().find(|slot| Arc::ptr_eq(slot, &lose)) {
+                *slot = mbox;
+            }
+        }
+        Ok(())
+    }
+}
 
-    for path in &cfg.storage.search {
+/// Walks the search roots and configures the mailboxes in them.
+///
+/// Nothing global is touched, so failing in the middle leaves whatever ran before in place.
+fn scan(cfg: &Cfg) -> Result<Found, Error> {
+    let meta = MetaIndex::new(cfg);
+    let exclude = cutoff::exclude_globs(cfg)?;
+    let mut dedup = PathTrie::new();
+    // Directories are reachable through symlinks from multiple places, look into each only once
+    let mut visited = HashSet::new();
+    let mut found = Found {
+        mailboxes: HashMap::new(),
+        order: Vec::new(),
+        report: ScanReport::default(),
+        scripts: Scripts::load(cfg)?,
+    };
+    for search in &cfg.storage.search {
+        let path = &search.path;
+        let mut root = RootReport::new(path.clone());
         let path_str = path.display();
         debug!("Looking for maildirs in {:?}", path_str);
-        let mut walkdir = WalkDir::new(path)
-            .follow_links(true)
-            .into_iter();
+        let mut walkdir = WalkDir::new(path).follow_links(true);
+        if let Some(depth) = search.max_depth.or(cfg.storage.max_depth) {
+            walkdir = walkdir.max_depth(depth);
+        }
+        let mut walkdir = walkdir.into_iter();
         loop {
-            match walkdir.next() {
+            let entry = match walkdir.next() {
                 None => break,
+                // Not an error of the filesystem, just a symlink to a directory we are already in.
+                // As the visited directories are not entered again, each such link comes up only
+                // once.
+                Some(Err(ref e)) if e.loop_ancestor().is_some() => {
+                    root.entries += 1;
+                    if let (Some(link), Some(ancestor)) = (e.path(), e.loop_ancestor()) {
+                        warn!("Not following {}, it loops back to {}", link.display(),
+                              ancestor.display());
+                        root.skip(link.to_owned(), LOOP);
+                    }
+                    continue;
+                }
                 Some(Err(e)) => {
+                    root.entries += 1;
+                    root.errors += 1;
+                    // Not even the root itself could be read, nothing more will come out of it
+                    if e.depth() == 0 {
+                        root.aborted = true;
+                    }
                     error!("Scanning for mailboxes in {}: {}", path_str, e);
+                    continue;
+                }
+                Some(Ok(entry)) => entry,
+            };
+            root.entries += 1;
+
+            let key = PathKey::new(entry.path());
+            let ctx = Context {
+                cfg,
+                dedup: &dedup,
+                visited: &visited,
+                key: &key,
+                root: path,
+                exclude: &exclude,
+            };
+            if let Some(rule) = cutoff::cutoff(&ctx, &entry) {
+                trace!("Not descending into {:?}: {}", entry.path(), rule.name);
+                if rule.reported {
+                    root.skip(entry.path().to_owned(), rule.name);
                 }
-                Some(Ok(ref entry)) if scan_cutoff(&dedup, entry) => {
-                    trace!("Not descending into {:?}", entry.path());
+                // Skipping the "current dir" after a file would skip the rest of its parent.
+                if entry.file_type().is_dir() {
                     walkdir.skip_current_dir();
                 }
-                Some(Ok(entry)) => match Mailbox::detect(&entry) {
-                    Err(e) => {
-                        error!("Detecting a mailbox in {}: {}", entry.path().display(), e);
+                continue;
+            }
+            if entry.file_type().is_dir() {
+                visited.insert(key.clone());
+            }
+
+            let folder_of = maildirpp_parent(&dedup, &entry);
+            match Mailbox::detect(&entry) {
+                Err(e) => {
+                    root.errors += 1;
+                    error!("Detecting a mailbox in {}: {}", entry.path().display(), e);
+                }
+                Ok(None) => trace!("No mailbox found in {}", entry.path().display()),
+                Ok(Some(_)) if folder_of.is_none() && !allow_nested(cfg, &dedup, &key) => {
+                    root.skip(entry.into_path(), NESTED);
+                }
+                Ok(Some(mut mbox)) => {
+                    if let Some(parent) = folder_of {
+                        mbox.name = maildirpp_name(parent, &entry);
                     }
-                    Ok(None) => trace!("No mailbox found in {}", entry.path().display()),
-                    Ok(Some(mbox)) => {
-                        let mbox = configure_mbox(&lua, mbox)
-                            .with_context(|_| {
-                                format!("Failed to configure mbox {}", entry.path().display())
-                            })?;
-                        let mbox = Arc::new(mbox);
-                        let name = mbox.name().to_owned();
-                        assert!(MAILBOXES.lock().insert(name, Arc::clone(&mbox)).is_none());
-                        queue.push(Task::rescan(Arc::clone(&mbox)));
-                        Notification::send(Notification::MailboxAppeared(mbox));
-                        assert!(dedup.insert(entry.into_path()));
+                    meta.apply(&key, &mut mbox);
+                    // Still known as a mailbox when ignored, so nothing inside it is picked up
+                    let detected = Arc::new(mbox.clone());
+                    let configured = found.scripts.configure(mbox, cfg.normalize_names)
+                        .with_context(|_| {
+                            format!("Failed to configure mbox {}", entry.path().display())
+                        })?;
+                    match configured {
+                        Some(mbox) => {
+                            root.mailboxes += 1;
+                            let mbox = register(&mut found.mailboxes, mbox);
+                            found.order.push(Arc::clone(&mbox));
+                            assert!(dedup.insert(key, mbox).is_none());
+                        }
+                        None => {
+                            if entry.file_type().is_dir() {
+                                walkdir.skip_current_dir();
+                            }
+                            root.skip(entry.into_path(), IGNORED);
+                            assert!(dedup.insert(key, detected).is_none());
+                        }
                     }
                 }
             }
         }
+
+        root.finish(cfg.storage.max_error_fraction.0);
+        if root.failed {
+            let msg = format!("Search root {} failed ({} of {} entries errored), its mailboxes \
+                               may be incomplete", path_str, root.errors, root.entries);
+            error!("{}", msg);
+            Notification::send(Notification::Error(msg));
+        }
+        found.report.roots.push(root);
     }
 
-    Ok(queue)
+    found.scripts.log_stats();
+    found.check_shortcuts(cfg.shortcut_conflicts)?;
+    Ok(found)
+}
+
+fn rescan_task(cfg: &Cfg, mbox: &Arc<Mailbox>) -> Task {
+    if cfg.full_rescan {
+        Task::full_rescan(Arc::clone(mbox))
+    } else {
+        Task::rescan(Arc::clone(mbox))
+    }
+}
+
+/// Checks and publishes what the freshly registered mailboxes are.
+fn scan_done(cfg: &Cfg, report: &ScanReport) {
+    if cfg.normalize_names {
+        check_normalized_names();
+    }
+    export::export(cfg);
+    *LAST_SCAN.lock() = report.clone();
+}
+
+crate fn initial_scan(cfg: &Cfg) -> Result<(Queue, ScanReport), Error> {
+    let found = scan(cfg)?;
+    let mut queue = Queue::new();
+    *MAILBOXES.lock() = found.mailboxes;
+    *HOOKS.lock() = Some(found.scripts);
+    for mbox in found.order {
+        queue.push(rescan_task(cfg, &mbox));
+        Notification::send(Notification::MailboxAppeared(mbox));
+    }
+    scan_done(cfg, &found.report);
+    Ok((queue, found.report))
+}
+
+/// Scans the search roots again and replaces the registered mailboxes with what was found.
+///
+/// A mailbox found again at the same path with the same configuration is kept as it was,
+/// including its cache. One whose configuration changed is replaced, but starts with the cache of
+/// the old one. Only the newly found mailboxes are rescanned.
+///
+/// If the scan fails, the registered mailboxes stay untouched.
+crate fn rescan_roots(cfg: &Cfg, pool: &Handle) -> Result<ScanReport, Error> {
+    let found = scan(cfg)?;
+    let mut mailboxes = MAILBOXES.lock();
+    let old = mailboxes
+        .values()
+        .map(|mbox| (mbox.path.clone(), Arc::clone(mbox)))
+        .collect::<HashMap<_, _>>();
+    let mut appeared = Vec::new();
+    let mut fresh = Vec::new();
+    let mut kept = HashMap::new();
+    for mbox in found.order {
+        let mbox = match old.get(&mbox.path) {
+            Some(existing) if existing.same_config(&mbox) => Arc::clone(existing),
+            Some(existing) => {
+                mbox.take_cache(existing);
+                appeared.push(Arc::clone(&mbox));
+                mbox
+            }
+            None => {
+                fresh.push(Arc::clone(&mbox));
+                appeared.push(Arc::clone(&mbox));
+                mbox
+            }
+        };
+        kept.insert(mbox.name.clone(), mbox);
+    }
+    let vanished = mailboxes
+        .drain()
+        .map(|(_, mbox)| mbox)
+        .filter(|mbox| !kept.get(&mbox.name).map_or(false, |k| Arc::ptr_eq(k, mbox)))
+        .collect::<Vec<_>>();
+    *mailboxes = kept;
+    drop(mailboxes);
+    *HOOKS.lock() = Some(found.scripts);
+    debug!("Rescanned the search roots, {} mailboxes appeared and {} vanished", appeared.len(),
+           vanished.len());
+
+    for mbox in vanished {
+        Notification::send(Notification::MailboxVanished(mbox));
+    }
+    for mbox in appeared {
+        Notification::send(Notification::MailboxAppeared(mbox));
+    }
+    // Only after announcing them, so their content doesn't come first
+    for mbox in fresh {
+        pool.push(rescan_task(cfg, &mbox));
+    }
+    scan_done(cfg, &found.report);
+    Ok(found.report)
 }
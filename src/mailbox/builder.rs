@@ -0,0 +1,136 @@
+//! Constructing mailboxes without looking at the filesystem.
+//!
+//! The detection builds its mailboxes through this too, so the checks of what makes a valid
+//! mailbox live in one place.
+
+use std::path::{Path, PathBuf};
+
+use failure::{bail, format_err, Error};
+use parking_lot::Mutex;
+
+use crate::path_key::PathKey;
+use super::{Cache, Mailbox, MboxFormat, Type};
+use super::mbox::Mbox;
+use super::mdir::Mdir;
+use super::mh::Mh;
+
+#[derive(Clone, Debug)]
+crate struct MailboxBuilder {
+    path: PathBuf,
+    name: Option<String>,
+    kind: String,
+    prio: usize,
+    shortcut: Option<String>,
+    hidden: bool,
+    counts: Option<(usize, Option<usize>)>,
+}
+
+impl MailboxBuilder {
+    /// Starts an mbox at the path, named by its last component.
+    crate fn new<P: AsRef<Path>>(path: P) -> Self {
+        MailboxBuilder {
+            path: path.as_ref().to_owned(),
+            name: None,
+            kind: Type::Plain.name().to_owned(),
+            prio: 0,
+            shortcut: None,
+            hidden: false,
+            counts: None,
+        }
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    crate fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = path.as_ref().to_owned();
+        self
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    crate fn with_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// The kind, as used in filters (eg. `maildir` or `mbox-gz`).
+    crate fn with_kind<K: Into<String>>(mut self, kind: K) -> Self {
+        self.kind = kind.into();
+        self
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    crate fn with_prio(mut self, prio: usize) -> Self {
+        self.prio = prio;
+        self
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    crate fn with_shortcut<S: Into<String>>(mut self, shortcut: S) -> Self {
+        self.shortcut = Some(shortcut.into());
+        self
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    crate fn with_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Makes the mailbox look like it was read already, with this many (unseen) messages.
+    ///
+    /// Only maildirs and MH folders know which messages are unseen.
+    #[cfg_attr(not(test), allow(dead_code))]
+    crate fn with_counts(mut self, total: usize, unseen: Option<usize>) -> Self {
+        self.counts = Some((total, unseen));
+        self
+    }
+
+    crate fn build(self) -> Result<Mailbox, Error> {
+        let tp = Type::from_name(&self.kind)
+            .ok_or_else(|| format_err!("Unknown mailbox kind {}", self.kind))?;
+        let shortcut = match self.shortcut {
+            None => None,
+            Some(shortcut) => {
+                let mut chars = shortcut.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => bail!("Shortcut {:?} is not a single character", shortcut),
+                }
+            }
+        };
+        let cache = match self.counts {
+            None => Cache::Unscanned,
+            Some((total, unseen)) => {
+                if unseen.map_or(false, |unseen| unseen > total) {
+                    bail!("More unseen messages than the {} messages", total);
+                }
+                match tp {
+                    Type::Dir => Cache::Mdir(Mdir::with_counts(total, unseen.unwrap_or(0))),
+                    Type::Mh => Cache::Mh(Mh::with_counts(total, unseen.unwrap_or(0))),
+                    _ if unseen.is_some() => {
+                        bail!("A mailbox of kind {} doesn't know unseen counts", self.kind);
+                    }
+                    _ => Cache::Mbox(Mbox::with_count(total)),
+                }
+            }
+        };
+        let path = self.path;
+        let name = self.name.unwrap_or_else(|| {
+            path.file_name()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "<???>".to_owned())
+        });
+        Ok(Mailbox {
+            key: PathKey::new(&path),
+            path,
+            name,
+            tp,
+            cache: Mutex::new(cache),
+            stamp: Mutex::new(None),
+            prio: self.prio,
+            shortcut,
+            hidden: self.hidden,
+            poll_interval: None,
+            mbox_format: MboxFormat::default(),
+        })
+    }
+}
@@ -0,0 +1,42 @@
+//! Stopping long running work from the outside.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use failure::{Error, Fail};
+
+/// The error of work stopped through its token.
+///
+/// It's neither a success nor a real failure, the work simply didn't finish.
+#[derive(Debug)]
+pub(super) struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str("Cancelled")
+    }
+}
+
+impl Fail for Cancelled {}
+
+/// A shared flag asking the work to stop.
+///
+/// The work checks it at bounded intervals (eg. every few messages) and bails out with
+/// `Cancelled`, leaving whatever it was building unused.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Token(Arc<AtomicBool>);
+
+impl Token {
+    pub(super) fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub(super) fn check(&self) -> Result<(), Error> {
+        if self.0.load(Ordering::Relaxed) {
+            Err(Cancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+}
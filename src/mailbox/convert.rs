@@ -0,0 +1,478 @@
+//! Conversion of a maildir into a single mbox file.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use failure::{bail, Error, ResultExt};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{debug, warn};
+
+use super::{tmp_path, MDIR_SUBDIRS};
+use super::mdir;
+
+crate struct Options {
+    crate include_trashed: bool,
+    crate force: bool,
+    crate compress: bool,
+}
+
+struct Message {
+    path: PathBuf,
+    time: u64,
+    new: bool,
+    flags: String,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    converted: usize,
+    read: usize,
+    flagged: usize,
+    skipped_trashed: usize,
+}
+
+const DAYS: &[&str] = &["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: &[&str] = &[
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a unix timestamp the way the From_ line wants it (asctime, in UTC).
+fn asctime(time: u64) -> String {
+    let days = (time / 86_400) as i64;
+    let secs = time % 86_400;
+    // Civil from days, by Howard Hinnant
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{} {} {:2} {:02}:{:02}:{:02} {}", DAYS[((days + 4) % 7) as usize],
+            MONTHS[(month - 1) as usize], day, secs / 3600, secs / 60 % 60, secs % 60, year)
+}
+
+/// Splits the data into lines, keeping the line terminators.
+fn lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    for (idx, &b) in data.iter().enumerate() {
+        if b == b'\n' {
+            result.push(&data[start..=idx]);
+            start = idx + 1;
+        }
+    }
+    if start < data.len() {
+        result.push(&data[start..]);
+    }
+    result
+}
+
+/// Splits the message into the header block (including the final empty line) and the body.
+fn split_header(data: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+    for line in lines(data) {
+        pos += line.len();
+        if line == b"\n" || line == b"\r\n" {
+            break;
+        }
+    }
+    data.split_at(pos)
+}
+
+fn header_name(line: &[u8]) -> Option<&[u8]> {
+    line.iter().position(|&b| b == b':').map(|pos| &line[..pos])
+}
+
+/// Rewrites the header block, dropping any Status and X-Status and putting ours at the end.
+fn rewrite_header(header: &[u8], status: &[u8], x_status: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(header.len() + 32);
+    let mut skipping = false;
+    let mut terminator: &[u8] = b"\n";
+    for line in lines(header) {
+        if line == b"\n" || line == b"\r\n" {
+            terminator = line;
+            break;
+        }
+        let continuation = line.first().map(|&b| b == b' ' || b == b'\t') == Some(true);
+        if !continuation {
+            skipping = header_name(line)
+                .map(|name| {
+                    name.eq_ignore_ascii_case(b"Status") || name.eq_ignore_ascii_case(b"X-Status")
+                })
+                .unwrap_or(false);
+        }
+        if !skipping {
+            out.extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                out.push(b'\n');
+            }
+        }
+    }
+    if !status.is_empty() {
+        out.extend_from_slice(b"Status: ");
+        out.extend_from_slice(status);
+        out.push(b'\n');
+    }
+    if !x_status.is_empty() {
+        out.extend_from_slice(b"X-Status: ");
+        out.extend_from_slice(x_status);
+        out.push(b'\n');
+    }
+    out.extend_from_slice(terminator);
+    out
+}
+
+/// The envelope sender for the From_ line.
+fn sender(header: &[u8]) -> String {
+    for line in lines(header) {
+        if header_name(line).map(|n| n.eq_ignore_ascii_case(b"Return-Path")) != Some(true) {
+            continue;
+        }
+        let value = String::from_utf8_lossy(&line[b"Return-Path:".len()..]);
+        let value = value.trim().trim_start_matches('<').trim_end_matches('>');
+        if !value.is_empty() && !value.contains(char::is_whitespace) {
+            return value.to_owned();
+        }
+    }
+    "MAILER-DAEMON".to_owned()
+}
+
+/// Is it a line that needs another `>` in mboxrd (`From ` prefixed by any number of `>`)?
+fn needs_quoting(line: &[u8]) -> bool {
+    let unquoted = line.iter().position(|&b| b != b'>').map(|pos| &line[pos..]);
+    unquoted.map(|rest| rest.starts_with(b"From ")) == Some(true)
+}
+
+fn write_message<W: Write>(out: &mut W, msg: &Message, data: &[u8]) -> Result<(), Error> {
+    let (header, body) = split_header(data);
+    let read = msg.flags.contains('S');
+    let mut x_status = Vec::new();
+    if msg.flags.contains('R') {
+        x_status.push(b'A');
+    }
+    if msg.flags.contains('F') {
+        x_status.push(b'F');
+    }
+    if msg.flags.contains('T') {
+        x_status.push(b'D');
+    }
+    // Messages still in new/ have not been seen by any client, so they don't get the O(ld) status
+    let status: &[u8] = match (msg.new, read) {
+        (_, true) => b"RO",
+        (false, false) => b"O",
+        (true, false) => b"",
+    };
+
+    writeln!(out, "From {} {}", sender(header), asctime(msg.time))?;
+    out.write_all(&rewrite_header(header, status, &x_status))?;
+    let mut last_nl = true;
+    for line in lines(body) {
+        if needs_quoting(line) {
+            out.write_all(b">")?;
+        }
+        out.write_all(line)?;
+        last_nl = line.ends_with(b"\n");
+    }
+    if !last_nl {
+        out.write_all(b"\n")?;
+    }
+    // The empty line separating the messages
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+fn collect(maildir: &Path) -> Result<Vec<Message>, Error> {
+    let mut messages = Vec::new();
+    for &(sub, new) in &[("cur", false), ("new", true)] {
+        let dir = maildir.join(sub);
+        for entry in fs::read_dir(&dir).with_context(|_| format!("Can't read {}", dir.display()))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') || !entry.file_type()?.is_file() {
+                continue;
+            }
+            // Delivery time is at the start of the name by convention, fall back to mtime.
+            let time = name
+                .split('.')
+                .next()
+                .and_then(|t| t.parse().ok())
+                .or_else(|| {
+                    entry
+                        .metadata()
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs())
+                })
+                .unwrap_or(0);
+            messages.push(Message {
+                path: entry.path(),
+                time,
+                new,
+                flags: mdir::flags(&name).unwrap_or("").to_owned(),
+            });
+        }
+    }
+    messages.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.path.cmp(&b.path)));
+    Ok(messages)
+}
+
+fn write_all<W: Write>(out: &mut W, messages: &[Message], opts: &Options)
+    -> Result<Summary, Error>
+{
+    let mut summary = Summary::default();
+    for msg in messages {
+        if msg.flags.contains('T') && !opts.include_trashed {
+            summary.skipped_trashed += 1;
+            continue;
+        }
+        let data = match fs::read(&msg.path) {
+            Ok(data) => data,
+            // It got removed or moved (eg. from new to cur) under our hands.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("Message {} disappeared during conversion", msg.path.display());
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        write_message(out, msg, &data)?;
+        summary.converted += 1;
+        if msg.flags.contains('S') {
+            summary.read += 1;
+        }
+        if msg.flags.contains('F') {
+            summary.flagged += 1;
+        }
+    }
+    Ok(summary)
+}
+
+/// Converts a maildir into an mbox (in the mboxrd flavour).
+///
+/// The result is written into a temporary file first and renamed into place once complete.
+crate fn to_mbox(maildir: &Path, mbox: &Path, opts: &Options) -> Result<(), Error> {
+    if !MDIR_SUBDIRS.iter().all(|sub| maildir.join(sub).is_dir()) {
+        bail!("{} is not a maildir", maildir.display());
+    }
+    if mbox.exists() && !opts.force {
+        bail!("{} already exists (use --force to overwrite)", mbox.display());
+    }
+    let messages = collect(maildir)?;
+    debug!("Converting {} messages from {}", messages.len(), maildir.display());
+
+    let tmp = tmp_path(mbox);
+    let f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .with_context(|_| format!("Can't create {}", tmp.display()))?;
+    let result = (|| -> Result<Summary, Error> {
+        let mut out = BufWriter::new(f);
+        let summary = if opts.compress {
+            let mut gz = GzEncoder::new(out, Compression::default());
+            let summary = write_all(&mut gz, &messages, opts)?;
+            out = gz.finish()?;
+            summary
+        } else {
+            write_all(&mut out, &messages, opts)?
+        };
+        let f: File = out.into_inner().map_err(|e| e.into_error())?;
+        f.sync_all()?;
+        fs::rename(&tmp, mbox)?;
+        Ok(summary)
+    })();
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+
+    println!("Converted {} messages from {} into {}", summary.converted, maildir.display(),
+             mbox.display());
+    println!("    {} read, {} flagged, {} unread", summary.read, summary.flagged,
+             summary.converted - summary.read);
+    if summary.skipped_trashed > 0 {
+        println!("    {} trashed messages skipped", summary.skipped_trashed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use super::super::cancel::Token;
+    use super::super::mbox::{Format, Mbox};
+    use crate::fixtures::{self, TempDir};
+
+    const HEADER: &str = "Return-Path: <a@example.com>\nSubject: Hello\nStatus: U\n\n";
+
+    /// The messages of the test maildir, by their place and file name, and their bodies.
+    const MESSAGES: &[(&str, &str, &str)] = &[
+        ("cur", "1500000000.M1P1.test:2,S", "Plain\nbody\n"),
+        ("cur", "1500000001.M1P1.test:2,", "From the start\n>From quoted\n>>From twice\n"),
+        ("cur", "1500000002.M1P1.test:2,RF", "Fromage is not a separator\n\nFrom after empty\n"),
+        ("cur", "1500000003.M1P1.test:2,ST", "Trashed\n"),
+        ("new", "1500000004.M1P1.test", "Fresh\r\nwith CRLF\r\n"),
+        ("cur", "1500000005.M1P1.test:2,S", "No newline at the end"),
+    ];
+
+    fn maildir(dir: &Path) -> PathBuf {
+        let mdir = dir.join("mdir");
+        for sub in MDIR_SUBDIRS {
+            fs::create_dir_all(mdir.join(sub)).unwrap();
+        }
+        for (sub, name, body) in MESSAGES {
+            fixtures::write(mdir.join(sub).join(name), format!("{}{}", HEADER, body));
+        }
+        mdir
+    }
+
+    fn opts(include_trashed: bool, force: bool, compress: bool) -> Options {
+        Options {
+            include_trashed,
+            force,
+            compress,
+        }
+    }
+
+    /// Splits the mbox back into the messages with the quoting undone.
+    fn read_back(data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mbox = Mbox::parse(data, Format::Mboxrd, &Token::default()).unwrap();
+        mbox.messages()
+            .map(|msg| {
+                let start = msg.offset as usize;
+                let raw = &data[start..start + msg.length as usize];
+                let from_end = raw.iter().position(|&b| b == b'\n').unwrap() + 1;
+                let (header, body) = split_header(&raw[from_end..]);
+                let mut unquoted = Vec::new();
+                for line in lines(body) {
+                    if line.starts_with(b">") && needs_quoting(&line[1..]) {
+                        unquoted.extend_from_slice(&line[1..]);
+                    } else {
+                        unquoted.extend_from_slice(line);
+                    }
+                }
+                (header.to_vec(), unquoted)
+            })
+            .collect()
+    }
+
+    fn status(header: &[u8], name: &str) -> Option<String> {
+        let prefix = format!("{}: ", name);
+        lines(header)
+            .into_iter()
+            .map(|line| String::from_utf8_lossy(line).trim_end().to_owned())
+            .find(|line| line.starts_with(&prefix))
+            .map(|line| line[prefix.len()..].to_owned())
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new();
+        let mdir = maildir(dir.path());
+        let target = dir.path().join("out");
+        to_mbox(&mdir, &target, &opts(false, false, false)).unwrap();
+        let data = fs::read(&target).unwrap();
+        assert!(data.starts_with(b"From a@example.com Fri Jul 14 02:40:00 2017\n"));
+
+        let messages = read_back(&data);
+        let kept = MESSAGES.iter().filter(|(_, name, _)| !name.contains('T')).collect::<Vec<_>>();
+        assert_eq!(kept.len(), messages.len());
+        let flags = [
+            (Some("RO"), None),
+            (Some("O"), None),
+            (Some("O"), Some("AF")),
+            (None, None),
+            (Some("RO"), None),
+        ];
+        let expected = kept.iter().zip(&flags);
+        for (((_, name, body), (st, x_st)), (header, read)) in expected.zip(messages) {
+            let mut body = body.as_bytes().to_vec();
+            if !body.ends_with(b"\n") {
+                body.push(b'\n');
+            }
+            assert_eq!(body, read, "{}", name);
+            assert_eq!(st.map(str::to_owned), status(&header, "Status"), "{}", name);
+            assert_eq!(x_st.map(str::to_owned), status(&header, "X-Status"), "{}", name);
+            assert_eq!(Some("Hello".to_owned()), status(&header, "Subject"));
+        }
+        // Nothing left behind
+        assert_eq!(2, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn quoting() {
+        let quoted = ["From x\n", ">From x\n", ">>>From x\n"];
+        let plain = ["Fromage\n", " From x\n", ">From\n", "> From x\n", "\n"];
+        assert!(quoted.iter().all(|line| needs_quoting(line.as_bytes())));
+        assert!(plain.iter().all(|line| !needs_quoting(line.as_bytes())));
+    }
+
+    #[test]
+    fn trashed() {
+        let dir = TempDir::new();
+        let mdir = maildir(dir.path());
+        let target = dir.path().join("out");
+        to_mbox(&mdir, &target, &opts(true, false, false)).unwrap();
+        let messages = read_back(&fs::read(&target).unwrap());
+        assert_eq!(MESSAGES.len(), messages.len());
+        let (header, body) = &messages[3];
+        assert_eq!(b"Trashed\n", &body[..]);
+        assert_eq!(Some("RO".to_owned()), status(header, "Status"));
+        assert_eq!(Some("D".to_owned()), status(header, "X-Status"));
+    }
+
+    #[test]
+    fn force() {
+        let dir = TempDir::new();
+        let mdir = maildir(dir.path());
+        let target = dir.path().join("out");
+        fixtures::write(&target, "precious");
+        assert!(to_mbox(&mdir, &target, &opts(false, false, false)).is_err());
+        assert_eq!(b"precious", &fs::read(&target).unwrap()[..]);
+        to_mbox(&mdir, &target, &opts(false, true, false)).unwrap();
+        assert_eq!(5, read_back(&fs::read(&target).unwrap()).len());
+        assert!(to_mbox(&dir.path().join("nothing"), &target, &opts(false, true, false)).is_err());
+    }
+
+    #[test]
+    fn compress() {
+        let dir = TempDir::new();
+        let mdir = maildir(dir.path());
+        let plain = dir.path().join("plain");
+        let compressed = dir.path().join("compressed.gz");
+        to_mbox(&mdir, &plain, &opts(false, false, false)).unwrap();
+        to_mbox(&mdir, &compressed, &opts(false, false, true)).unwrap();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(File::open(&compressed).unwrap())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(fs::read(&plain).unwrap(), decompressed);
+    }
+
+    /// A temporary file of a killed run doesn't block the next ones.
+    #[test]
+    fn leftover_tmp() {
+        let dir = TempDir::new();
+        let mdir = maildir(dir.path());
+        let target = dir.path().join("out");
+        let leftover = tmp_path(&target);
+        fixtures::write(&leftover, "half written");
+        fixtures::write(dir.path().join("out.tmp"), "half written");
+        to_mbox(&mdir, &target, &opts(false, false, false)).unwrap();
+        assert_eq!(5, read_back(&fs::read(&target).unwrap()).len());
+        assert!(leftover.exists(), "Someone else's file is not touched");
+    }
+}
@@ -0,0 +1,144 @@
+//! Rules deciding which parts of the search roots are not looked into.
+//!
+//! They are consulted in order for each entry of the walk and the first one that applies prunes
+//! the entry (and, if it is a directory, everything inside it).
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use failure::{Error, ResultExt};
+use walkdir::DirEntry;
+
+use crate::config::Cfg;
+use crate::glob::Glob;
+use crate::path_key::PathKey;
+use crate::path_trie::PathTrie;
+use super::{maildirpp_parent, Mailbox, MDIR_SUBDIRS};
+
+/// What the rules get to look at besides the entry itself.
+pub(super) struct Context<'a> {
+    pub(super) cfg: &'a Cfg,
+    /// The already found mailboxes, by their keys.
+    pub(super) dedup: &'a PathTrie<Arc<Mailbox>>,
+    /// Keys of the directories already looked into.
+    pub(super) visited: &'a HashSet<PathKey>,
+    /// Key of the entry being looked at.
+    pub(super) key: &'a PathKey,
+    /// The search root the entry was found in.
+    pub(super) root: &'a Path,
+    pub(super) exclude: &'a [Arc<Glob>],
+}
+
+/// Compiles the storage.exclude patterns.
+pub(super) fn exclude_globs(cfg: &Cfg) -> Result<Vec<Arc<Glob>>, Error> {
+    cfg.storage
+        .exclude
+        .iter()
+        .map(|pattern| {
+            Glob::cached(pattern)
+                .with_context(|_| format!("Invalid exclude pattern {}", pattern))
+                .map_err(Error::from)
+        })
+        .collect()
+}
+
+pub(super) struct Rule {
+    pub(super) name: &'static str,
+    /// Entries pruned by the rule are mailbox candidates worth listing as skipped (the parts of a
+    /// maildir are not).
+    pub(super) reported: bool,
+    applies: fn(&Context, &DirEntry) -> bool,
+}
+
+// Patterns with a slash match the path relative to the search root, the others the name at any
+// depth (like in .gitignore)
+fn excluded(ctx: &Context, entry: &DirEntry) -> bool {
+    let relative = match entry.path().strip_prefix(ctx.root) {
+        Ok(relative) if relative != Path::new("") => relative,
+        // The search root itself is never excluded
+        _ => return false,
+    };
+    ctx.exclude.iter().any(|glob| {
+        if glob.has_separator() {
+            glob.is_match(relative)
+        } else {
+            glob.is_match(entry.file_name())
+        }
+    })
+}
+
+// Dot-directories are mostly tooling (.git, .cache, .notmuch), not mail. Except for the Maildir++
+// folders.
+fn hidden(ctx: &Context, entry: &DirEntry) -> bool {
+    let storage = &ctx.cfg.storage;
+    let name = entry.file_name().to_string_lossy();
+    entry.file_type().is_dir()
+        && name.starts_with('.')
+        && !storage.scan_hidden
+        && entry.path() != ctx.root
+        && !storage.allow_hidden.iter().any(|allowed| *allowed == name)
+        && maildirpp_parent(ctx.dedup, entry).is_none()
+}
+
+fn duplicate(ctx: &Context, _entry: &DirEntry) -> bool {
+    ctx.dedup.contains(ctx.key)
+}
+
+// The same directory, reached again through a symlink (or an overlapping search root)
+fn visited(ctx: &Context, entry: &DirEntry) -> bool {
+    entry.file_type().is_dir() && ctx.visited.contains(ctx.key)
+}
+
+// A subdirectory owned by some already scanned maildir (eg. "cur", "new" or "tmp"). Everything in
+// there is a message, even if it starts with a From line, so it's not a nested mailbox.
+fn mdir_subdir(ctx: &Context, entry: &DirEntry) -> bool {
+    let path = entry.path();
+    if let (Some(parent), Some(last)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+        entry.file_type().is_dir()
+            && MDIR_SUBDIRS.contains(&last)
+            && ctx.dedup.contains(PathKey::new(parent))
+    } else {
+        false
+    }
+}
+
+pub(super) const RULES: &[Rule] = &[
+    // First, so excluded files are never even opened
+    Rule {
+        name: "excluded by storage.exclude",
+        reported: true,
+        applies: excluded,
+    },
+    Rule {
+        name: "hidden directory",
+        reported: false,
+        applies: hidden,
+    },
+    Rule {
+        name: "already registered as a mailbox",
+        reported: true,
+        applies: duplicate,
+    },
+    Rule {
+        name: "already looked into through another path",
+        reported: false,
+        applies: visited,
+    },
+    Rule {
+        name: "subdirectory of a registered maildir",
+        reported: false,
+        applies: mdir_subdir,
+    },
+];
+
+impl Rule {
+    pub(super) fn applies(&self, ctx: &Context, entry: &DirEntry) -> bool {
+        (self.applies)(ctx, entry)
+    }
+}
+
+/// Finds the first rule pruning this entry, if any.
+pub(super) fn cutoff(ctx: &Context, entry: &DirEntry) -> Option<&'static Rule> {
+    RULES.iter().find(|rule| rule.applies(ctx, entry))
+}
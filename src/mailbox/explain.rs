@@ -0,0 +1,246 @@
+//! Explanation of what the scan would do with a single path.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use failure::{Error, ResultExt};
+use walkdir::WalkDir;
+
+use crate::config::Cfg;
+use crate::path_key::PathKey;
+use crate::path_trie::PathTrie;
+use super::{allow_nested, maildirpp_name, maildirpp_parent, Mailbox, Type, IGNORED, NESTED};
+use super::cutoff::{self, Context};
+use super::meta::MetaIndex;
+use super::script::Scripts;
+
+/// Prints the rules consulted for the path and each of its ancestors up to the search root.
+///
+/// Only the ancestors are looked at, not the rest of the tree, so mailboxes found elsewhere (eg.
+/// the same mailbox reachable through another search root) are not known. The storage.meta and lua
+/// configuration is run for the mailboxes found on the way.
+crate fn explain(cfg: &Cfg, path: &Path) -> Result<(), Error> {
+    let stdout = io::stdout();
+    explain_to(cfg, path, &mut stdout.lock())
+}
+
+fn explain_to(cfg: &Cfg, path: &Path, out: &mut dyn Write) -> Result<(), Error> {
+    let search = cfg
+        .storage
+        .search
+        .iter()
+        .find(|search| path.starts_with(&search.path));
+    let (root, max_depth) = match search {
+        Some(search) => (&search.path, search.max_depth.or(cfg.storage.max_depth)),
+        None => {
+            writeln!(out, "{}: not under any search root, would not be scanned",
+                     path.display())?;
+            return Ok(());
+        }
+    };
+    writeln!(out, "{}: under search root {}", path.display(), root.display())?;
+
+    let scripts = Scripts::load(cfg)?;
+    let meta = MetaIndex::new(cfg);
+    let exclude = cutoff::exclude_globs(cfg)?;
+    let mut dedup = PathTrie::new();
+    let mut visited = HashSet::new();
+
+    let relative = path.strip_prefix(root)?;
+    let depth = relative.components().count();
+    if let Some(max_depth) = max_depth {
+        if depth > max_depth {
+            writeln!(out, "{}: {} levels deep, more than max_depth {}, would not be scanned",
+                     path.display(), depth, max_depth)?;
+            return Ok(());
+        }
+    }
+    let mut current = root.to_owned();
+    let mut levels = vec![current.clone()];
+    for component in relative.components() {
+        current.push(component);
+        levels.push(current.clone());
+    }
+
+    for level in levels {
+        let last = level == path;
+        let entry = WalkDir::new(&level)
+            .follow_links(true)
+            .max_depth(0)
+            .into_iter()
+            .next()
+            .expect("Walkdir always yields at least the root");
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                writeln!(out, "{}: can't be read: {}", level.display(), e)?;
+                return Ok(());
+            }
+        };
+        writeln!(out, "{}:", level.display())?;
+        let key = PathKey::new(&level);
+        let ctx = Context {
+            cfg,
+            dedup: &dedup,
+            visited: &visited,
+            key: &key,
+            root,
+            exclude: &exclude,
+        };
+        for rule in cutoff::RULES {
+            if rule.applies(&ctx, &entry) {
+                writeln!(out, "    {}: pruned", rule.name)?;
+                if !last {
+                    writeln!(out, "{}: not scanned, an ancestor is pruned", path.display())?;
+                }
+                return Ok(());
+            }
+            writeln!(out, "    {}: no", rule.name)?;
+        }
+        if entry.file_type().is_dir() {
+            visited.insert(key.clone());
+        }
+        writeln!(out, "    would be probed, detection result: {}",
+                 describe(Type::guess(&entry)))?;
+        if let Some(mut mbox) = Mailbox::detect(&entry)? {
+            let folder_of = maildirpp_parent(&dedup, &entry);
+            if let Some(parent) = folder_of {
+                writeln!(out, "    Maildir++ subfolder of {}", parent.name())?;
+                mbox.name = maildirpp_name(parent, &entry);
+            } else if !allow_nested(cfg, &dedup, &key) {
+                writeln!(out, "    {}, not registered (nested_mailboxes = {:?})", NESTED,
+                         cfg.nested_mailboxes)?;
+                continue;
+            }
+            meta.apply(&key, &mut mbox);
+            let configured = scripts.configure(mbox, cfg.normalize_names)
+                .with_context(|_| format!("Failed to configure mbox {}", level.display()))?;
+            match configured {
+                Some(mbox) => {
+                    writeln!(out, "    registered as mailbox {}", mbox.name())?;
+                    dedup.insert(key, Arc::new(mbox));
+                }
+                None => {
+                    writeln!(out, "    {}, not registered", IGNORED)?;
+                    if !last {
+                        writeln!(out, "{}: not scanned, an ancestor is ignored",
+                                 path.display())?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe(guess: Result<Option<Type>, Error>) -> String {
+    match guess {
+        Ok(Some(tp)) => tp.name().to_owned(),
+        Ok(None) => "not a mailbox".to_owned(),
+        Err(e) => format!("error: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use crate::config;
+    use crate::fixtures::{MaildirSpec, MboxSpec, Rng, TempDir};
+    use super::*;
+
+    fn explanation(root: &Path, extra: &str, path: &Path) -> Vec<String> {
+        let cfg = config::parse(&format!("[storage]\nsearch = [{:?}]\n{}\n",
+                                         root.display().to_string(), extra));
+        let mut out = Vec::new();
+        explain_to(&cfg, path, &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(str::to_owned).collect()
+    }
+
+    /// The rule prunes the level and nothing further down is looked at.
+    fn assert_pruned(out: &[String], level: &Path, rule: &str) {
+        let level_line = format!("{}:", level.display());
+        let pos = out.iter().position(|line| *line == level_line).expect(&level_line);
+        let after = &out[pos + 1..];
+        let names = cutoff::RULES.iter().map(|rule| rule.name).collect::<Vec<_>>();
+        let idx = names.iter().position(|name| *name == rule).unwrap();
+        for (line, name) in after.iter().zip(&names[..idx]) {
+            assert_eq!(format!("    {}: no", name), *line, "{:?}", out);
+        }
+        assert_eq!(format!("    {}: pruned", rule), after[idx], "{:?}", out);
+        assert!(after[idx + 1..].iter().all(|line| !line.starts_with("    ")), "{:?}", out);
+    }
+
+    /// Each of the rules, pruning a level on the way (or the path itself).
+    #[test]
+    fn rules() {
+        let dir = TempDir::new();
+        let root = dir.path().join("mail");
+        let mut rng = Rng::new(12);
+        let mdir = root.join("mdir");
+        MaildirSpec::default().build(&mdir, &mut rng);
+        MboxSpec::default().build(&root.join("skip").join("mbox"), &mut rng);
+        MboxSpec::default().build(&root.join(".hidden").join("mbox"), &mut rng);
+        symlink(".", mdir.join("again")).unwrap();
+        symlink(".", root.join("loop")).unwrap();
+
+        let cases = vec![
+            ("excluded by storage.exclude", root.join("skip"), root.join("skip").join("mbox")),
+            ("hidden directory", root.join(".hidden"), root.join(".hidden").join("mbox")),
+            ("already registered as a mailbox", mdir.join("again"), mdir.join("again")),
+            ("already looked into through another path", root.join("loop"), root.join("loop")),
+            ("subdirectory of a registered maildir", mdir.join("cur"), mdir.join("cur")),
+        ];
+        // None forgotten, if there's a new rule it needs a case here
+        let mut names = cases.iter().map(|case| case.0).collect::<Vec<_>>();
+        names.sort();
+        let mut rules = cutoff::RULES.iter().map(|rule| rule.name).collect::<Vec<_>>();
+        rules.sort();
+        assert_eq!(rules, names);
+
+        for (rule, level, path) in cases {
+            let out = explanation(&root, "exclude = [\"skip\"]", &path);
+            assert_pruned(&out, &level, rule);
+            let not_scanned = format!("{}: not scanned, an ancestor is pruned", path.display());
+            assert_eq!(level != path, out.contains(&not_scanned), "{:?}", out);
+        }
+        // Without the exclusion, the same path is found
+        let out = explanation(&root, "", &root.join("skip").join("mbox"));
+        assert_eq!("    registered as mailbox mbox", out.last().unwrap());
+    }
+
+    /// None of the rules apply, so it's probed and registered.
+    #[test]
+    fn probed() {
+        let dir = TempDir::new();
+        let root = dir.path().join("mail");
+        let mut rng = Rng::new(13);
+        let mbox = root.join("sub").join("inbox");
+        MboxSpec::default().build(&mbox, &mut rng);
+
+        let out = explanation(&root, "", &mbox);
+        let header = format!("{}: under search root {}", mbox.display(), root.display());
+        let mut expected = vec![header];
+        for (level, detected) in &[(&root, "not a mailbox"), (&root.join("sub"), "not a mailbox"),
+                                   (&mbox, "mbox")] {
+            expected.push(format!("{}:", level.display()));
+            for rule in cutoff::RULES {
+                expected.push(format!("    {}: no", rule.name));
+            }
+            expected.push(format!("    would be probed, detection result: {}", detected));
+        }
+        expected.push("    registered as mailbox inbox".to_owned());
+        assert_eq!(expected, out);
+
+        let outside = explanation(&root, "", dir.path());
+        let expected = format!("{}: not under any search root, would not be scanned",
+                               dir.path().display());
+        assert_eq!(vec![expected], outside);
+        let deep = explanation(&root, "max_depth = 1", &mbox);
+        assert!(deep[1].ends_with("2 levels deep, more than max_depth 1, would not be scanned"),
+                "{:?}", deep);
+    }
+}
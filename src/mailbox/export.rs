@@ -0,0 +1,271 @@
+//! Writing the list of mailboxes into files for other programs to consume.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use failure::Error;
+use log::{debug, error, warn};
+
+use crate::config::{Cfg, ExportFormat};
+use super::{tmp_path, Mailbox, Notification, MAILBOXES};
+
+/// Quotes the bytes for mutt's parser.
+///
+/// Single quotes have no escapes inside them, so a single quote is written as `'\''`, like in
+/// shell.
+fn mutt_quote(out: &mut Vec<u8>, s: &[u8]) {
+    out.push(b'\'');
+    for &b in s {
+        if b == b'\'' {
+            out.extend_from_slice(b"'\\''");
+        } else {
+            out.push(b);
+        }
+    }
+    out.push(b'\'');
+}
+
+fn render(format: ExportFormat, mailboxes: &[Arc<Mailbox>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    match format {
+        ExportFormat::MuttMailboxes => {
+            out.extend_from_slice(b"mailboxes");
+            for mbox in mailboxes {
+                let path = mbox.path.as_os_str().as_bytes();
+                // Mutt won't take a newline even inside quotes, it ends the command
+                if path.contains(&b'\n') {
+                    warn!("Can't put {} into mutt mailboxes, it contains a newline",
+                          mbox.path.display());
+                    continue;
+                }
+                out.push(b' ');
+                mutt_quote(&mut out, path);
+            }
+            out.push(b'\n');
+        }
+        ExportFormat::PathList => {
+            for mbox in mailboxes {
+                let path = mbox.path.as_os_str().as_bytes();
+                if path.contains(&b'\n') {
+                    warn!("Can't put {} into a path list, it contains a newline",
+                          mbox.path.display());
+                    continue;
+                }
+                out.extend_from_slice(path);
+                out.push(b'\n');
+            }
+        }
+    }
+    out
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_owned(),
+    }
+}
+
+/// Writes the content into a temporary file next to the target and renames it over the target.
+///
+/// Readers therefore see either the old or the new content as a whole, never a half-written file.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+    let tmp = tmp_path(path);
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .and_then(|mut f| {
+            f.write_all(content)?;
+            f.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result?;
+    Ok(())
+}
+
+/// The mailboxes to export, the important ones first.
+fn listed<'a, I: IntoIterator<Item = &'a Arc<Mailbox>>>(mailboxes: I) -> Vec<Arc<Mailbox>> {
+    let mut listed = mailboxes
+        .into_iter()
+        .filter(|mbox| !mbox.hidden)
+        .cloned()
+        .collect::<Vec<_>>();
+    listed.sort_by(|a, b| b.prio.cmp(&a.prio).then_with(|| a.name.cmp(&b.name)));
+    listed
+}
+
+/// Writes all the configured export files.
+///
+/// Failures are reported, but don't stop anything else.
+crate fn export(cfg: &Cfg) {
+    if cfg.export.is_empty() {
+        return;
+    }
+    let mailboxes = listed(MAILBOXES.lock().values());
+    for (&format, path) in &cfg.export {
+        let path = expand_home(path);
+        debug!("Exporting {:?} into {}", format, path.display());
+        if let Err(e) = write_atomic(&path, &render(format, &mailboxes)) {
+            let msg = format!("Failed to export mailboxes into {}: {}", path.display(), e);
+            error!("{}", msg);
+            Notification::send(Notification::Error(msg));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use std::thread;
+
+    use crate::fixtures::{self, TempDir};
+    use crate::mailbox::MailboxBuilder;
+    use crate::mutt;
+    use super::*;
+
+    fn mbox(path: &str, prio: usize, hidden: bool) -> Arc<Mailbox> {
+        let mbox = MailboxBuilder::new(path)
+            .with_prio(prio)
+            .with_hidden(hidden)
+            .build()
+            .unwrap();
+        Arc::new(mbox)
+    }
+
+    const NASTY: &[&str] = &[
+        "/plain/path",
+        "/with space/and  two",
+        "/single'quote",
+        "/''double'single",
+        "/double\"quote",
+        "/dollar$HOME/${x}",
+        "/back\\slash\\",
+        "/hash # not a comment",
+        "/tab\there",
+        "/all of 'em \"$\\ together'",
+    ];
+
+    /// Whatever we write, mutt reads back as the same paths.
+    #[test]
+    fn mutt_round_trip() {
+        let mailboxes = NASTY.iter().map(|p| mbox(p, 0, false)).collect::<Vec<_>>();
+        let out = render(ExportFormat::MuttMailboxes, &mailboxes);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.ends_with('\n'));
+        assert_eq!(out.lines().count(), 1);
+        let tokens = mutt::tokenize(out.trim_end()).unwrap();
+        assert_eq!(tokens[0], "mailboxes");
+        assert_eq!(&tokens[1..], NASTY);
+
+        for path in NASTY {
+            let mut quoted = Vec::new();
+            mutt_quote(&mut quoted, path.as_bytes());
+            let quoted = String::from_utf8(quoted).unwrap();
+            assert_eq!(mutt::tokenize(&quoted).unwrap(), vec![path.to_string()], "{}", quoted);
+        }
+    }
+
+    /// A newline can't be quoted for either format, so such paths are left out.
+    #[test]
+    fn newline_skipped() {
+        let mailboxes = vec![
+            mbox("/a", 0, false),
+            mbox("/new\nline", 0, false),
+            mbox("/b", 0, false),
+        ];
+        let mutt = render(ExportFormat::MuttMailboxes, &mailboxes);
+        assert_eq!(mutt, b"mailboxes '/a' '/b'\n");
+        let list = render(ExportFormat::PathList, &mailboxes);
+        assert_eq!(list, b"/a\n/b\n");
+    }
+
+    #[test]
+    fn hidden_not_listed() {
+        let mailboxes = vec![
+            mbox("/low", 1, false),
+            mbox("/hidden", 5, true),
+            mbox("/high", 3, false),
+            mbox("/also-low", 1, false),
+        ];
+        let listed = listed(&mailboxes);
+        let paths = listed.iter().map(|m| m.path.to_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(paths, ["/high", "/also-low", "/low"]);
+    }
+
+    fn dir_names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Someone reading the old file keeps reading the old content, the new goes to a new file.
+    #[test]
+    fn atomic_replace() {
+        let dir = TempDir::new();
+        let target = dir.path().join("sub").join("mailboxes");
+        write_atomic(&target, b"old content\n").unwrap();
+
+        let mut reader = File::open(&target).unwrap();
+        let mut start = [0; 4];
+        reader.read_exact(&mut start).unwrap();
+        write_atomic(&target, b"new content\n").unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(&start, b"old ");
+        assert_eq!(rest, "content\n");
+
+        assert_eq!(fs::read(&target).unwrap(), b"new content\n");
+        assert_eq!(dir_names(&dir.path().join("sub")), ["mailboxes"]);
+    }
+
+    /// Concurrent writers don't trip over each other's temporary files.
+    #[test]
+    fn atomic_concurrent() {
+        let dir = TempDir::new();
+        let target = dir.path().join("mailboxes");
+        let writers = (0..8)
+            .map(|i| {
+                let target = target.clone();
+                thread::spawn(move || {
+                    let content = format!("writer {}\n", i).repeat(1000);
+                    for _ in 0..10 {
+                        write_atomic(&target, content.as_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let content = fs::read_to_string(&target).unwrap();
+        let first = content.lines().next().unwrap();
+        assert!(content.lines().all(|l| l == first));
+        assert_eq!(content.lines().count(), 1000);
+        assert_eq!(dir_names(dir.path()), ["mailboxes"]);
+    }
+
+    /// A failed rename doesn't leave the temporary file behind.
+    #[test]
+    fn failed_rename() {
+        let dir = TempDir::new();
+        let target = dir.path().join("target");
+        fixtures::write(&target.join("file"), "a directory in the way");
+        assert!(write_atomic(&target, b"content").is_err());
+        assert_eq!(dir_names(dir.path()), ["target"]);
+    }
+}
@@ -0,0 +1,128 @@
+//! Filters deciding which mailboxes a config callback applies to.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+use regex::Regex;
+use rlua::{Error as LuaError, Table, UserData};
+
+use crate::glob::Glob;
+use super::Mailbox;
+use super::normalize::normalize;
+
+// All the kinds, even if support for some of the compressions is not built in
+const KINDS: &[&str] = &[
+    "mbox", "mbox-gz", "mbox-xz", "mbox-bz2", "mbox-zst", "mmdf", "maildir", "mh",
+];
+
+/// A single filter table. All the present conditions must hold.
+#[derive(Clone, Debug, Default)]
+struct Condition {
+    kind: Option<String>,
+    path_glob: Option<Arc<Glob>>,
+    name_match: Option<Regex>,
+}
+
+impl Condition {
+    fn from_lua(table: Table) -> Result<Self, LuaError> {
+        let mut cond = Condition::default();
+        for pair in table.pairs::<String, String>() {
+            let (key, value) = pair?;
+            match key.as_str() {
+                "kind" if KINDS.contains(&value.as_str()) => cond.kind = Some(value),
+                "kind" => {
+                    let msg = format!("Unknown mailbox kind {}, expected one of {}",
+                                      value, KINDS.join(", "));
+                    return Err(LuaError::RuntimeError(msg));
+                }
+                "path_glob" => {
+                    cond.path_glob = Some(Glob::cached(&value).map_err(LuaError::external)?);
+                }
+                "name_match" => {
+                    cond.name_match = Some(Regex::new(&value).map_err(LuaError::external)?);
+                }
+                _ => return Err(LuaError::RuntimeError(format!("Unknown filter {}", key))),
+            }
+        }
+        Ok(cond)
+    }
+
+    fn matches(&self, mbox: &Mailbox, normalize_names: bool) -> bool {
+        if let Some(ref kind) = self.kind {
+            if mbox.tp.name() != kind {
+                return false;
+            }
+        }
+        if let Some(ref glob) = self.path_glob {
+            if !glob.is_match(&mbox.path) {
+                return false;
+            }
+        }
+        if let Some(ref re) = self.name_match {
+            let name_matches = re.is_match(&mbox.name)
+                || (normalize_names && re.is_match(&normalize(&mbox.name)));
+            if !name_matches {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let mut parts = Vec::new();
+        if let Some(ref kind) = self.kind {
+            parts.push(format!("kind = {}", kind));
+        }
+        if let Some(ref glob) = self.path_glob {
+            parts.push(format!("path_glob = {}", glob));
+        }
+        if let Some(ref re) = self.name_match {
+            parts.push(format!("name_match = {}", re));
+        }
+        write!(fmt, "{{ {} }}", parts.join(", "))
+    }
+}
+
+/// Filter of a config callback.
+///
+/// It is any of the conditions (an array of filter tables is OR-ed together). No conditions at
+/// all means the callback applies to everything.
+#[derive(Clone, Debug, Default)]
+crate struct Filter(Vec<Condition>);
+
+impl Filter {
+    crate fn from_lua(filter: Option<Table>) -> Result<Self, LuaError> {
+        let filter = match filter {
+            None => return Ok(Filter::default()),
+            Some(filter) => filter,
+        };
+        if filter.raw_len() > 0 {
+            filter
+                .sequence_values::<Table>()
+                .map(|cond| cond.and_then(Condition::from_lua))
+                .collect::<Result<_, _>>()
+                .map(Filter)
+        } else {
+            Ok(Filter(vec![Condition::from_lua(filter)?]))
+        }
+    }
+
+    crate fn matches(&self, mbox: &Mailbox, normalize_names: bool) -> bool {
+        self.0.is_empty() || self.0.iter().any(|cond| cond.matches(mbox, normalize_names))
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        if self.0.is_empty() {
+            fmt.write_str("none")
+        } else {
+            let conds = self.0.iter().map(Condition::to_string).collect::<Vec<_>>();
+            fmt.write_str(&conds.join(" or "))
+        }
+    }
+}
+
+impl UserData for Filter {}
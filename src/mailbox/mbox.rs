@@ -1,4 +1,533 @@
-#[derive(Clone, Debug, Default)]
+//! The cache of a single mbox file.
+//!
+//! The file is split into messages on the `From ` separator lines. As not every writer quotes
+//! `From ` lines inside the bodies (`>From `), a line counts as a separator only if it comes at the
+//! start of the file or after an empty line and it looks like a separator ‒ it has the sender and
+//! something resembling a time after it.
+//!
+//! Writers of the mboxcl formats record the body length in a `Content-Length` header instead (and
+//! mboxcl2 doesn't quote anything). Such a length is trusted only if it ends right at a message
+//! boundary (a blank line and a separator, or the end of the file). Otherwise the message is split
+//! on the separators as if the header wasn't there. How much the lengths are trusted beyond that
+//! depends on the format, see `Format`.
+//!
+//! MMDF mailboxes look the same inside, but each message is enclosed in `^A^A^A^A` delimiter lines
+//! instead, so there's nothing to guess about. Such a file is recognized by its first line.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::BufRead;
+
+use failure::Error;
+use log::trace;
+
+use crate::redact::Content;
+use super::cancel::Token;
+
+const SEPARATOR: &[u8] = b"From ";
+/// The line before and after each message of an MMDF mailbox.
+const MMDF_DELIMITER: &[u8] = b"\x01\x01\x01\x01";
+
+/// Whether the data starts with an MMDF delimiter line.
+pub(super) fn starts_mmdf(start: &[u8]) -> bool {
+    start.starts_with(MMDF_DELIMITER) && {
+        let eol = &start[MMDF_DELIMITER.len()..];
+        eol.starts_with(b"\n") || eol.starts_with(b"\r\n")
+    }
+}
+
+/// How to find where the messages end.
+///
+/// Only scripts can choose anything else than the default.
+#[cfg_attr(not(feature = "lua"), allow(dead_code))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) enum Format {
+    /// Use the Content-Length headers as long as they fit.
+    ///
+    /// Once one doesn't end at a boundary, the writer apparently doesn't keep them up to date and
+    /// the rest of the file is split only on the separators.
+    Auto,
+    /// Split only on the separators.
+    Mboxrd,
+    /// The bodies are quoted, so every separator is real.
+    ///
+    /// A Content-Length only lets the next message start without the blank line before it. If
+    /// there's a separator inside the body it claims, the header is wrong.
+    Mboxcl,
+    /// Nothing is quoted, so the Content-Length headers decide.
+    ///
+    /// Separators inside the body a header claims are part of the message. A header that doesn't
+    /// end at a boundary is ignored, but the next ones are still trusted.
+    Mboxcl2,
+}
+
+impl Format {
+    #[cfg_attr(not(feature = "lua"), allow(dead_code))]
+    pub(super) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Format::Auto),
+            "mboxrd" => Some(Format::Mboxrd),
+            "mboxcl" => Some(Format::Mboxcl),
+            "mboxcl2" => Some(Format::Mboxcl2),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Auto
+    }
+}
+
+/// How many bytes are read between looking if the parsing should stop.
+const CANCEL_CHECK_BYTES: u64 = 1024 * 1024;
+
+/// What is known about a single message inside the mbox.
+#[derive(Clone, Default)]
+pub(super) struct Message {
+    /// Where the message (its `From ` line) starts.
+    ///
+    /// For compressed mailboxes, this is in the decompressed data. In MMDF, it is the line after
+    /// the delimiter.
+    pub(super) offset: u64,
+    /// The length of the message, including the `From ` line.
+    ///
+    /// The empty line separating it from the next message is not part of it, nor are the MMDF
+    /// delimiters.
+    pub(super) length: u64,
+    pub(super) from: Option<String>,
+    pub(super) subject: Option<String>,
+    pub(super) date: Option<String>,
+    pub(super) message_id: Option<String>,
+    /// The length of the body, as claimed by the message.
+    pub(super) content_length: Option<u64>,
+}
+
+// What people write to each other doesn't belong into shared logs
+impl Debug for Message {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Message")
+            .field("offset", &self.offset)
+            .field("length", &self.length)
+            .field("from", &Content(&self.from))
+            .field("subject", &Content(&self.subject))
+            .field("date", &self.date)
+            .field("message_id", &Content(&self.message_id))
+            .field("content_length", &self.content_length)
+            .finish()
+    }
+}
+
+impl Message {
+    fn header(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let (name, value) = match line.find(':') {
+            Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+            None => return,
+        };
+        let name = name.to_ascii_lowercase();
+        if name == "content-length" {
+            if self.content_length.is_none() {
+                self.content_length = value.parse().ok();
+            }
+            return;
+        }
+        let slot = match name.as_str() {
+            "from" => &mut self.from,
+            "subject" => &mut self.subject,
+            "date" => &mut self.date,
+            "message-id" => &mut self.message_id,
+            _ => return,
+        };
+        // The first occurrence wins, like in most readers.
+        if slot.is_none() {
+            *slot = Some(value.to_owned());
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub(super) struct Mbox {
+    messages: Vec<Message>,
+}
+
+// The list of messages would make the logs unreadable
+impl Debug for Mbox {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Mbox")
+            .field("messages", &self.message_count())
+            .finish()
+    }
+}
+
+fn trim_eol(line: &[u8]) -> &[u8] {
+    let line = if line.ends_with(b"\n") { &line[..line.len() - 1] } else { line };
+    if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line }
+}
+
+/// Collects the header lines of a message, joining the folded ones.
+#[derive(Default)]
+struct Headers {
+    line: Vec<u8>,
+}
+
+impl Headers {
+    /// Takes the next line of the headers. Returns false on the empty line ending them.
+    fn feed(&mut self, msg: &mut Message, content: &[u8]) -> bool {
+        let continuation = content.starts_with(b" ") || content.starts_with(b"\t");
+        if continuation && !self.line.is_empty() {
+            self.line.push(b' ');
+            self.line.extend_from_slice(trim_start(content));
+        } else {
+            self.finish(msg);
+            self.line.extend_from_slice(content);
+        }
+        if content.is_empty() {
+            self.line.clear();
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Processes the header still waiting for possible continuation lines.
+    fn finish(&mut self, msg: &mut Message) {
+        if !self.line.is_empty() {
+            msg.header(&self.line);
+        }
+        self.line.clear();
+    }
+}
+
+/// A message whose Content-Length is yet to be confirmed by a boundary right after its body.
+struct Pending {
+    /// Where the next message should start (either the blank line or the separator).
+    end: u64,
+    blank_seen: bool,
+    /// How many messages were there before it.
+    index: usize,
+    /// The message with all its headers.
+    message: Message,
+}
+
+/// Checks the line is a plausible `From sender date` separator.
+fn is_separator(line: &[u8]) -> bool {
+    if !line.starts_with(SEPARATOR) {
+        return false;
+    }
+    let rest = String::from_utf8_lossy(&line[SEPARATOR.len()..]);
+    let mut words = rest.split_whitespace();
+    // The sender
+    if words.next().is_none() {
+        return false;
+    }
+    // Somewhere in the date there's a time, like 12:34 or 12:34:56
+    words.any(|word| {
+        let parts = word.split(':').collect::<Vec<_>>();
+        (parts.len() == 2 || parts.len() == 3)
+            && parts.iter().all(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()))
+    })
+}
+
+impl Mbox {
+    /// Reads the whole mbox and builds the list of messages in it.
+    ///
+    /// An MMDF mailbox is split on its delimiters, regardless of the format.
+    pub(super) fn parse<R: BufRead>(mut input: R, format: Format, cancel: &Token)
+        -> Result<Self, Error>
+    {
+        if starts_mmdf(input.fill_buf()?) {
+            return Mbox::parse_mmdf(input, cancel);
+        }
+        let mut messages = Vec::new();
+        let mut current: Option<Message> = None;
+        let mut in_headers = false;
+        let mut headers = Headers::default();
+        // Whether the last line was empty and where it started
+        let mut prev_empty = true;
+        let mut prev_start = 0;
+        let mut offset = 0;
+        let mut line = Vec::new();
+        let mut next_check = 0;
+        let mut pending: Option<Pending> = None;
+        let mut use_lengths = format != Format::Mboxrd;
+
+        loop {
+            if offset >= next_check {
+                cancel.check()?;
+                next_check = offset + CANCEL_CHECK_BYTES;
+            }
+            line.clear();
+            let len = input.read_until(b'\n', &mut line)? as u64;
+            if len == 0 {
+                break;
+            }
+            let content = trim_eol(&line);
+
+            // Whatever looked like separators inside a confirmed body were not, so they are
+            // forgotten and the separator right after the body is taken even without a blank line.
+            let mut confirmed = false;
+            if let Some(mut p) = pending.take() {
+                if offset == p.end && is_separator(content) {
+                    messages.truncate(p.index);
+                    current = Some(p.message);
+                    in_headers = false;
+                    confirmed = true;
+                } else if offset == p.end && !p.blank_seen && content.is_empty() {
+                    p.end += len;
+                    p.blank_seen = true;
+                    pending = Some(p);
+                } else if offset >= p.end {
+                    trace!("Content-Length of message at {} doesn't end at a boundary",
+                           p.message.offset);
+                    if format == Format::Auto {
+                        use_lengths = false;
+                    }
+                } else if format == Format::Mboxcl && prev_empty && is_separator(content) {
+                    trace!("Content-Length of message at {} claims the next message",
+                           p.message.offset);
+                } else {
+                    pending = Some(p);
+                }
+            }
+
+            if (prev_empty || confirmed) && is_separator(content) {
+                if let Some(mut msg) = current.take() {
+                    // The empty line before the separator belongs to the format, not the message.
+                    let end = if prev_empty { prev_start } else { offset };
+                    msg.length = end - msg.offset;
+                    messages.push(msg);
+                }
+                current = Some(Message {
+                    offset,
+                    ..Message::default()
+                });
+                in_headers = true;
+                headers = Headers::default();
+            } else if let Some(ref mut msg) = current {
+                if in_headers {
+                    in_headers = headers.feed(msg, content);
+                    if !in_headers {
+                        if let (Some(body), None) = (msg.content_length, &pending) {
+                            if use_lengths {
+                                pending = Some(Pending {
+                                    end: offset + len + body,
+                                    blank_seen: false,
+                                    index: messages.len(),
+                                    message: msg.clone(),
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+
+            prev_empty = content.is_empty();
+            prev_start = offset;
+            offset += len;
+        }
+
+        // The last body may end right at the end of the file
+        if let Some(p) = pending {
+            if offset == p.end {
+                messages.truncate(p.index);
+                current = Some(p.message);
+                in_headers = false;
+            }
+        }
+
+        if let Some(mut msg) = current {
+            // A message cut off inside its headers still has the last one pending
+            if in_headers {
+                headers.finish(&mut msg);
+            }
+            // No more separators, so nothing of the tail belongs to the format (a trailing empty
+            // line included).
+            let end = if prev_empty { prev_start } else { offset };
+            msg.length = end.max(msg.offset) - msg.offset;
+            messages.push(msg);
+        }
+
+        Ok(Mbox { messages })
+    }
+
+    fn parse_mmdf<R: BufRead>(mut input: R, cancel: &Token) -> Result<Self, Error> {
+        let mut messages = Vec::new();
+        let mut current: Option<Message> = None;
+        let mut in_headers = false;
+        let mut headers = Headers::default();
+        let mut offset = 0;
+        let mut line = Vec::new();
+        let mut next_check = 0;
+
+        loop {
+            if offset >= next_check {
+                cancel.check()?;
+                next_check = offset + CANCEL_CHECK_BYTES;
+            }
+            line.clear();
+            let len = input.read_until(b'\n', &mut line)? as u64;
+            if len == 0 {
+                break;
+            }
+            let content = trim_eol(&line);
+
+            if content == MMDF_DELIMITER {
+                match current.take() {
+                    Some(mut msg) => {
+                        if in_headers {
+                            headers.finish(&mut msg);
+                        }
+                        msg.length = offset - msg.offset;
+                        messages.push(msg);
+                    }
+                    None => {
+                        current = Some(Message {
+                            offset: offset + len,
+                            ..Message::default()
+                        });
+                        in_headers = true;
+                        headers = Headers::default();
+                    }
+                }
+            } else if let Some(ref mut msg) = current {
+                if in_headers {
+                    in_headers = headers.feed(msg, content);
+                }
+            }
+            // Anything between a closing and an opening delimiter is no message, so it's skipped.
+
+            offset += len;
+        }
+
+        // The closing delimiter of the last message may be missing
+        if let Some(mut msg) = current {
+            if in_headers {
+                headers.finish(&mut msg);
+            }
+            msg.length = offset - msg.offset;
+            messages.push(msg);
+        }
+
+        Ok(Mbox { messages })
+    }
+
+    /// A made up mbox with this many messages, as if it was parsed.
+    pub(super) fn with_count(count: usize) -> Self {
+        Mbox {
+            messages: vec![Message::default(); count],
+        }
+    }
+
+    pub(super) fn message_count(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub(super) fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.messages.iter()
+    }
+}
+
+fn trim_start(line: &[u8]) -> &[u8] {
+    let start = line
+        .iter()
+        .position(|b| *b != b' ' && *b != b'\t')
+        .unwrap_or_else(|| line.len());
+    &line[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEP: &str = "From someone@example.com Mon Jan  1 12:34:56 2018\n";
+
+    /// A message with the given body and a Content-Length header claiming `claimed` bytes of it.
+    fn cl_message(body: &str, claimed: usize) -> String {
+        format!("{}Subject: test\nContent-Length: {}\n\n{}", SEP, claimed, body)
+    }
+
+    /// A body with an unquoted separator line inside, as mboxcl2 writers leave it.
+    fn unquoted_body() -> String {
+        format!("Hello\n\n{}Still the same body\n", SEP)
+    }
+
+    fn count(data: &str, format: Format) -> usize {
+        Mbox::parse(data.as_bytes(), format, &Token::default())
+            .unwrap()
+            .message_count()
+    }
+
+    #[test]
+    fn separators() {
+        let data = format!("{}Subject: a\n\nHi\n>From the past\nFrom nobody\n\n{}Subject: b\n\nX\n",
+                           SEP, SEP);
+        for format in &[Format::Auto, Format::Mboxrd, Format::Mboxcl, Format::Mboxcl2] {
+            assert_eq!(2, count(&data, *format), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn message_bounds() {
+        let first = format!("{}Subject: a\n\nHi\n", SEP);
+        let data = format!("{}\n{}Subject: b\n\nX\n\n", first, SEP);
+        let mbox = Mbox::parse(data.as_bytes(), Format::Auto, &Token::default()).unwrap();
+        let messages = mbox.messages().collect::<Vec<_>>();
+        assert_eq!(0, messages[0].offset);
+        assert_eq!(first.len() as u64, messages[0].length);
+        assert_eq!(Some("a"), messages[0].subject.as_ref().map(|s| s.as_str()));
+        assert_eq!(first.len() as u64 + 1, messages[1].offset);
+        assert_eq!(Some("b"), messages[1].subject.as_ref().map(|s| s.as_str()));
+    }
+
+    #[test]
+    fn content_length() {
+        let body = unquoted_body();
+        let data = format!("{}\n{}Subject: b\n\nX\n", cl_message(&body, body.len()), SEP);
+        assert_eq!(2, count(&data, Format::Auto));
+        assert_eq!(2, count(&data, Format::Mboxcl2));
+        // With quoted bodies, the separator is a real one and the header is wrong
+        assert_eq!(3, count(&data, Format::Mboxcl));
+        assert_eq!(3, count(&data, Format::Mboxrd));
+    }
+
+    #[test]
+    fn content_length_at_the_end() {
+        let body = unquoted_body();
+        let data = cl_message(&body, body.len());
+        assert_eq!(1, count(&data, Format::Auto));
+        assert_eq!(2, count(&data, Format::Mboxrd));
+    }
+
+    /// The separator right after a confirmed body counts even without the blank line.
+    #[test]
+    fn content_length_no_blank() {
+        let data = format!("{}{}Subject: b\n\nX\n", cl_message("Hello\n", 6), SEP);
+        assert_eq!(1, count(&data, Format::Mboxrd));
+        for format in &[Format::Auto, Format::Mboxcl, Format::Mboxcl2] {
+            assert_eq!(2, count(&data, *format), "{:?}", format);
+        }
+    }
+
+    /// After a wrong header, auto stops trusting them, while mboxcl2 trusts the next ones.
+    #[test]
+    fn content_length_wrong() {
+        let body = unquoted_body();
+        let data = format!("{}\n{}", cl_message("Hello\n", 2), cl_message(&body, body.len()));
+        assert_eq!(3, count(&data, Format::Auto));
+        assert_eq!(2, count(&data, Format::Mboxcl2));
+    }
 
+    #[test]
+    fn mmdf() {
+        let data = "\x01\x01\x01\x01\nSubject: a\n\nFrom here\n\x01\x01\x01\x01\n\
+                    \x01\x01\x01\x01\nSubject: b\n\nX\n\x01\x01\x01\x01\n";
+        assert!(starts_mmdf(data.as_bytes()));
+        let mbox = Mbox::parse(data.as_bytes(), Format::Mboxrd, &Token::default()).unwrap();
+        let subjects = mbox
+            .messages()
+            .map(|msg| msg.subject.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a", "b"], subjects);
+    }
 }
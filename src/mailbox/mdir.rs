@@ -1,4 +1,139 @@
+//! The cache of a single maildir.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::Path;
+
+use failure::{Error, ResultExt};
+
+use super::cancel::Token;
+
+/// The subdirectories holding delivered messages. Whatever is in `tmp` is still being delivered.
+const MESSAGE_DIRS: &[&str] = &["cur", "new"];
+/// How many files are looked at between looking if the scan should stop.
+const CANCEL_CHECK_FILES: usize = 1000;
+
+/// The flags part of a maildir file name (the characters after `:2,`), if there's any.
+pub(super) fn flags(file_name: &str) -> Option<&str> {
+    file_name.rfind(":2,").map(|pos| &file_name[pos + 3..])
+}
+
+/// The standard maildir flags.
 #[derive(Clone, Debug, Default)]
+pub(super) struct Flags {
+    pub(super) passed: bool,
+    pub(super) replied: bool,
+    pub(super) seen: bool,
+    pub(super) trashed: bool,
+    pub(super) draft: bool,
+    pub(super) flagged: bool,
+}
+
+impl Flags {
+    /// Parses the flags from the file name. Unknown flags are ignored.
+    fn parse(file_name: &str) -> Self {
+        let mut result = Flags::default();
+        for flag in flags(file_name).unwrap_or("").chars() {
+            match flag {
+                'P' => result.passed = true,
+                'R' => result.replied = true,
+                'S' => result.seen = true,
+                'T' => result.trashed = true,
+                'D' => result.draft = true,
+                'F' => result.flagged = true,
+                _ => (),
+            }
+        }
+        result
+    }
+}
+
+/// A single message file.
+#[derive(Clone, Debug)]
+pub(super) struct Entry {
+    pub(super) file_name: String,
+    pub(super) size: u64,
+    /// Found in `new`, not yet looked at by any client.
+    pub(super) new: bool,
+    pub(super) flags: Flags,
+}
+
+#[derive(Clone, Default)]
 pub(super) struct Mdir {
+    entries: Vec<Entry>,
+}
+
+// The list of entries would make the logs unreadable
+impl Debug for Mdir {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Mdir")
+            .field("total", &self.total())
+            .field("unseen", &self.unseen())
+            .finish()
+    }
+}
+
+impl Mdir {
+    /// Lists the messages in the maildir.
+    pub(super) fn scan(path: &Path, cancel: &Token) -> Result<Self, Error> {
+        let mut entries = Vec::new();
+        for sub in MESSAGE_DIRS {
+            let dir = path.join(sub);
+            let listing = fs::read_dir(&dir)
+                .with_context(|_| format!("Failed to list {}", dir.display()))?;
+            for (idx, file) in listing.enumerate() {
+                if idx % CANCEL_CHECK_FILES == 0 {
+                    cancel.check()?;
+                }
+                let file = file?;
+                let file_name = file.file_name().to_string_lossy().into_owned();
+                // Dot files are not messages by the maildir convention
+                if file_name.starts_with('.') {
+                    continue;
+                }
+                let meta = file.metadata()?;
+                if !meta.is_file() {
+                    continue;
+                }
+                entries.push(Entry {
+                    flags: Flags::parse(&file_name),
+                    file_name,
+                    size: meta.len(),
+                    new: *sub == "new",
+                });
+            }
+        }
+        Ok(Mdir { entries })
+    }
+
+    /// A made up maildir with this many messages, as if it was read.
+    pub(super) fn with_counts(total: usize, unseen: usize) -> Self {
+        let entries = (0..total)
+            .map(|idx| Entry {
+                file_name: format!("{}.made-up", idx),
+                size: 0,
+                new: false,
+                flags: Flags {
+                    seen: idx >= unseen,
+                    ..Flags::default()
+                },
+            })
+            .collect();
+        Mdir { entries }
+    }
+
+    pub(super) fn total(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(super) fn unseen(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.flags.seen)
+            .count()
+    }
 
+    pub(super) fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
 }
@@ -0,0 +1,128 @@
+//! Applying the storage.meta section of the config to the detected mailboxes.
+
+use log::{trace, warn};
+
+use crate::config::{Cfg, StorageMeta};
+use crate::path_key::PathKey;
+use crate::path_trie::PathTrie;
+use super::Mailbox;
+
+/// The meta entries, by the key of the mailbox they apply to.
+///
+/// An absolute path in the config names the mailbox directly. A relative one is taken against
+/// each of the search roots. Both go through the same canonicalization as the scan, so the entry
+/// applies no matter which root (or symlink) the mailbox is found through. If more entries end up
+/// at the same mailbox, an absolute one wins over relative ones and otherwise the earlier search
+/// root wins.
+///
+/// Keyed by the same trie as the scan results. Only exact matches apply, an entry says nothing
+/// about the mailboxes nested inside the one it names.
+pub(super) struct MetaIndex<'a>(PathTrie<&'a StorageMeta>);
+
+impl<'a> MetaIndex<'a> {
+    pub(super) fn new(cfg: &'a Cfg) -> Self {
+        let mut index = PathTrie::new();
+        let (absolute, relative): (Vec<_>, Vec<_>) = cfg
+            .storage
+            .meta
+            .iter()
+            .partition(|(path, _)| path.is_absolute());
+        for (path, meta) in absolute {
+            if index.insert(PathKey::new(path), meta).is_some() {
+                warn!("Multiple storage.meta entries for {}", path.display());
+            }
+        }
+        for root in &cfg.storage.search {
+            for (path, meta) in &relative {
+                let key = PathKey::new(root.path.join(path));
+                if !index.contains(&key) {
+                    index.insert(key, *meta);
+                }
+            }
+        }
+        MetaIndex(index)
+    }
+
+    /// Sets whatever the config says about the mailbox.
+    pub(super) fn apply(&self, key: &PathKey, mbox: &mut Mailbox) {
+        if let Some(meta) = self.0.get(key) {
+            trace!("Applying {:?} to {}", meta, key);
+            if let Some(ref name) = meta.name {
+                mbox.name = name.clone();
+            }
+            if meta.shortcut.is_some() {
+                mbox.shortcut = meta.shortcut;
+            }
+            mbox.prio = usize::from(meta.prio);
+            mbox.hidden = meta.hidden;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+
+    use crate::config;
+    use crate::fixtures::{MaildirSpec, MboxSpec, Rng, TempDir};
+    use crate::mailbox::MailboxBuilder;
+    use super::*;
+
+    fn configured(index: &MetaIndex, path: &Path) -> (String, usize) {
+        let mut mbox = MailboxBuilder::new(path).with_name("unnamed").build().unwrap();
+        index.apply(&PathKey::new(path), &mut mbox);
+        (mbox.name, mbox.prio)
+    }
+
+    #[test]
+    fn entries() {
+        let dir = TempDir::new();
+        let mut rng = Rng::new(14);
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        for path in &["both", "abs", "nested/inner", "second-only"] {
+            MboxSpec::default().build(&first.join(path), &mut rng);
+        }
+        MaildirSpec::default().build(&first.join("nested"), &mut rng);
+        symlink(&first, &second).unwrap();
+        MboxSpec::default().build(&dir.path().join("elsewhere"), &mut rng);
+
+        let toml = format!(r#"
+            [storage]
+            search = [{first:?}, {other:?}]
+            [storage.meta."both"]
+            name = "relative"
+            prio = 1
+            [storage.meta.{abs:?}]
+            name = "absolute"
+            prio = 2
+            [storage.meta."abs"]
+            name = "loses"
+            [storage.meta."nested"]
+            name = "outer"
+            prio = 3
+            [storage.meta.{linked:?}]
+            name = "through link"
+            prio = 4
+        "#, first = first.display().to_string(),
+            other = dir.path().join("other").display().to_string(),
+            abs = first.join("abs").display().to_string(),
+            linked = second.join("second-only").display().to_string());
+        let cfg = config::parse(&toml);
+        let index = MetaIndex::new(&cfg);
+
+        let expected: Vec<(&str, PathBuf, &str, usize)> = vec![
+            ("relative", first.join("both"), "relative", 1),
+            ("absolute wins", first.join("abs"), "absolute", 2),
+            ("through symlink", second.join("abs"), "absolute", 2),
+            ("exact only", first.join("nested"), "outer", 3),
+            ("not nested", first.join("nested/inner"), "unnamed", 0),
+            ("absolute through link", first.join("second-only"), "through link", 4),
+            ("not mentioned", dir.path().join("elsewhere"), "unnamed", 0),
+            ("missing", first.join("missing"), "unnamed", 0),
+        ];
+        for (case, path, name, prio) in expected {
+            assert_eq!((name.to_owned(), prio), configured(&index, &path), "{}", case);
+        }
+    }
+}
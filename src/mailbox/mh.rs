@@ -0,0 +1,193 @@
+//! The cache of a single MH folder.
+//!
+//! Each message is a file named by its number. Which messages are unseen is recorded in the
+//! `unseen` sequence of the `.mh_sequences` file.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use failure::{bail, Error, ResultExt};
+use log::warn;
+
+use super::cancel::Token;
+
+/// Files only MH folders have. Numbered files alone are too common to tell it's a folder.
+pub(super) const MARKERS: &[&str] = &[".mh_sequences", ".xmhcache"];
+pub(super) const SEQUENCES: &str = ".mh_sequences";
+/// The name of the sequence nmh keeps the unseen messages in, unless configured otherwise.
+const UNSEEN: &str = "unseen";
+/// How many files are looked at between looking if the scan should stop.
+const CANCEL_CHECK_FILES: usize = 1000;
+
+/// A sequence of messages, like `1-5 7 10-12`.
+///
+/// It's kept as the ranges it is written as, a broken file may claim far more messages than there
+/// can be in the folder.
+#[derive(Debug, Default)]
+struct Sequence {
+    /// Sorted and not overlapping (nor touching).
+    ranges: Vec<(u32, u32)>,
+}
+
+impl Sequence {
+    fn parse(spec: &str) -> Result<Self, Error> {
+        let mut ranges = Vec::new();
+        for range in spec.split_whitespace() {
+            let mut bounds = range.splitn(2, '-');
+            let start: u32 = bounds.next().unwrap_or("").parse()?;
+            let end: u32 = match bounds.next() {
+                Some(end) => end.parse()?,
+                None => start,
+            };
+            if start > end {
+                bail!("Range {} is backwards", range);
+            }
+            ranges.push((start, end));
+        }
+        ranges.sort();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Ok(Sequence { ranges: merged })
+    }
+
+    fn contains(&self, msg: u32) -> bool {
+        match self.ranges.binary_search_by(|(start, _)| start.cmp(&msg)) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(idx) => self.ranges[idx - 1].1 >= msg,
+        }
+    }
+}
+
+/// Reads the unseen sequence of the folder. A missing file simply means nothing is unseen.
+fn unseen(path: &Path) -> Result<Sequence, Error> {
+    let file = path.join(SEQUENCES);
+    let content = match fs::read_to_string(&file) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Sequence::default()),
+        result => result.with_context(|_| format!("Failed to read {}", file.display()))?,
+    };
+    for line in content.lines() {
+        if let Some(pos) = line.find(':') {
+            if line[..pos].trim() == UNSEEN {
+                return Ok(Sequence::parse(&line[pos + 1..])
+                    .with_context(|_| format!("Broken unseen sequence in {}", file.display()))?);
+            }
+        }
+    }
+    Ok(Sequence::default())
+}
+
+#[derive(Clone, Default)]
+pub(super) struct Mh {
+    messages: Vec<u32>,
+    unseen: usize,
+}
+
+// The list of messages would make the logs unreadable
+impl Debug for Mh {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Mh")
+            .field("total", &self.total())
+            .field("unseen", &self.unseen())
+            .finish()
+    }
+}
+
+impl Mh {
+    /// Lists the messages in the folder.
+    pub(super) fn scan(path: &Path, cancel: &Token) -> Result<Self, Error> {
+        let listing = fs::read_dir(path)
+            .with_context(|_| format!("Failed to list {}", path.display()))?;
+        let mut messages = Vec::new();
+        for (idx, file) in listing.enumerate() {
+            if idx % CANCEL_CHECK_FILES == 0 {
+                cancel.check()?;
+            }
+            let file = file?;
+            let number = match file.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(number) => number,
+                // Subfolders, the sequences and whatever else lives there
+                None => continue,
+            };
+            if file.metadata()?.is_file() {
+                messages.push(number);
+            }
+        }
+        messages.sort();
+        // The sequences are only a hint about the messages, not worth failing the scan over
+        let unseen = match unseen(path) {
+            Ok(unseen) => messages.iter().filter(|msg| unseen.contains(**msg)).count(),
+            Err(e) => {
+                warn!("{}", e);
+                0
+            }
+        };
+        Ok(Mh { messages, unseen })
+    }
+
+    /// A made up folder with this many messages, as if it was read.
+    pub(super) fn with_counts(total: usize, unseen: usize) -> Self {
+        Mh {
+            messages: (1..=total as u32).collect(),
+            unseen,
+        }
+    }
+
+    pub(super) fn total(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub(super) fn unseen(&self) -> usize {
+        self.unseen
+    }
+
+    pub(super) fn messages(&self) -> impl Iterator<Item = u32> + '_ {
+        self.messages.iter().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence() {
+        let seq = Sequence::parse("10-12 1-5 7 4-6").unwrap();
+        assert_eq!(vec![(1, 7), (10, 12)], seq.ranges);
+        for msg in &[1, 3, 6, 7, 10, 12] {
+            assert!(seq.contains(*msg), "{} is in", msg);
+        }
+        for msg in &[0, 8, 9, 13, 100] {
+            assert!(!seq.contains(*msg), "{} is not in", msg);
+        }
+    }
+
+    #[test]
+    fn sequence_empty() {
+        assert!(!Sequence::parse("").unwrap().contains(1));
+        assert!(!Sequence::default().contains(1));
+    }
+
+    /// A huge range is just a range, it doesn't get expanded.
+    #[test]
+    fn sequence_huge() {
+        let seq = Sequence::parse("1-4294967295").unwrap();
+        assert!(seq.contains(1));
+        assert!(seq.contains(u32::max_value()));
+        assert!(!seq.contains(0));
+    }
+
+    #[test]
+    fn sequence_broken() {
+        assert!(Sequence::parse("1-x").is_err());
+        assert!(Sequence::parse("5-1").is_err());
+        assert!(Sequence::parse("4294967296").is_err());
+    }
+}
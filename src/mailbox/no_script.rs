@@ -0,0 +1,47 @@
+//! Stand-in for the lua configuration when built without the `lua` feature.
+//!
+//! The config refuses any scripts in that case, so mailboxes are registered as detected.
+
+use std::sync::Arc;
+
+use failure::Error;
+
+use crate::config::Cfg;
+use super::{CallbackStats, Mailbox};
+
+/// No scripts, nothing to run.
+pub(super) struct Scripts;
+
+impl Scripts {
+    pub(super) fn load(_cfg: &Cfg) -> Result<Self, Error> {
+        Ok(Scripts)
+    }
+
+    pub(super) fn configure(&self, mbox: Mailbox, _normalize_names: bool)
+        -> Result<Option<Mailbox>, Error>
+    {
+        Ok(Some(mbox))
+    }
+
+    pub(super) fn has_post_scan(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    pub(super) fn post_scan(&self, mbox: Mailbox, _normalize_names: bool)
+        -> Result<Option<Mailbox>, Error>
+    {
+        Ok(Some(mbox))
+    }
+
+    pub(super) fn on_content(&self, _mbox: &Arc<Mailbox>, _new: usize, _total: usize)
+        -> Result<(), Error>
+    {
+        Ok(())
+    }
+
+    pub(super) fn log_stats(&self) {}
+
+    pub(super) fn stats(&self) -> Vec<CallbackStats> {
+        Vec::new()
+    }
+}
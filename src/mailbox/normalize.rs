@@ -0,0 +1,120 @@
+//! Normalization of mailbox names for matching.
+//!
+//! The display name is never changed, this only produces a key for comparisons, so that eg.
+//! "prichozi" finds "Příchozí".
+
+use std::collections::BTreeMap;
+
+fn transliterate(c: char, out: &mut String) {
+    let replacement = match c {
+        'á' | 'à' | 'â' | 'ä' | 'ã' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'č' | 'ć' | 'ç' => "c",
+        'ď' | 'đ' => "d",
+        'é' | 'è' | 'ê' | 'ë' | 'ě' | 'ē' | 'ę' => "e",
+        'í' | 'ì' | 'î' | 'ï' | 'ī' => "i",
+        'ľ' | 'ĺ' | 'ł' => "l",
+        'ň' | 'ń' | 'ñ' => "n",
+        'ó' | 'ò' | 'ô' | 'ö' | 'õ' | 'ő' | 'ø' | 'ō' => "o",
+        'ř' | 'ŕ' => "r",
+        'š' | 'ś' | 'ş' => "s",
+        'ť' | 'ţ' => "t",
+        'ú' | 'ù' | 'û' | 'ü' | 'ů' | 'ű' | 'ū' => "u",
+        'ý' | 'ÿ' => "y",
+        'ž' | 'ź' | 'ż' => "z",
+        'ß' => "ss",
+        'æ' => "ae",
+        'œ' => "oe",
+        c => {
+            out.push(c);
+            return;
+        }
+    };
+    out.push_str(replacement);
+}
+
+/// Case-folds the name and replaces common Latin diacritics by their ASCII counterparts.
+crate fn normalize(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.chars().flat_map(char::to_lowercase) {
+        transliterate(c, &mut result);
+    }
+    result
+}
+
+/// Groups of names that become the same once normalized, by what they normalize to.
+///
+/// Both the groups and the names in them are sorted.
+crate fn ambiguous<'a, I>(names: I) -> Vec<(String, Vec<String>)>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut seen = BTreeMap::<String, Vec<String>>::new();
+    for name in names {
+        seen.entry(normalize(name))
+            .or_insert_with(Vec::new)
+            .push(name.clone());
+    }
+    seen.into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(normalized, mut names)| {
+            names.sort();
+            (normalized, names)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transliteration() {
+        let cases = &[
+            ('á', "a"), ('ą', "a"), ('č', "c"), ('đ', "d"), ('ě', "e"), ('ï', "i"), ('ł', "l"),
+            ('ñ', "n"), ('ő', "o"), ('ø', "o"), ('ř', "r"), ('ş', "s"), ('ť', "t"), ('ů', "u"),
+            ('ÿ', "y"), ('ż', "z"), ('ß', "ss"), ('æ', "ae"), ('œ', "oe"), ('a', "a"), ('Á', "Á"),
+            ('я', "я"), ('-', "-"), (' ', " "), ('1', "1"),
+        ];
+        for &(c, expected) in cases {
+            let mut out = "x".to_owned();
+            transliterate(c, &mut out);
+            assert_eq!(format!("x{}", expected), out, "{}", c);
+        }
+    }
+
+    #[test]
+    fn normalization() {
+        let cases = &[
+            ("Příchozí", "prichozi"),
+            ("PŘÍCHOZÍ", "prichozi"),
+            ("prichozi", "prichozi"),
+            ("Straße", "strasse"),
+            ("ÆØÅ", "aeoa"),
+            ("Žluťoučký kůň", "zlutoucky kun"),
+            ("Sent Items", "sent items"),
+            ("lists/Rust-Users", "lists/rust-users"),
+            ("Входящие", "входящие"),
+            ("", ""),
+        ];
+        for &(name, expected) in cases {
+            assert_eq!(expected, normalize(name), "{}", name);
+            // Normalizing twice changes nothing more
+            assert_eq!(expected, normalize(expected), "{}", name);
+        }
+    }
+
+    #[test]
+    fn ambiguity() {
+        let names = ["Příchozí", "prichozi", "Odeslané", "PRICHOZI", "odeslane", "Koš"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let expected = vec![
+            ("odeslane".to_owned(), vec!["Odeslané".to_owned(), "odeslane".to_owned()]),
+            ("prichozi".to_owned(),
+             vec!["PRICHOZI".to_owned(), "Příchozí".to_owned(), "prichozi".to_owned()]),
+        ];
+        assert_eq!(expected, ambiguous(&names));
+        assert!(ambiguous(&names[5..]).is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+//! Rescanning the mailboxes periodically.
+//!
+//! This is for mailboxes where watching doesn't help, like ones on NFS or inside compressed files
+//! rewritten by other tools.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use log::{debug, trace};
+
+use crate::config::Cfg;
+use super::{Mailbox, MAILBOXES};
+use super::task::Task;
+use super::workers::Handle;
+
+/// Polls the mailboxes until dropped.
+crate struct Poll {
+    _stop: Sender<()>,
+}
+
+/// Starts pushing rescans of the mailboxes into the pool, each at its poll interval.
+///
+/// The queue merges a rescan with the one already waiting for the same mailbox, so polling faster
+/// than the mailbox can be rescanned leaves at most one rescan pending, not a pile of them.
+crate fn poll(cfg: &Cfg, pool: Handle) -> Result<Poll, Error> {
+    // Nothing is ever sent, dropping the sender wakes the thread up to stop
+    let (stop, stopped) = mpsc::channel();
+    let polled = MAILBOXES
+        .lock()
+        .values()
+        .map(|mbox| (Arc::clone(mbox), mbox.poll_interval(cfg)))
+        .filter(|(_, interval)| *interval > Duration::from_secs(0))
+        .collect::<Vec<(Arc<Mailbox>, Duration)>>();
+    if polled.is_empty() {
+        debug!("No mailboxes to poll");
+        return Ok(Poll {
+            _stop: stop,
+        });
+    }
+    debug!("Polling {} mailboxes", polled.len());
+
+    let now = Instant::now();
+    // The initial scan has just been queued, so the first poll comes one interval later
+    let mut due = polled
+        .iter()
+        .enumerate()
+        .map(|(idx, (_, interval))| (Reverse(now + *interval), idx))
+        .collect::<BinaryHeap<_>>();
+
+    thread::Builder::new()
+        .name("poll".to_owned())
+        .spawn(move || loop {
+            let (Reverse(when), idx) = due.pop().expect("Polled mailboxes never run out");
+            let now = Instant::now();
+            if when > now {
+                if let Err(RecvTimeoutError::Disconnected) = stopped.recv_timeout(when - now) {
+                    debug!("Stopped polling");
+                    return;
+                }
+            }
+            let (ref mbox, interval) = polled[idx];
+            trace!("Polling mailbox {}", mbox.name());
+            pool.push(Task::rescan(Arc::clone(mbox)));
+            // Counted from now, so a long stall (eg. a suspended laptop) doesn't cause a burst of
+            // all the missed polls
+            due.push((Reverse(Instant::now() + interval), idx));
+        })?;
+    Ok(Poll {
+        _stop: stop,
+    })
+}
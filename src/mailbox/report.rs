@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// How many skipped candidates are remembered for each root. The rest are only counted.
+const MAX_SKIPPED: usize = 100;
+
+/// A mailbox candidate the scan found but didn't register.
+#[derive(Clone, Debug)]
+crate struct Skipped {
+    crate path: PathBuf,
+    crate reason: &'static str,
+    crate when: SystemTime,
+}
+
+/// Outcome of walking a single search root.
+#[derive(Clone, Debug)]
+crate struct RootReport {
+    crate path: PathBuf,
+    crate entries: usize,
+    crate errors: usize,
+    crate mailboxes: usize,
+    /// The walk didn't get past the root itself.
+    crate aborted: bool,
+    crate failed: bool,
+    /// The first few skipped candidates.
+    crate skipped: Vec<Skipped>,
+    /// Skipped candidates that didn't fit into the list.
+    crate skipped_overflow: usize,
+    /// Number of skipped candidates by the reason.
+    crate skip_counts: BTreeMap<&'static str, usize>,
+}
+
+impl RootReport {
+    crate fn new(path: PathBuf) -> Self {
+        RootReport {
+            path,
+            entries: 0,
+            errors: 0,
+            mailboxes: 0,
+            aborted: false,
+            failed: false,
+            skipped: Vec::new(),
+            skipped_overflow: 0,
+            skip_counts: BTreeMap::new(),
+        }
+    }
+
+    pub(super) fn skip(&mut self, path: PathBuf, reason: &'static str) {
+        *self.skip_counts.entry(reason).or_insert(0) += 1;
+        if self.skipped.len() < MAX_SKIPPED {
+            self.skipped.push(Skipped {
+                path,
+                reason,
+                when: SystemTime::now(),
+            });
+        } else {
+            self.skipped_overflow += 1;
+        }
+    }
+
+    pub(super) fn finish(&mut self, max_error_fraction: f64) {
+        let fraction = if self.entries == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.entries as f64
+        };
+        self.failed = self.aborted || fraction > max_error_fraction;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+crate struct ScanReport {
+    crate roots: Vec<RootReport>,
+}
+
+impl ScanReport {
+    crate fn failed_roots(&self) -> impl Iterator<Item = &RootReport> {
+        self.roots.iter().filter(|root| root.failed)
+    }
+
+    /// The path is inside a root that failed, so the mailboxes there may be incomplete or gone.
+    crate fn in_failed_root(&self, path: &Path) -> bool {
+        self.failed_roots().any(|root| path.starts_with(&root.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_failed_root() {
+        let mut failed = RootReport::new(PathBuf::from("/mail/broken"));
+        failed.entries = 4;
+        failed.errors = 3;
+        failed.finish(0.5);
+        let mut healthy = RootReport::new(PathBuf::from("/mail/fine"));
+        healthy.entries = 4;
+        healthy.errors = 1;
+        healthy.finish(0.5);
+        let report = ScanReport {
+            roots: vec![failed, healthy],
+        };
+        assert!(report.in_failed_root(Path::new("/mail/broken/INBOX")));
+        assert!(report.in_failed_root(Path::new("/mail/broken")));
+        assert!(!report.in_failed_root(Path::new("/mail/fine/INBOX")));
+        // Only whole components count
+        assert!(!report.in_failed_root(Path::new("/mail/broken-not/INBOX")));
+        assert!(!ScanReport::default().in_failed_root(Path::new("/mail/broken/INBOX")));
+    }
+}
@@ -0,0 +1,391 @@
+//! The lua side of mailbox configuration.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::{bail, Error, ResultExt};
+use log::{debug, error, trace, warn};
+use rlua::{
+    AnyUserData, Error as LuaError, Lua, Function, String as LuaString, UserData, UserDataMethods,
+    Table,
+};
+
+use crate::config::{Cfg, ScriptIsolation};
+use crate::error::Kind;
+use crate::units::DurationSpec;
+use super::{CallbackStats, Mailbox};
+use super::mbox::Format as MboxFormat;
+use super::filter::Filter;
+
+const CONFIG_CBACKS: &str = "config-cbacks";
+const POST_SCAN_CBACKS: &str = "post-scan-cbacks";
+const CONTENT_CBACKS: &str = "content-cbacks";
+const CURRENT_SCRIPT: &str = "current-script";
+/// Set by the ignore method, cleared before the callbacks of each mailbox.
+const IGNORED: &str = "ignored";
+/// Wraps a table (and the tables in it) so the scripts can read it, but not change it.
+const READ_ONLY: &str = r#"
+local function read_only(t)
+    for k, v in pairs(t) do
+        if type(v) == "table" then
+            t[k] = read_only(v)
+        end
+    end
+    return setmetatable({}, {
+        __index = t,
+        __newindex = function() error("mix.config is read-only", 2) end,
+        __len = function() return #t end,
+        __pairs = function() return next, t, nil end,
+        __metatable = false,
+    })
+end
+return read_only
+"#;
+
+/// Paths go to lua as they are, they don't have to be valid utf8.
+fn lua_path<'lua>(lua: &'lua Lua, path: &Path) -> Result<LuaString<'lua>, LuaError> {
+    lua.create_string(path.as_os_str().as_bytes())
+}
+
+/// The methods reading the mailbox, shared by the configured and the registered one.
+fn add_getters<'lua, T, M>(methods: &mut M, get: fn(&T) -> &Mailbox)
+where
+    T: UserData + 'static,
+    M: UserDataMethods<'lua, T>,
+{
+    methods.add_method("name", move |_, this, ()| Ok(get(this).name().to_owned()));
+    methods.add_method("path", move |lua: &_, this, ()| lua_path(lua, &get(this).path));
+    methods.add_method("kind", move |_, this, ()| Ok(get(this).kind()));
+    methods.add_method("prio", move |_, this, ()| Ok(get(this).prio()));
+    methods.add_method("hidden", move |_, this, ()| Ok(get(this).hidden()));
+    methods.add_method("shortcut", move |_, this, ()| {
+        Ok(get(this).shortcut().map(|sc| sc.to_string()))
+    });
+    // Nil until the mailbox is read, which is never the case in the config callbacks
+    methods.add_method("total", move |_, this, ()| {
+        Ok(get(this).scanned_counts().map(|(total, _)| total))
+    });
+    // Mboxes don't know which messages are unseen, so they give nil always
+    methods.add_method("unseen", move |_, this, ()| {
+        Ok(get(this).scanned_counts().and_then(|(_, unseen)| unseen))
+    });
+}
+
+impl UserData for Mailbox {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        add_getters(methods, |this: &Mailbox| this);
+        methods.add_method("ignore", |lua: &Lua, _, ()| {
+            lua.set_named_registry_value(IGNORED, true)
+        });
+        methods.add_method_mut("set_name", |_, this, name| {
+            this.name = name;
+            Ok(())
+        });
+        methods.add_method_mut("set_prio", |_, this, prio| {
+            this.prio = prio;
+            Ok(())
+        });
+        methods.add_method_mut("set_hidden", |_, this, hidden| {
+            this.hidden = hidden;
+            Ok(())
+        });
+        // An empty string (or nil) clears the shortcut
+        methods.add_method_mut("set_shortcut", |_, this, sc: Option<String>| {
+            let sc = sc.unwrap_or_default();
+            let mut chars = sc.chars();
+            this.shortcut = match (chars.next(), chars.next()) {
+                (first, None) => first,
+                _ => {
+                    let msg = format!("Shortcut {:?} is not a single character", sc);
+                    return Err(LuaError::RuntimeError(msg));
+                }
+            };
+            Ok(())
+        });
+        methods.add_method_mut("set_mbox_format", |_, this, format: String| {
+            this.mbox_format = MboxFormat::from_name(&format).ok_or_else(|| {
+                LuaError::RuntimeError(format!("Unknown mbox format {}", format))
+            })?;
+            Ok(())
+        });
+        // Numbers are converted to strings by lua, so both 30 and "5m" work
+        methods.add_method_mut("set_poll_interval", |_, this, interval: String| {
+            let interval = DurationSpec::parse(&interval).map_err(LuaError::RuntimeError)?;
+            this.poll_interval = Some(interval.0);
+            Ok(())
+        });
+    }
+}
+
+/// A registered mailbox, as the content hooks see it. It can't be changed any more.
+struct Registered(Arc<Mailbox>);
+
+impl UserData for Registered {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        add_getters(methods, |this: &Registered| &*this.0);
+    }
+}
+
+fn lua_load<P: AsRef<Path>>(lua: &Lua, script: P, isolation: ScriptIsolation)
+    -> Result<(), Error>
+{
+    debug!("Running lua script from {}", script.as_ref().display());
+    let mut f = File::open(&script)?;
+    // TODO: Once lua supports non-utf8 stuff, use Vec<u8>
+    let mut code = Vec::new();
+    f.read_to_end(&mut code)?;
+    let name = script.as_ref().to_string_lossy();
+    lua.set_named_registry_value(CURRENT_SCRIPT, &*name)?;
+    match isolation {
+        ScriptIsolation::Shared => lua.exec(&code, Some(&name)).map_err(Error::from),
+        ScriptIsolation::PerFile => {
+            // Globals the script sets land in its own table, everything else is looked up in the
+            // shared globals.
+            let env = lua.create_table()?;
+            let meta = lua.create_table()?;
+            meta.set("__index", lua.globals())?;
+            env.set_metatable(Some(meta));
+            // The rust side can't pass an environment to a chunk, but lua's own load can.
+            let load = lua.globals().get::<_, Function>("load")?;
+            let code = lua.create_string(&code)?;
+            let (chunk, err) = load
+                .call::<_, (Option<Function>, Option<String>)>((code, &*name, "t", env))?;
+            match chunk {
+                Some(chunk) => chunk.call::<_, ()>(()).map_err(Error::from),
+                None => bail!("{}", err.unwrap_or_default()),
+            }
+        }
+    }
+}
+
+/// Registers callbacks into the given registry table, optionally limited by a filter.
+fn register_cback(lua: &Lua, registry: &str, c: Function, filter: Option<Table>)
+    -> Result<(), LuaError>
+{
+    let cback = lua.create_table()?;
+    cback.set("cback", c)?;
+    cback.set("filter", Filter::from_lua(filter)?)?;
+    cback.set("script", lua.named_registry_value::<String>(CURRENT_SCRIPT)?)?;
+    let cbacks = lua.named_registry_value::<Table>(registry)?;
+    let len = cbacks.raw_len();
+    cbacks.raw_set(len + 1, cback)
+}
+
+/// The configuration as the scripts see it, in `mix.config`.
+fn config_table<'lua>(lua: &'lua Lua, cfg: &Cfg) -> Result<Table<'lua>, LuaError> {
+    let search = cfg
+        .storage
+        .search
+        .iter()
+        .map(|root| lua_path(lua, &root.path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let meta = lua.create_table()?;
+    for (path, entry) in &cfg.storage.meta {
+        let table = lua.create_table()?;
+        table.set("name", entry.name.clone())?;
+        table.set("shortcut", entry.shortcut.map(|sc| sc.to_string()))?;
+        table.set("prio", entry.prio)?;
+        meta.set(lua_path(lua, path)?, table)?;
+    }
+    let storage = lua.create_table()?;
+    storage.set("search", lua.create_sequence_from(search)?)?;
+    storage.set("meta", meta)?;
+    let config = lua.create_table()?;
+    config.set("socket", lua_path(lua, &cfg.socket)?)?;
+    config.set("storage", storage)?;
+    // A copy, so changing it wouldn't change anything anyway. But it's better to say so.
+    let read_only = lua.eval::<_, Function>(READ_ONLY, Some("read-only config"))?;
+    read_only.call(config)
+}
+
+fn describe(cback: &Table, what: &str, idx: usize, filter: &Filter) -> String {
+    let script = cback
+        .get::<_, String>("script")
+        .unwrap_or_else(|_| "<???>".to_owned());
+    format!("{} callback #{} from {} (filter {})", what, idx + 1, script, filter)
+}
+
+/// The user scripts, loaded and ready to configure mailboxes.
+///
+/// The instance of the last scan is kept to run the content hooks.
+pub(super) struct Scripts {
+    lua: Lua,
+    slow: Duration,
+    normalize_names: bool,
+    /// Time spent in each callback, by its registry and index.
+    stats: RefCell<BTreeMap<(&'static str, usize), CallbackStats>>,
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+impl Scripts {
+    /// Creates the lua instance used for configuring the mailboxes and runs the user scripts in it.
+    pub(super) fn load(cfg: &Cfg) -> Result<Self, Error> {
+        let lua = Lua::new();
+
+        trace!("Preparing configuration lua instance");
+        // Set up functions the scripts can call
+        lua.set_named_registry_value(CONFIG_CBACKS, lua.create_table()?)?;
+        lua.set_named_registry_value(POST_SCAN_CBACKS, lua.create_table()?)?;
+        lua.set_named_registry_value(CONTENT_CBACKS, lua.create_table()?)?;
+        // This'll allow them to register config callbacks, optionally limited by a filter
+        let register_config = |lua: &Lua, (c, filter): (Function, Option<Table>)| {
+            register_cback(lua, CONFIG_CBACKS, c, filter)
+        };
+        lua.globals().set("register_config", lua.create_function(register_config)?)?;
+        // And the same for callbacks once the mailboxes are read
+        let register_post_scan = |lua: &Lua, (c, filter): (Function, Option<Table>)| {
+            register_cback(lua, POST_SCAN_CBACKS, c, filter)
+        };
+        lua.globals().set("register_post_scan", lua.create_function(register_post_scan)?)?;
+        // And hooks for when the content of a mailbox changes
+        let register_on_content = |lua: &Lua, (c, filter): (Function, Option<Table>)| {
+            register_cback(lua, CONTENT_CBACKS, c, filter)
+        };
+        lua.globals().set("register_on_content", lua.create_function(register_on_content)?)?;
+        let mix = lua.create_table()?;
+        mix.set("config", config_table(&lua, cfg)?)?;
+        lua.globals().set("mix", mix)?;
+
+        for script in &cfg.scripts {
+            lua_load(&lua, script, cfg.script_isolation)
+                .with_context(|_| format!("Failed to load lua script {}", script.display()))
+                .context(Kind::Script)?;
+        }
+
+        Ok(Scripts {
+            lua,
+            slow: cfg.slow_callback.0,
+            normalize_names: cfg.normalize_names,
+            stats: RefCell::new(BTreeMap::new()),
+        })
+    }
+
+    /// Runs the matching config callbacks on a freshly detected mailbox.
+    ///
+    /// Gives nothing if a callback ignored the mailbox (by calling its ignore method or returning
+    /// false). The rest of the callbacks don't run then.
+    pub(super) fn configure(&self, mbox: Mailbox, normalize_names: bool)
+        -> Result<Option<Mailbox>, Error>
+    {
+        self.run(CONFIG_CBACKS, "Config", mbox, normalize_names)
+    }
+
+    /// Whether any post-scan callbacks are registered.
+    pub(super) fn has_post_scan(&self) -> Result<bool, Error> {
+        let cbacks = self.lua.named_registry_value::<Table>(POST_SCAN_CBACKS)?;
+        Ok(cbacks.raw_len() > 0)
+    }
+
+    /// Runs the matching post-scan callbacks on an already read mailbox.
+    ///
+    /// The mailbox may get ignored here too.
+    pub(super) fn post_scan(&self, mbox: Mailbox, normalize_names: bool)
+        -> Result<Option<Mailbox>, Error>
+    {
+        self.run(POST_SCAN_CBACKS, "Post-scan", mbox, normalize_names)
+    }
+
+    fn run(&self, registry: &'static str, what: &str, mbox: Mailbox, normalize_names: bool)
+        -> Result<Option<Mailbox>, Error>
+    {
+        let lua = &self.lua;
+        let cbacks = lua.named_registry_value::<Table>(registry)?;
+        let handle = lua.create_userdata(mbox)?;
+        lua.set_named_registry_value(IGNORED, false)?;
+
+        for (idx, cback) in cbacks.sequence_values::<Table>().enumerate() {
+            let cback = cback?;
+            let filter = cback.get::<_, AnyUserData>("filter")?;
+            let filter = filter.borrow::<Filter>()?;
+            if !filter.matches(&*handle.borrow::<Mailbox>()?, normalize_names) {
+                continue;
+            }
+            let describe = || describe(&cback, what, idx, &filter);
+            let start = Instant::now();
+            let result = cback
+                .get::<_, Function>("cback")?
+                .call::<_, Option<bool>>(handle.clone());
+            let name = handle.borrow::<Mailbox>()?.name().to_owned();
+            self.record(registry, idx, &describe, &name, start.elapsed());
+            let returned = result.with_context(|_| format!("{} failed", describe()))?;
+            // Returning false does the same as ignore, returning nothing (or anything else) doesn't
+            if lua.named_registry_value::<bool>(IGNORED)? || returned == Some(false) {
+                trace!("{} ignored mailbox {}", describe(), handle.borrow::<Mailbox>()?.name());
+                return Ok(None);
+            }
+        }
+
+        let result = handle.borrow::<Mailbox>()?.clone();
+        Ok(Some(result))
+    }
+
+    /// Runs the matching content hooks on a mailbox whose counts changed.
+    ///
+    /// A failing hook is only logged, the others still run.
+    pub(super) fn on_content(&self, mbox: &Arc<Mailbox>, new: usize, total: usize)
+        -> Result<(), Error>
+    {
+        let lua = &self.lua;
+        let cbacks = lua.named_registry_value::<Table>(CONTENT_CBACKS)?;
+        if cbacks.raw_len() == 0 {
+            return Ok(());
+        }
+        let handle = lua.create_userdata(Registered(Arc::clone(mbox)))?;
+
+        for (idx, cback) in cbacks.sequence_values::<Table>().enumerate() {
+            let cback = cback?;
+            let filter = cback.get::<_, AnyUserData>("filter")?;
+            let filter = filter.borrow::<Filter>()?;
+            if !filter.matches(mbox, self.normalize_names) {
+                continue;
+            }
+            let describe = || describe(&cback, "Content", idx, &filter);
+            let start = Instant::now();
+            let result = cback
+                .get::<_, Function>("cback")?
+                .call::<_, ()>((handle.clone(), new, total));
+            self.record(CONTENT_CBACKS, idx, &describe, mbox.name(), start.elapsed());
+            if let Err(e) = result {
+                error!("{} failed on mailbox {}: {}", describe(), mbox.name(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Warns about a slow callback and adds the call to its stats.
+    fn record(&self, registry: &'static str, idx: usize, describe: &dyn Fn() -> String,
+              mbox: &str, elapsed: Duration)
+    {
+        if elapsed > self.slow {
+            warn!("{} took {}ms on mailbox {}", describe(), millis(elapsed), mbox);
+        }
+        let mut stats = self.stats.borrow_mut();
+        let stats = stats.entry((registry, idx)).or_insert_with(|| CallbackStats {
+            description: describe(),
+            ..CallbackStats::default()
+        });
+        stats.calls += 1;
+        stats.total += elapsed;
+    }
+
+    /// Logs how much time each of the config callbacks took.
+    pub(super) fn log_stats(&self) {
+        for stats in self.stats.borrow().values() {
+            debug!("{}: {} calls, {}ms total", stats.description, stats.calls,
+                   millis(stats.total));
+        }
+    }
+
+    pub(super) fn stats(&self) -> Vec<CallbackStats> {
+        self.stats.borrow().values().cloned().collect()
+    }
+}
@@ -1,9 +1,13 @@
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
 use std::collections::BTreeSet;
 use std::ops::Deref;
 use std::sync::Arc;
 
-use super::Mailbox;
+use log::{debug, error};
+
+use super::{content_changed, Mailbox, Notification};
+use super::cancel::{Cancelled, Token};
+use crate::path_key::PathKey;
 
 #[derive(Clone, Debug)]
 pub(super) struct ArcCmp<T>(Arc<T>);
@@ -52,60 +56,341 @@ impl<T> PartialOrd for ArcCmp<T> {
 
 impl<T> Ord for ArcCmp<T> {
     fn cmp(&self, other: &Self) -> Ordering {
-        // TODO: Is there a nicer way to compare two Arcs?
-        let me = self as &T as *const _ as usize;
-        let other = other as &T as *const _ as usize;
+        // Compare the addresses of the shared allocations, not of the wrappers, to be consistent
+        // with Arc::ptr_eq in PartialEq.
+        let me = &*self.0 as *const T;
+        let other = &*other.0 as *const T;
         me.cmp(&other)
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(super) enum RescanMode {
+    /// Allowed to take any shortcut the caches offer.
+    Incremental,
+    /// Ignore whatever is known about the mailbox and rebuild everything from scratch.
+    Full,
+}
+
 // Note: The order of tasks is significant, as it specifies priority
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub(super) enum Task {
-    Rescan(ArcCmp<Mailbox>),
+    Rescan(ArcCmp<Mailbox>, RescanMode),
+}
+
+impl PartialOrd for Task {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Mailboxes with higher prio go first, the name makes the order stable between runs. The identity
+// of the mailbox comes last, only to stay consistent with Eq.
+impl Ord for Task {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order_key().cmp(&other.order_key())
+    }
 }
 
 impl Task {
     pub fn rescan(mbox: Arc<Mailbox>) -> Self {
-        Task::Rescan(ArcCmp::from(mbox))
+        Task::Rescan(ArcCmp::from(mbox), RescanMode::Incremental)
+    }
+    pub fn full_rescan(mbox: Arc<Mailbox>) -> Self {
+        Task::Rescan(ArcCmp::from(mbox), RescanMode::Full)
     }
-    fn perform(self) {
-        unimplemented!();
+    fn order_key(&self) -> (Reverse<usize>, &str, &ArcCmp<Mailbox>, RescanMode) {
+        match self {
+            Task::Rescan(mbox, mode) => (Reverse(mbox.prio), &mbox.name, mbox, *mode),
+        }
+    }
+    fn mailbox(&self) -> &ArcCmp<Mailbox> {
+        match self {
+            Task::Rescan(mbox, _) => mbox,
+        }
+    }
+    pub(super) fn perform(self, cancel: &Token) {
+        match self {
+            Task::Rescan(mbox, mode) => match mbox.rescan(mode, cancel) {
+                Ok(before) => {
+                    let mbox = mbox.into_inner();
+                    content_changed(&mbox, before);
+                    Notification::send(Notification::MailboxContent(mbox));
+                }
+                // The cache is left as it was
+                Err(ref e) if e.downcast_ref::<Cancelled>().is_some() => {
+                    debug!("Rescan of mailbox {} cancelled", mbox.name());
+                }
+                Err(e) => {
+                    let msg = format!("Failed to rescan mailbox {}: {}", mbox.name(), e);
+                    error!("{}", msg);
+                    Notification::send(Notification::Error(msg));
+                }
+            },
+        }
     }
 }
 
 // We use BTreeSet, not BinaryHeap even though the BinaryHeap is more natural for priority queues.
 // We want to have deduplication and we get it for free here.
+//
+// The busy set holds the mailboxes some task is being performed on right now. No two tasks on the
+// same mailbox may run at once, so tasks of busy mailboxes are left in the queue until the
+// running one finishes. It is keyed by the path, not by the Arc ‒ after a rescan of the roots,
+// the same mailbox may be in the queue as a different Arc, while a task of the old one still
+// runs.
 #[derive(Debug)]
-crate struct Queue(BTreeSet<Task>);
+crate struct Queue {
+    tasks: BTreeSet<Task>,
+    busy: BTreeSet<PathKey>,
+}
 
 impl Queue {
     pub(super) fn new() -> Self {
-        Queue(BTreeSet::new())
+        Queue {
+            tasks: BTreeSet::new(),
+            busy: BTreeSet::new(),
+        }
     }
     pub(super) fn push(&mut self, task: Task) {
+        // A full rescan does everything the incremental one would, so they coalesce into the full
+        // one, whichever came first.
+        match task {
+            Task::Rescan(ref mbox, RescanMode::Full) => {
+                self.tasks.remove(&Task::Rescan(mbox.clone(), RescanMode::Incremental));
+            }
+            Task::Rescan(ref mbox, RescanMode::Incremental) => {
+                if self.tasks.contains(&Task::Rescan(mbox.clone(), RescanMode::Full)) {
+                    return;
+                }
+            }
+        }
         // We don't care if it was already in there. It'll merge duplicates.
-        self.0.insert(task);
+        self.tasks.insert(task);
     }
 
-    fn pop(&mut self) -> Option<Task> {
-        if let Some(task) = self.0.iter().next().cloned() {
-            self.0.remove(&task);
-            Some(task)
-        } else {
-            None
+    /// Takes the most important task whose mailbox is not busy.
+    ///
+    /// The mailbox is marked as busy until the task is handed back through `finish`.
+    pub(super) fn pop(&mut self) -> Option<Task> {
+        let busy = &self.busy;
+        let task = self
+            .tasks
+            .iter()
+            .find(|task| !busy.contains(&task.mailbox().key))
+            .cloned()?;
+        self.tasks.remove(&task);
+        self.busy.insert(task.mailbox().key.clone());
+        Some(task)
+    }
+
+    pub(super) fn finish(&mut self, task: &Task) {
+        let was_busy = self.busy.remove(&task.mailbox().key);
+        debug_assert!(was_busy, "Finished task of mailbox that wasn't busy");
+    }
+
+    /// Some task is being performed right now.
+    pub(super) fn is_busy(&self) -> bool {
+        !self.busy.is_empty()
+    }
+
+    /// Nothing waits in the queue and nothing is being performed.
+    pub(super) fn is_idle(&self) -> bool {
+        self.tasks.is_empty() && self.busy.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::thread;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::mailbox::MailboxBuilder;
+
+    fn mbox(path: &str) -> Arc<Mailbox> {
+        Arc::new(MailboxBuilder::new(path).build().unwrap())
+    }
+
+    /// The identity of the allocation decides, not the content.
+    #[test]
+    fn arc_cmp_identity() {
+        let a = ArcCmp::from(1);
+        let b = ArcCmp::from(1);
+        let same = &a;
+        assert_eq!(a, *same);
+        assert_eq!(a, a.clone());
+        assert_eq!(Ordering::Equal, a.cmp(&a.clone()));
+        assert_ne!(a, b);
+        assert_ne!(Ordering::Equal, a.cmp(&b));
+        assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+
+        let set = vec![a.clone(), b.clone(), a.clone(), b]
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        assert_eq!(2, set.len());
+        assert!(set.contains(&a));
+    }
+
+    #[test]
+    fn task_identity() {
+        let a = mbox("/nonexistent/mix/a");
+        let other = mbox("/nonexistent/mix/a");
+        let task = Task::rescan(Arc::clone(&a));
+        let same = &task;
+        assert_eq!(task, *same);
+        assert_eq!(task, task.clone());
+        assert_eq!(Ordering::Equal, task.cmp(&task.clone()));
+        assert_eq!(task, Task::rescan(Arc::clone(&a)));
+        assert_ne!(task, Task::full_rescan(Arc::clone(&a)));
+        assert_ne!(task, Task::rescan(Arc::clone(&other)));
+
+        let set = vec![
+            task.clone(),
+            Task::rescan(Arc::clone(&a)),
+            Task::rescan(Arc::clone(&other)),
+            task,
+        ];
+        assert_eq!(2, set.into_iter().collect::<BTreeSet<_>>().len());
+    }
+
+    fn pop_names(queue: &mut Queue) -> Vec<(String, RescanMode)> {
+        let mut names = Vec::new();
+        while let Some(task) = queue.pop() {
+            queue.finish(&task);
+            let Task::Rescan(mbox, mode) = task;
+            names.push((mbox.name().to_owned(), mode));
         }
+        names
     }
 
-    /// One turn of the queue.
-    ///
-    /// Returns true if there was a task (and it was performed) and false if it was empty.
-    pub(super) fn turn(&mut self) -> bool {
-        if let Some(task) = self.pop() {
-            task.perform();
-            true
-        } else {
-            false
+    /// Higher prio goes first, the name decides between the same prio.
+    #[test]
+    fn pop_order() {
+        let named = |name: &str, prio| {
+            let mbox = MailboxBuilder::new(format!("/nonexistent/mix/{}", name))
+                .with_prio(prio)
+                .build()
+                .unwrap();
+            Arc::new(mbox)
+        };
+        let mut queue = Queue::new();
+        queue.push(Task::rescan(named("b", 0)));
+        queue.push(Task::rescan(named("a", 0)));
+        queue.push(Task::full_rescan(named("z", 2)));
+        queue.push(Task::rescan(named("c", 1)));
+        queue.push(Task::rescan(named("a", 2)));
+        let expected = vec![
+            ("a".to_owned(), RescanMode::Incremental),
+            ("z".to_owned(), RescanMode::Full),
+            ("c".to_owned(), RescanMode::Incremental),
+            ("a".to_owned(), RescanMode::Incremental),
+            ("b".to_owned(), RescanMode::Incremental),
+        ];
+        assert_eq!(expected, pop_names(&mut queue));
+    }
+
+    /// The full rescan wins over the incremental one, no matter which one comes first.
+    #[test]
+    fn full_over_incremental() {
+        let first = mbox("/nonexistent/mix/first");
+        let second = mbox("/nonexistent/mix/second");
+        let mut queue = Queue::new();
+        queue.push(Task::rescan(Arc::clone(&first)));
+        queue.push(Task::full_rescan(Arc::clone(&first)));
+        queue.push(Task::full_rescan(Arc::clone(&second)));
+        queue.push(Task::rescan(Arc::clone(&second)));
+        let expected = vec![
+            ("first".to_owned(), RescanMode::Full),
+            ("second".to_owned(), RescanMode::Full),
+        ];
+        assert_eq!(expected, pop_names(&mut queue));
+    }
+
+    /// Polling a slow mailbox doesn't pile up its rescans.
+    #[test]
+    fn dedup_rescans() {
+        let slow = mbox("/nonexistent/mix/slow");
+        let mut queue = Queue::new();
+        queue.push(Task::rescan(Arc::clone(&slow)));
+        queue.push(Task::rescan(Arc::clone(&slow)));
+        assert_eq!(1, queue.tasks.len());
+
+        // While it is being rescanned, more of the polls come
+        let running = queue.pop().unwrap();
+        for _ in 0..10 {
+            queue.push(Task::rescan(Arc::clone(&slow)));
+        }
+        assert_eq!(1, queue.tasks.len());
+        assert!(queue.pop().is_none(), "The mailbox is still busy");
+        queue.finish(&running);
+        let expected = vec![("slow".to_owned(), RescanMode::Incremental)];
+        assert_eq!(expected, pop_names(&mut queue));
+    }
+
+    /// Different Arcs of the same path are still the same mailbox for the busy set.
+    #[test]
+    fn busy_by_path() {
+        let old = mbox("/nonexistent/mix/a");
+        let new = mbox("/nonexistent/mix/a");
+        let mut queue = Queue::new();
+        queue.push(Task::rescan(Arc::clone(&old)));
+        queue.push(Task::full_rescan(Arc::clone(&new)));
+        let first = queue.pop().unwrap();
+        assert!(queue.pop().is_none());
+        queue.finish(&first);
+        let second = queue.pop().unwrap();
+        assert!(!Arc::ptr_eq(first.mailbox(), second.mailbox()));
+        queue.finish(&second);
+        assert!(queue.is_idle());
+    }
+
+    /// Many threads draining a queue full of tasks of few paths, each path in several Arcs.
+    #[test]
+    fn busy_stress() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 50;
+        let paths = (0..5).map(|idx| format!("/nonexistent/mix/{}", idx)).collect::<Vec<_>>();
+        let queue = Arc::new(Mutex::new(Queue::new()));
+        let running = Arc::new(Mutex::new(HashSet::new()));
+        let threads = (0..THREADS)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let running = Arc::clone(&running);
+                let paths = paths.clone();
+                thread::spawn(move || {
+                    let mut performed = 0;
+                    for round in 0..ROUNDS {
+                        for path in &paths {
+                            let task = if round % 2 == 0 {
+                                Task::rescan(mbox(path))
+                            } else {
+                                Task::full_rescan(mbox(path))
+                            };
+                            queue.lock().push(task);
+                        }
+                        let task = queue.lock().pop();
+                        if let Some(task) = task {
+                            let path = task.mailbox().path().to_owned();
+                            assert!(running.lock().insert(path.clone()), "{:?} twice", path);
+                            thread::yield_now();
+                            assert!(running.lock().remove(&path));
+                            queue.lock().finish(&task);
+                            performed += 1;
+                        }
+                    }
+                    performed
+                })
+            })
+            .collect::<Vec<_>>();
+        let performed: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert!(performed > 0);
+        let mut queue = queue.lock();
+        while let Some(task) = queue.pop() {
+            queue.finish(&task);
         }
+        assert!(queue.is_idle());
     }
 }
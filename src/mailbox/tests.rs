@@ -0,0 +1,535 @@
+//! Tests of detecting, scanning and reading the mailboxes, on generated fixtures.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use test::Bencher;
+use walkdir::{DirEntry, WalkDir};
+
+use crate::config::{self, Cfg};
+use crate::glob::Glob;
+use crate::fixtures::{self, Compression, MaildirSpec, Manifest, MboxSpec, MhSpec, Rng, TempDir};
+use super::*;
+
+/// The walk entry of the path itself.
+fn entry(path: &Path) -> DirEntry {
+    WalkDir::new(path).into_iter().next().unwrap().unwrap()
+}
+
+fn detect(path: &Path) -> Option<Mailbox> {
+    Mailbox::detect(&entry(path)).unwrap()
+}
+
+/// A configuration searching in the given roots, with whatever more options.
+fn cfg(roots: &[&Path], extra: &str) -> Cfg {
+    let roots = roots
+        .iter()
+        .map(|root| format!("{:?}", root.display().to_string()))
+        .collect::<Vec<_>>();
+    config::parse(&format!("{}\n[storage]\nsearch = [{}]\n", extra, roots.join(", ")))
+}
+
+fn assert_counts(manifest: &Manifest, unseen_known: bool) {
+    let mbox = detect(&manifest.path).expect("Not detected as a mailbox");
+    let before = mbox.rescan(RescanMode::Full, &Token::default()).unwrap();
+    assert!(before.is_none(), "The first read has no before");
+    let unseen = if unseen_known { Some(manifest.unseen) } else { None };
+    assert_eq!((manifest.messages, unseen), mbox.counts(), "{}", manifest.path.display());
+}
+
+#[test]
+fn detect_kinds() {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(1);
+    MaildirSpec::default().build(&dir.path().join("mdir"), &mut rng);
+    MboxSpec::default().build(&dir.path().join("mbox"), &mut rng);
+    let gz = MboxSpec {
+        compression: Compression::Gzip,
+        ..MboxSpec::default()
+    };
+    gz.build(&dir.path().join("mbox.gz"), &mut rng);
+    MhSpec::default().build(&dir.path().join("mh"), &mut rng);
+    fixtures::write(dir.path().join("mmdf"), "\x01\x01\x01\x01\nSubject: a\n\n\x01\x01\x01\x01\n");
+
+    let kind = |name: &str| detect(&dir.path().join(name)).map(|mbox| mbox.kind());
+    assert_eq!(Some("maildir"), kind("mdir"));
+    assert_eq!(Some("mbox"), kind("mbox"));
+    assert_eq!(Some("mbox-gz"), kind("mbox.gz"));
+    assert_eq!(Some("mh"), kind("mh"));
+    assert_eq!(Some("mmdf"), kind("mmdf"));
+    assert_eq!("mdir", detect(&dir.path().join("mdir")).unwrap().name());
+}
+
+#[test]
+fn detect_not_mailboxes() {
+    let dir = TempDir::new();
+    fixtures::write(dir.path().join("text"), "Just some notes\n");
+    fixtures::write(dir.path().join("empty"), "");
+    fixtures::write(dir.path().join("short"), "Fro");
+    // Looks like gzip, but isn't
+    fixtures::write(dir.path().join("fake.gz"), b"\x1F\x8Bnot really");
+    // Plenty of directories are full of numbered files
+    for idx in 1..5 {
+        fixtures::write(dir.path().join("numbers").join(idx.to_string()), "x");
+    }
+    // Only some of the maildir subdirectories
+    fs::create_dir_all(dir.path().join("half").join("cur")).unwrap();
+    for name in &["text", "empty", "short", "fake.gz", "numbers", "half"] {
+        assert!(detect(&dir.path().join(name)).is_none(), "{} is not a mailbox", name);
+    }
+}
+
+#[test]
+fn count_maildir() {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(2);
+    let plain = MaildirSpec {
+        messages: 50,
+        ..MaildirSpec::default()
+    };
+    assert_counts(&plain.build(&dir.path().join("plain"), &mut rng), true);
+    let messy = MaildirSpec {
+        messages: 30,
+        collisions: 5,
+        non_utf8: 3,
+        junk: true,
+        ..MaildirSpec::default()
+    };
+    assert_counts(&messy.build(&dir.path().join("messy"), &mut rng), true);
+}
+
+#[test]
+fn count_mbox() {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(3);
+    let specs = vec![
+        MboxSpec::default(),
+        MboxSpec {
+            quoting: false,
+            ..MboxSpec::default()
+        },
+        MboxSpec {
+            crlf: true,
+            corrupt: 3,
+            messages: 40,
+            ..MboxSpec::default()
+        },
+        MboxSpec {
+            compression: Compression::Gzip,
+            messages: 25,
+            ..MboxSpec::default()
+        },
+    ];
+    for (idx, spec) in specs.iter().enumerate() {
+        let manifest = spec.build(&dir.path().join(idx.to_string()), &mut rng);
+        assert_counts(&manifest, false);
+        let mbox = detect(&manifest.path).unwrap();
+        let parsed = Mbox::parse(mbox.open_mbox().unwrap(), MboxFormat::Auto, &Token::default())
+            .unwrap();
+        let offsets = parsed.messages().map(|msg| msg.offset).collect::<Vec<_>>();
+        assert_eq!(manifest.offsets, offsets, "{:?}", spec);
+    }
+}
+
+#[test]
+fn count_mh() {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(4);
+    let spec = MhSpec {
+        messages: 40,
+        junk: true,
+        ..MhSpec::default()
+    };
+    assert_counts(&spec.build(&dir.path().join("mh"), &mut rng), true);
+}
+
+#[test]
+fn builder() {
+    let mbox = MailboxBuilder::new("/mail/INBOX")
+        .with_kind("maildir")
+        .with_prio(3)
+        .with_shortcut("i")
+        .with_counts(10, Some(4))
+        .build()
+        .unwrap();
+    assert_eq!("INBOX", mbox.name());
+    assert_eq!(Path::new("/mail/INBOX"), mbox.path());
+    assert_eq!("maildir", mbox.kind());
+    assert_eq!(3, mbox.prio());
+    assert_eq!(Some('i'), mbox.shortcut());
+    assert_eq!((10, Some(4)), mbox.counts());
+
+    let mbox = MailboxBuilder::new("/mail/box.gz")
+        .with_path("/mail/archive.gz")
+        .with_name("archive")
+        .with_kind("mbox-gz")
+        .with_counts(7, None)
+        .build()
+        .unwrap();
+    assert_eq!("archive", mbox.name());
+    assert_eq!(Path::new("/mail/archive.gz"), mbox.path());
+    assert_eq!((7, None), mbox.counts());
+
+    let mh = MailboxBuilder::new("/mail/mh").with_kind("mh").with_counts(5, None).build();
+    assert_eq!((5, Some(0)), mh.unwrap().counts());
+    let unread = MailboxBuilder::new("/mail/box").build().unwrap();
+    assert!(unread.scanned_counts().is_none());
+}
+
+#[test]
+fn builder_invalid() {
+    let invalid = vec![
+        MailboxBuilder::new("/mail/INBOX").with_shortcut("ab"),
+        MailboxBuilder::new("/mail/INBOX").with_shortcut(""),
+        MailboxBuilder::new("/mail/INBOX").with_kind("pst"),
+        // Only maildir like caches know what is unseen
+        MailboxBuilder::new("/mail/box.gz").with_kind("mbox-gz").with_counts(3, Some(1)),
+        MailboxBuilder::new("/mail/INBOX").with_kind("maildir").with_counts(3, Some(4)),
+    ];
+    for builder in invalid {
+        assert!(builder.clone().build().is_err(), "{:?}", builder);
+    }
+}
+
+#[test]
+fn notification_json() {
+    let mbox = MailboxBuilder::new("/mail/INBOX")
+        .with_kind("maildir")
+        .with_shortcut("i")
+        .with_counts(3, Some(1))
+        .build()
+        .map(Arc::new)
+        .unwrap();
+    let appeared = Notification::MailboxAppeared(Arc::clone(&mbox)).to_json();
+    assert_eq!(json!({
+        "event": "mailbox-appeared",
+        "name": "INBOX",
+        "path": "/mail/INBOX",
+        "kind": "maildir",
+        "prio": 0,
+        "shortcut": "i",
+    }), appeared);
+    let content = Notification::MailboxContent(mbox).to_json();
+    assert_eq!(json!({
+        "event": "mailbox-content",
+        "name": "INBOX",
+        "messages": 3,
+        "unseen": 1,
+    }), content);
+}
+
+/// An incremental rescan trusts the cache of an unchanged mailbox, a full one reads it anyway.
+#[test]
+fn rescan_modes() {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(5);
+    let manifest = MboxSpec::default().build(&dir.path().join("mbox"), &mut rng);
+    let mbox = detect(&manifest.path).unwrap();
+    let token = Token::default();
+    mbox.rescan(RescanMode::Incremental, &token).unwrap();
+    assert_eq!((manifest.messages, None), mbox.counts());
+
+    // A cache that is wrong, but looks valid
+    *mbox.cache.lock() = Cache::Mbox(Mbox::default());
+    let before = mbox.rescan(RescanMode::Incremental, &token).unwrap();
+    assert_eq!(Some((0, None)), before);
+    assert_eq!((0, None), mbox.counts(), "The file didn't change, nothing was read");
+
+    let before = mbox.rescan(RescanMode::Full, &token).unwrap();
+    assert_eq!(Some((0, None)), before);
+    assert_eq!((manifest.messages, None), mbox.counts(), "The whole file was parsed again");
+
+    // A change makes even the incremental one read it
+    let more = MboxSpec {
+        messages: 3,
+        ..MboxSpec::default()
+    };
+    more.build(&manifest.path, &mut rng);
+    mbox.rescan(RescanMode::Incremental, &token).unwrap();
+    assert_eq!((3, None), mbox.counts());
+}
+
+const POLICIES: &[&str] = &["register", "warn", "ignore"];
+
+/// Scans the directory with the nesting policy, returning the registered and skipped paths.
+fn scan_nested(dir: &Path, policy: &str) -> (Vec<PathBuf>, Vec<(PathBuf, &'static str)>) {
+    let cfg = cfg(&[dir], &format!("nested_mailboxes = {:?}", policy));
+    let found = scan(&cfg).unwrap();
+    let registered = found
+        .order
+        .iter()
+        .map(|mbox| mbox.path().to_owned())
+        .collect::<Vec<_>>();
+    let skipped = found.report.roots[0]
+        .skipped
+        .iter()
+        .map(|skipped| (skipped.path.clone(), skipped.reason))
+        .collect::<Vec<_>>();
+    (registered, skipped)
+}
+
+/// Whatever lies in cur, new and tmp are messages, even ones looking like an mbox.
+#[test]
+fn maildir_messages_not_nested() {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(8);
+    let mdir = dir.path().join("mdir");
+    MaildirSpec::default().build(&mdir, &mut rng);
+    // Procmail and getmail deliver with the From line
+    fixtures::write(mdir.join("cur").join("1500000000.M1P1.mixtest:2,S"),
+                    "From someone@example.com Thu Jan  1 00:00:00 1970\nSubject: a\n\nHi\n");
+    fixtures::write(mdir.join("new").join("1500000001.M1P1.mixtest"),
+                    "From someone@example.com Thu Jan  1 00:00:00 1970\nSubject: b\n\nHi\n");
+    MboxSpec::default().build(&mdir.join("cur").join("saved.mbox"), &mut rng);
+    for policy in POLICIES {
+        assert_eq!((vec![mdir.clone()], vec![]), scan_nested(dir.path(), policy), "{}", policy);
+    }
+}
+
+/// A maildir inside another one (not a Maildir++ folder) is subject to the nesting policy.
+#[test]
+fn maildir_in_maildir() {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(10);
+    let mdir = dir.path().join("mdir");
+    MaildirSpec::default().build(&mdir, &mut rng);
+    let inner = mdir.join("inner");
+    MaildirSpec::default().build(&inner, &mut rng);
+    let mbox = mdir.join("saved.mbox");
+    MboxSpec::default().build(&mbox, &mut rng);
+
+    let (mut registered, skipped) = scan_nested(dir.path(), "register");
+    registered.sort();
+    assert_eq!(vec![mdir.clone(), inner.clone(), mbox.clone()], registered);
+    assert!(skipped.is_empty());
+    // Ignoring them only doesn't warn, they are still reported as skipped
+    for policy in &["warn", "ignore"] {
+        let (registered, mut skipped) = scan_nested(dir.path(), policy);
+        skipped.sort();
+        assert_eq!(vec![mdir.clone()], registered, "{}", policy);
+        assert_eq!(vec![(inner.clone(), NESTED), (mbox.clone(), NESTED)], skipped, "{}", policy);
+    }
+}
+
+/// Symlinks back up the tree and to a sibling don't make the scan loop or see a mailbox twice.
+#[test]
+fn symlink_loop() {
+    use std::os::unix::fs::symlink;
+
+    let dir = TempDir::new();
+    let mut rng = Rng::new(9);
+    let mail = dir.path().join("mail");
+    let inner = mail.join("inner");
+    MaildirSpec::default().build(&inner.join("mdir"), &mut rng);
+    MboxSpec::default().build(&mail.join("mbox"), &mut rng);
+    symlink(&mail, inner.join("up")).unwrap();
+    symlink(&inner, mail.join("again")).unwrap();
+
+    let cfg = cfg(&[&mail], "");
+    let found = scan(&cfg).unwrap();
+    let mut paths = found
+        .order
+        .iter()
+        .map(|mbox| PathKey::new(mbox.path()))
+        .collect::<Vec<_>>();
+    paths.sort();
+    let expected = vec![PathKey::new(inner.join("mdir")), PathKey::new(mail.join("mbox"))];
+    assert_eq!(expected, paths);
+    let root = &found.report.roots[0];
+    assert_eq!(0, root.errors);
+    assert!(root.skipped.iter().any(|skipped| skipped.reason == LOOP), "{:?}", root.skipped);
+}
+
+/// Looking up by the exact name and by the normalized one, with the normalization on and off.
+#[test]
+fn lookup_names() {
+    // The registry is shared with other tests, so the names are unique to this one
+    let names = &["lookup-test Příchozí", "lookup-test Koš", "lookup-test koš", "lookup-test KOŠE"];
+    let mailboxes = names
+        .iter()
+        .map(|name| {
+            let mbox = MailboxBuilder::new(format!("/nonexistent/mix/{}", name))
+                .with_name(*name)
+                .build()
+                .unwrap();
+            Arc::new(mbox)
+        })
+        .collect::<Vec<_>>();
+    MAILBOXES
+        .lock()
+        .extend(mailboxes.iter().map(|mbox| (mbox.name.clone(), Arc::clone(mbox))));
+
+    let found = |name, normalize| lookup(name, normalize).map(|mbox| mbox.name.clone());
+    for &normalize in &[false, true] {
+        for name in names {
+            assert_eq!(Some(name.to_string()), found(name, normalize));
+        }
+        assert_eq!(None, found("lookup-test nothing", normalize));
+        assert_eq!(None, found("lookup-test", normalize));
+    }
+    let normalized = &["lookup-test prichozi", "lookup-test PŘÍCHOZÍ", "lookup-test příchozí"];
+    for name in normalized {
+        assert_eq!(None, found(name, false));
+        assert_eq!(Some(names[0].to_owned()), found(name, true));
+    }
+    assert_eq!(None, found("lookup-test KOSE", false));
+    assert_eq!(Some(names[3].to_owned()), found("lookup-test kose", true));
+    // Ambiguous, the exact match wins and otherwise it's one of them
+    assert_eq!(Some(names[2].to_owned()), found(names[2], true));
+    let kos = found("lookup-test kos", true).unwrap();
+    assert!(kos == names[1] || kos == names[2], "{}", kos);
+
+    let mut registered = MAILBOXES.lock();
+    let ambiguous = normalize::ambiguous(registered.keys())
+        .into_iter()
+        .filter(|(normalized, _)| normalized.starts_with("lookup-test"))
+        .collect::<Vec<_>>();
+    let kos = vec![names[1].to_owned(), names[2].to_owned()];
+    let expected = vec![("lookup-test kos".to_owned(), kos)];
+    assert_eq!(expected, ambiguous);
+    for name in names {
+        registered.remove(*name);
+    }
+}
+
+/// Mailbox files at these paths, relative to the search root.
+const GLOB_TREE: &[&str] = &[
+    "inbox", "old.bak", "sub/old.bak", "spam", "sub/spamtrap", "junk/m", "sub/junk/m", "arch/a",
+    "arch/deep/b", "x/arch/c", "ab", "sub/ac/m", "dir/m", "sub/dir/m", "we[ir]d$ (name)",
+];
+
+const GLOB_PATTERNS: &[&str] = &[
+    "*.bak", "spam*", "**/junk", "arch/*", "[ab]?", "dir/", "**/dir/", "*[[]ir]d$ (*", "sub/**",
+    "?????",
+];
+
+/// Builds the GLOB_TREE, returning the root and the absolute paths of the mailboxes.
+fn glob_tree(dir: &TempDir) -> (PathBuf, Vec<PathBuf>) {
+    let root = dir.path().join("mail");
+    let mut rng = Rng::new(11);
+    let mut paths = GLOB_TREE.iter().map(|rel| root.join(rel)).collect::<Vec<_>>();
+    for path in &paths {
+        MboxSpec::default().build(path, &mut rng);
+    }
+    paths.sort();
+    (root, paths)
+}
+
+/// Storage.exclude matches the patterns the same way as the glob module says.
+#[test]
+fn exclude_globs() {
+    let dir = TempDir::new();
+    let (root, paths) = glob_tree(&dir);
+    for pattern in GLOB_PATTERNS {
+        let glob = Glob::cached(pattern).unwrap();
+        let excluded = |rel: &Path| {
+            rel.ancestors().filter(|a| *a != Path::new("")).any(|ancestor| {
+                if pattern.trim_end_matches('/').contains('/') {
+                    glob.is_match(ancestor)
+                } else {
+                    glob.is_match(ancestor.file_name().unwrap())
+                }
+            })
+        };
+        let expected = paths
+            .iter()
+            .filter(|path| !excluded(path.strip_prefix(&root).unwrap()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut cfg = cfg(&[&root], "");
+        cfg.storage.exclude = vec![pattern.to_string()];
+        let mut found = scan(&cfg)
+            .unwrap()
+            .order
+            .iter()
+            .map(|mbox| mbox.path().to_owned())
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(expected, found, "{}", pattern);
+    }
+}
+
+/// The path_glob filter of the scripts matches the same way as the glob module says.
+#[cfg(feature = "lua")]
+#[test]
+fn filter_globs() {
+    let dir = TempDir::new();
+    let (root, paths) = glob_tree(&dir);
+    for pattern in GLOB_PATTERNS {
+        let pattern = format!("{}/{}", root.display(), pattern);
+        let glob = Glob::cached(&pattern).unwrap();
+        let script = dir.path().join("config.lua");
+        fixtures::write(&script, format!(
+            "register_config(function(mbox) mbox:set_prio(1) end, {{ path_glob = {:?} }})",
+            pattern,
+        ));
+        let cfg = cfg(&[&root], &format!("scripts = [{:?}]", script));
+        let found = scan(&cfg).unwrap();
+        assert_eq!(paths.len(), found.order.len());
+        for mbox in &found.order {
+            let path = mbox.path();
+            assert_eq!(glob.is_match(path), mbox.prio() == 1, "{} {}", pattern, path.display());
+        }
+    }
+}
+
+/// The scripts count the calls of each callback, separately.
+#[cfg(feature = "lua")]
+#[test]
+fn script_stats() {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(7);
+    for name in &["a", "b", "c"] {
+        MaildirSpec::default().build(&dir.path().join("mail").join(name), &mut rng);
+    }
+    let script = dir.path().join("config.lua");
+    fixtures::write(&script, r#"
+        register_config(function(mbox) end)
+        register_config(function(mbox) end, { name_match = "^b$" })
+        register_on_content(function(mbox) end)
+    "#);
+    let cfg = cfg(&[&dir.path().join("mail")], &format!("scripts = [{:?}]", script));
+    let found = scan(&cfg).unwrap();
+    let mut stats = found
+        .scripts
+        .stats()
+        .into_iter()
+        .map(|stats| stats.calls)
+        .collect::<Vec<_>>();
+    stats.sort();
+    // The content hook wasn't called yet, so it is not there at all
+    assert_eq!(vec![1, 3], stats);
+}
+
+/// A corpus of a few thousand messages, in all the kinds, read from scratch.
+#[bench]
+fn read_corpus(b: &mut Bencher) {
+    let dir = TempDir::new();
+    let mut rng = Rng::new(1234);
+    for idx in 0..5 {
+        let maildir = MaildirSpec {
+            messages: 300,
+            ..MaildirSpec::default()
+        };
+        maildir.build(&dir.path().join(format!("mdir-{}", idx)), &mut rng);
+        let mbox = MboxSpec {
+            messages: 300,
+            compression: if idx % 2 == 0 { Compression::Plain } else { Compression::Gzip },
+            ..MboxSpec::default()
+        };
+        mbox.build(&dir.path().join(format!("mbox-{}", idx)), &mut rng);
+        let mh = MhSpec {
+            messages: 100,
+            ..MhSpec::default()
+        };
+        mh.build(&dir.path().join(format!("mh-{}", idx)), &mut rng);
+    }
+    let cfg = cfg(&[dir.path()], "");
+    b.iter(|| {
+        let found = scan(&cfg).unwrap();
+        for mbox in &found.order {
+            mbox.rescan(RescanMode::Full, &Token::default()).unwrap();
+        }
+        found.order.len()
+    });
+}
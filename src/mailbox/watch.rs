@@ -0,0 +1,140 @@
+//! Noticing changes of the mailboxes and scheduling their rescans.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+
+use failure::Error;
+use log::{debug, trace, warn};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::config::Cfg;
+use super::{Mailbox, Type, MAILBOXES};
+use super::task::Task;
+use super::workers::Handle;
+
+/// The mailboxes by the paths whose events concern them.
+///
+/// A maildir gets its message directories watched and an MH folder the folder itself, any file
+/// right inside them belongs to the mailbox. An mbox is watched through the directory it is in, as
+/// rewriting it usually replaces the file by a rename and that would end a watch of the file
+/// itself. Only the events of the file concern the mailbox then.
+#[derive(Default)]
+struct Targets {
+    dirs: HashMap<PathBuf, Arc<Mailbox>>,
+    files: HashMap<PathBuf, Arc<Mailbox>>,
+}
+
+impl Targets {
+    fn affected(&self, path: &Path) -> Option<&Arc<Mailbox>> {
+        self.files
+            .get(path)
+            .or_else(|| self.dirs.get(path))
+            .or_else(|| path.parent().and_then(|parent| self.dirs.get(parent)))
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Arc<Mailbox>> {
+        self.dirs.values().chain(self.files.values())
+    }
+}
+
+/// Watches the mailboxes until dropped.
+crate struct Watch {
+    _watcher: RecommendedWatcher,
+}
+
+/// Starts watching all the registered mailboxes.
+///
+/// Each change pushes a rescan of its mailbox to the pool. The events are debounced, so a burst
+/// of changes of one mailbox results in a single rescan. Mailboxes that can't be watched (eg. on
+/// a filesystem without inotify support) are only warned about.
+crate fn watch(cfg: &Cfg, pool: Handle) -> Result<Watch, Error> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::watcher(sender, cfg.watch_debounce.0)?;
+    let mut targets = Targets::default();
+    let mut watched = HashSet::new();
+    let mut add = |watcher: &mut RecommendedWatcher, path: &Path| {
+        if watched.contains(path) {
+            return true;
+        }
+        match watcher.watch(path, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watched.insert(path.to_owned());
+                true
+            }
+            Err(e) => {
+                warn!("Not watching {} for changes: {}", path.display(), e);
+                false
+            }
+        }
+    };
+    for mbox in MAILBOXES.lock().values() {
+        match mbox.tp {
+            Type::Dir => {
+                for sub in &["new", "cur"] {
+                    let dir = mbox.path.join(sub);
+                    if add(&mut watcher, &dir) {
+                        targets.dirs.insert(dir, Arc::clone(mbox));
+                    }
+                }
+            }
+            Type::Mh => {
+                if add(&mut watcher, &mbox.path) {
+                    targets.dirs.insert(mbox.path.clone(), Arc::clone(mbox));
+                }
+            }
+            _ => {
+                let dir = match mbox.path.parent() {
+                    Some(dir) if dir != Path::new("") => dir,
+                    _ => Path::new("."),
+                };
+                if add(&mut watcher, dir) {
+                    targets.files.insert(mbox.path.clone(), Arc::clone(mbox));
+                }
+            }
+        }
+    }
+    debug!("Watching {} paths", watched.len());
+
+    thread::Builder::new()
+        .name("watcher".to_owned())
+        .spawn(move || {
+            // Ends when the watcher is dropped, together with the sending end
+            for event in receiver {
+                trace!("Watch event {:?}", event);
+                let paths = match event {
+                    DebouncedEvent::Create(path)
+                    | DebouncedEvent::Write(path)
+                    | DebouncedEvent::Remove(path) => vec![path],
+                    // Maildir clients change flags by renaming the files, mbox writers replace
+                    // the whole file by one
+                    DebouncedEvent::Rename(from, to) => vec![from, to],
+                    // Events got lost, so anything may have changed
+                    DebouncedEvent::Rescan => {
+                        for mbox in targets.all() {
+                            pool.push(Task::rescan(Arc::clone(mbox)));
+                        }
+                        continue;
+                    }
+                    DebouncedEvent::Error(e, path) => {
+                        warn!("Watching {:?} failed: {}", path, e);
+                        continue;
+                    }
+                    // The debounced event comes later
+                    _ => continue,
+                };
+                for path in paths {
+                    if let Some(mbox) = targets.affected(&path) {
+                        debug!("Mailbox {} changed", mbox.name());
+                        pool.push(Task::rescan(Arc::clone(mbox)));
+                    }
+                }
+            }
+            debug!("Stopped watching");
+        })?;
+    Ok(Watch {
+        _watcher: watcher,
+    })
+}
@@ -0,0 +1,143 @@
+//! The threads performing the tasks from the queue.
+
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use log::{debug, trace};
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+use super::Mailbox;
+use super::cancel::Token;
+use super::task::{Queue, Task};
+
+struct State {
+    queue: Queue,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled whenever something changes in the state ‒ a task is added or finished, or the
+    /// pool is shutting down.
+    changed: Condvar,
+    /// Cancels the running tasks on shutdown.
+    cancel: Token,
+}
+
+fn worker(shared: &Shared) {
+    let mut state = shared.state.lock();
+    loop {
+        if state.shutdown {
+            return;
+        }
+        match state.queue.pop() {
+            Some(task) => {
+                trace!("Performing {:?}", task);
+                MutexGuard::unlocked(&mut state, || task.clone().perform(&shared.cancel));
+                state.queue.finish(&task);
+                // Tasks of this mailbox may be eligible again and someone might be waiting for
+                // the queue to become idle.
+                shared.changed.notify_all();
+            }
+            None => shared.changed.wait(&mut state),
+        }
+    }
+}
+
+/// Adds tasks to the queue of a running pool, from any thread.
+#[derive(Clone)]
+crate struct Handle(Arc<Shared>);
+
+impl Handle {
+    pub(super) fn push(&self, task: Task) {
+        self.0.state.lock().queue.push(task);
+        self.0.changed.notify_all();
+    }
+
+    /// Queues a rescan of the mailbox, one that may trust its caches.
+    crate fn rescan(&self, mbox: Arc<Mailbox>) {
+        self.push(Task::rescan(mbox));
+    }
+
+    /// Queues reading the whole mailbox again, whatever is cached.
+    crate fn full_rescan(&self, mbox: Arc<Mailbox>) {
+        self.push(Task::full_rescan(mbox));
+    }
+
+    /// Blocks until there are no tasks left to do and none is being done.
+    crate fn wait_idle(&self) {
+        let mut state = self.0.state.lock();
+        while !state.queue.is_idle() {
+            self.0.changed.wait(&mut state);
+        }
+    }
+}
+
+/// A pool of worker threads draining a task queue.
+///
+/// No two tasks of the same mailbox are performed at once.
+crate struct Pool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    /// Starts the threads, working on the given queue.
+    crate fn start(queue: Queue, workers: usize) -> Result<Self, Error> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+            cancel: Token::default(),
+        });
+        let threads = (0..workers.max(1))
+            .map(|idx| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("worker-{}", idx))
+                    .spawn(move || worker(&shared))
+                    .map_err(Error::from)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        debug!("Started {} workers", threads.len());
+        Ok(Pool { shared, threads })
+    }
+
+    /// A pool with nothing to do yet.
+    #[cfg(test)]
+    crate fn empty(workers: usize) -> Result<Self, Error> {
+        Self::start(Queue::new(), workers)
+    }
+
+    crate fn handle(&self) -> Handle {
+        Handle(Arc::clone(&self.shared))
+    }
+
+    /// Stops the workers.
+    ///
+    /// No more tasks are started and the ones still in the queue are abandoned. The tasks being
+    /// performed get the grace period to finish, then they are cancelled.
+    crate fn shutdown(self, grace: Duration) {
+        let deadline = Instant::now() + grace;
+        let mut state = self.shared.state.lock();
+        state.shutdown = true;
+        self.shared.changed.notify_all();
+        while state.queue.is_busy() {
+            if self.shared.changed.wait_until(&mut state, deadline).timed_out() {
+                debug!("Cancelling the tasks still running after the grace period");
+                self.shared.cancel.cancel();
+                break;
+            }
+        }
+        drop(state);
+        for thread in self.threads {
+            // A worker can't really panic, as we abort on panics
+            let _ = thread.join();
+        }
+        debug!("Workers shut down");
+    }
+}
@@ -1,28 +1,127 @@
 #![feature(crate_visibility_modifier, nll)]
+#![cfg_attr(test, feature(test))]
 #![forbid(unsafe_code)]
 
-use failure::{Error, ResultExt};
+use std::panic;
+use std::process;
+use std::thread;
+
+use failure::{bail, format_err, Error, ResultExt};
 use log::{debug, error};
 
 mod config;
+mod doctor;
+mod error;
+// Nothing but the tests and benchmarks uses them
+#[cfg(any(test, feature = "test-fixtures"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod fixtures;
+mod glob;
 mod mailbox;
+mod mutt;
+mod path_key;
+mod path_trie;
+mod redact;
+mod reload;
+mod server;
+mod shutdown;
+mod units;
+mod version;
+
+#[cfg(test)]
+extern crate test;
+
+use crate::config::{CmdLine, Command};
+use crate::error::Kind;
 
 fn run() -> Result<(), Error> {
-    let cfg = config::load()
-        .context("Failed to load configuration")?;
-    let work_queue = mailbox::initial_scan(&cfg)?;
+    let cmd_line = config::cmd_line();
+    match cmd_line.cmd {
+        Some(Command::ImportMutt { ref muttrc, ref output }) => {
+            mutt::import(muttrc, output.as_ref().map(|o| o.as_path()))
+        }
+        Some(Command::Convert { to_mbox: false, .. }) => {
+            bail!("Only conversion --to-mbox is supported")
+        }
+        Some(Command::Convert { ref source, ref target, include_trashed, force, compress, .. }) => {
+            let opts = mailbox::ConvertOptions {
+                include_trashed,
+                force,
+                compress,
+            };
+            mailbox::convert_to_mbox(source, target, &opts)
+        }
+        Some(Command::Query { ref socket, ref command }) => {
+            let socket = match socket {
+                Some(socket) => socket.clone(),
+                None => config::load(&cmd_line).context(Kind::Config)?.socket,
+            };
+            server::query(&socket, &command.join(" "))
+        }
+        Some(Command::Version { verbose }) => {
+            version::print(verbose);
+            Ok(())
+        }
+        Some(Command::Doctor { json }) => doctor::doctor(&cmd_line, json),
+        None => daemon(&cmd_line),
+    }
+}
+
+fn daemon(cmd_line: &CmdLine) -> Result<(), Error> {
+    let cfg = config::load(cmd_line)
+        .context(Kind::Config)?;
+    if let Some(ref path) = cmd_line.explain {
+        return mailbox::explain(&cfg, path);
+    }
+    // Bind before the scan, so a second instance fails right away
+    let listener = server::bind(&cfg)?;
+    let (work_queue, report) = mailbox::initial_scan(&cfg)?;
     debug!("Mailboxes: {:?}", *mailbox::MAILBOXES.lock());
     debug!("Initial work queue: {:?}", work_queue);
-    Ok(())
+    debug!("Scan report: {:?}", report);
+    if cfg.require_all_roots {
+        let failed = report
+            .failed_roots()
+            .map(|root| root.path.display().to_string())
+            .collect::<Vec<_>>();
+        if !failed.is_empty() {
+            let err = format_err!("Failed to scan search roots: {}", failed.join(", "));
+            return Err(err.context(Kind::FailedRoots).into());
+        }
+    }
+    let pool = mailbox::Pool::start(work_queue, cfg.workers)?;
+    reload::start(cmd_line, &cfg, pool.handle())?;
+    let (stop, stopped) = shutdown::listen()?;
+    let normalize_names = cfg.normalize_names;
+    let handle = pool.handle();
+    thread::Builder::new()
+        .name("server".to_owned())
+        .spawn(move || {
+            // Only when it fails, it runs forever otherwise
+            let _ = stop.send(server::run(listener, normalize_names, handle));
+        })?;
+    let result = stopped
+        .recv()
+        .expect("The signal thread never hangs up");
+    pool.shutdown(cfg.shutdown_grace.0);
+    server::unbind(&cfg);
+    result
 }
 
 fn main() {
-    env_logger::init();
+    redact::init_logger();
+    // We abort on panic, so it can't be caught. But we still can make sure of the exit code.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        process::exit(error::EXIT_INTERNAL);
+    }));
     if let Err(e) = run() {
         error!("{}", e);
         for cause in e.iter_causes() {
             error!("Because: {}", cause);
         }
         debug!("Backtrace: {}", e.backtrace());
+        process::exit(error::exit_code(&e));
     }
 }
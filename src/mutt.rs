@@ -0,0 +1,523 @@
+//! Import of mailbox definitions from a muttrc.
+//!
+//! Only the parts relevant to us are understood ‒ `set folder`, `mailboxes`, `named-mailboxes`,
+//! `unmailboxes` and `source`. Everything else is silently ignored, as it is mutt's business.
+//! Mailbox related lines we can't make sense of are reported as skipped.
+
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, ResultExt};
+use log::{debug, trace};
+use serde_derive::Serialize;
+
+const MAX_SOURCE_DEPTH: usize = 16;
+const REMOTE_PREFIXES: &[&str] = &[
+    "imap://", "imaps://", "pop://", "pops://", "notmuch://", "nntp://",
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+crate struct Entry {
+    crate path: PathBuf,
+    crate name: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+crate struct Skipped {
+    crate file: PathBuf,
+    crate line: usize,
+    crate text: String,
+    crate reason: String,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+crate struct Import {
+    crate mailboxes: Vec<Entry>,
+    crate skipped: Vec<Skipped>,
+}
+
+/// Splits a line into words the way mutt does.
+///
+/// Handles single and double quotes, backslash escapes and comments. Returns an error message for
+/// things we don't support (backticks) or broken lines.
+crate fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' if current.is_none() => break,
+            c if c.is_whitespace() => {
+                if let Some(token) = current.take() {
+                    tokens.push(token);
+                }
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or_else(|| "Trailing backslash".to_owned())?;
+                current.get_or_insert_with(String::new).push(escaped);
+            }
+            '\'' => {
+                let token = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => token.push(c),
+                        None => return Err("Unterminated single quote".to_owned()),
+                    }
+                }
+            }
+            '"' => {
+                let token = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            let escaped = chars
+                                .next()
+                                .ok_or_else(|| "Unterminated double quote".to_owned())?;
+                            token.push(escaped);
+                        }
+                        Some('`') => return Err("Backtick command substitution".to_owned()),
+                        Some(c) => token.push(c),
+                        None => return Err("Unterminated double quote".to_owned()),
+                    }
+                }
+            }
+            '`' => return Err("Backtick command substitution".to_owned()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    tokens.extend(current);
+    Ok(tokens)
+}
+
+/// Joins the physical lines ending with a backslash into logical ones.
+///
+/// Returns the logical lines together with the (1-based) number of the line they started on.
+fn logical_lines(content: &str) -> Vec<(usize, String)> {
+    let mut result = Vec::new();
+    let mut pending: Option<(usize, String)> = None;
+    for (idx, line) in content.lines().enumerate() {
+        let (start, mut acc) = pending.take().unwrap_or_else(|| (idx + 1, String::new()));
+        // An odd number of trailing backslashes means the newline is escaped
+        let trailing = line.chars().rev().take_while(|&c| c == '\\').count();
+        if trailing % 2 == 1 {
+            acc.push_str(&line[..line.len() - 1]);
+            pending = Some((start, acc));
+        } else {
+            acc.push_str(line);
+            result.push((start, acc));
+        }
+    }
+    result.extend(pending);
+    result
+}
+
+fn expand_home(path: &str) -> Result<PathBuf, String> {
+    if path == "~" || path.starts_with("~/") {
+        let home = env::var_os("HOME").ok_or_else(|| "HOME is not set".to_owned())?;
+        Ok(PathBuf::from(home).join(path[1..].trim_start_matches('/')))
+    } else if path.starts_with('~') {
+        Err("Home directories of other users are not supported".to_owned())
+    } else {
+        Ok(PathBuf::from(path))
+    }
+}
+
+struct Parser {
+    folder: Option<String>,
+    visited: HashSet<PathBuf>,
+    result: Import,
+}
+
+impl Parser {
+    fn skip(&mut self, file: &Path, line: usize, text: &str, reason: &str) {
+        trace!("Skipping {}:{}: {}", file.display(), line, reason);
+        self.result.skipped.push(Skipped {
+            file: file.to_owned(),
+            line,
+            text: text.to_owned(),
+            reason: reason.to_owned(),
+        });
+    }
+
+    fn resolve(&self, mailbox: &str) -> Result<PathBuf, String> {
+        if REMOTE_PREFIXES.iter().any(|prefix| mailbox.starts_with(prefix)) {
+            return Err("Remote mailbox".to_owned());
+        }
+        let path = if mailbox.starts_with('+') || mailbox.starts_with('=') {
+            let folder = self
+                .folder
+                .as_ref()
+                .ok_or_else(|| "Relative to folder, but folder is not set".to_owned())?;
+            expand_home(folder)?.join(&mailbox[1..])
+        } else {
+            expand_home(mailbox)?
+        };
+        if path.is_absolute() {
+            Ok(path)
+        } else {
+            Err("Relative path".to_owned())
+        }
+    }
+
+    fn add(&mut self, file: &Path, line: usize, text: &str, mailbox: &str, name: Option<String>) {
+        match self.resolve(mailbox) {
+            Ok(path) => {
+                if self.result.mailboxes.iter().all(|entry| entry.path != path) {
+                    self.result.mailboxes.push(Entry { path, name });
+                }
+            }
+            Err(reason) => self.skip(file, line, text, &format!("{}: {}", mailbox, reason)),
+        }
+    }
+
+    fn set(&mut self, args: &[String]) {
+        // Must handle all of "folder=x", "folder = x", "folder= x" and "folder =x"
+        let mut iter = args.iter().peekable();
+        while let Some(arg) = iter.next() {
+            let (name, value) = if let Some(pos) = arg.find('=') {
+                let value = &arg[pos + 1..];
+                if value.is_empty() {
+                    (arg[..pos].to_owned(), iter.next().cloned())
+                } else {
+                    (arg[..pos].to_owned(), Some(value.to_owned()))
+                }
+            } else if iter.peek().map(|next| next.starts_with('=')) == Some(true) {
+                let next = iter.next().unwrap();
+                if next.len() == 1 {
+                    (arg.clone(), iter.next().cloned())
+                } else {
+                    (arg.clone(), Some(next[1..].to_owned()))
+                }
+            } else {
+                // Boolean option, like `set sort_re`
+                (arg.clone(), None)
+            };
+            if name == "folder" {
+                self.folder = value;
+            }
+        }
+    }
+
+    fn mailboxes(&mut self, file: &Path, line: usize, text: &str, args: &[String]) {
+        let mut label = None;
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-label" => label = iter.next().cloned(),
+                "-notify" | "-nonotify" | "-poll" | "-nopoll" => (),
+                flag if flag.starts_with('-') => {
+                    self.skip(file, line, text, &format!("Unknown flag {}", flag));
+                }
+                mailbox => self.add(file, line, text, mailbox, label.take()),
+            }
+        }
+    }
+
+    fn named_mailboxes(&mut self, file: &Path, line: usize, text: &str, args: &[String]) {
+        for pair in args.chunks(2) {
+            match pair {
+                [name, mailbox] => self.add(file, line, text, mailbox, Some(name.clone())),
+                _ => self.skip(file, line, text, "Label without a mailbox"),
+            }
+        }
+    }
+
+    fn unmailboxes(&mut self, file: &Path, line: usize, text: &str, args: &[String]) {
+        for arg in args {
+            if arg == "*" {
+                self.result.mailboxes.clear();
+            } else {
+                match self.resolve(arg) {
+                    Ok(path) => self.result.mailboxes.retain(|entry| entry.path != path),
+                    Err(reason) => self.skip(file, line, text, &format!("{}: {}", arg, reason)),
+                }
+            }
+        }
+    }
+
+    fn source(&mut self, file: &Path, line: usize, text: &str, args: &[String], depth: usize)
+        -> Result<(), Error>
+    {
+        for arg in args {
+            if arg.ends_with('|') {
+                self.skip(file, line, text, "Sourcing output of a command");
+                continue;
+            }
+            let path = match expand_home(arg) {
+                Ok(path) => path,
+                Err(reason) => {
+                    self.skip(file, line, text, &reason);
+                    continue;
+                }
+            };
+            let path = file.parent().map(|dir| dir.join(&path)).unwrap_or(path);
+            // Just for the report, so the error doesn't matter
+            let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if depth >= MAX_SOURCE_DEPTH || !self.visited.insert(canonical) {
+                self.skip(file, line, text, "Recursive source");
+                continue;
+            }
+            self.file(&path, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, file: &Path, depth: usize) -> Result<(), Error> {
+        debug!("Reading muttrc {}", file.display());
+        let content = fs::read(file)
+            .with_context(|_| format!("Failed to read {}", file.display()))?;
+        let content = String::from_utf8_lossy(&content);
+        for (line, text) in logical_lines(&content) {
+            let tokens = match tokenize(&text) {
+                Ok(tokens) => tokens,
+                Err(reason) => {
+                    // We care only if it could have been something about mailboxes
+                    let cmd = text.split_whitespace().next().unwrap_or("");
+                    if ["set", "mailboxes", "named-mailboxes", "unmailboxes", "source"]
+                        .contains(&cmd)
+                    {
+                        self.skip(file, line, &text, &reason);
+                    }
+                    continue;
+                }
+            };
+            let (cmd, args) = match tokens.split_first() {
+                Some((cmd, args)) => (cmd, args),
+                None => continue,
+            };
+            match cmd.as_str() {
+                "set" => self.set(args),
+                "mailboxes" => self.mailboxes(file, line, &text, args),
+                "named-mailboxes" => self.named_mailboxes(file, line, &text, args),
+                "unmailboxes" => self.unmailboxes(file, line, &text, args),
+                "source" => self.source(file, line, &text, args, depth)?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+crate fn parse(muttrc: &Path) -> Result<Import, Error> {
+    let mut parser = Parser {
+        folder: None,
+        visited: HashSet::new(),
+        result: Import::default(),
+    };
+    parser.visited.insert(fs::canonicalize(muttrc).unwrap_or_else(|_| muttrc.to_owned()));
+    parser.file(muttrc, 0)?;
+    Ok(parser.result)
+}
+
+#[derive(Serialize)]
+struct MetaOut {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    prio: u8,
+}
+
+#[derive(Serialize)]
+struct StorageOut {
+    meta: BTreeMap<String, MetaOut>,
+}
+
+#[derive(Serialize)]
+struct CfgOut {
+    storage: StorageOut,
+}
+
+/// Renders the storage.meta configuration.
+///
+/// Mutt lists the important mailboxes first, so they get the highest priority.
+crate fn to_config(import: &Import) -> Result<String, Error> {
+    let meta = import
+        .mailboxes
+        .iter()
+        .enumerate()
+        .map(|(idx, entry)| {
+            let prio = (u8::max_value() as usize).saturating_sub(idx) as u8;
+            let meta = MetaOut {
+                name: entry.name.clone(),
+                prio,
+            };
+            (entry.path.to_string_lossy().into_owned(), meta)
+        })
+        .collect();
+    let cfg = CfgOut {
+        storage: StorageOut { meta },
+    };
+    Ok(toml::to_string(&cfg)?)
+}
+
+crate fn import(muttrc: &Path, output: Option<&Path>) -> Result<(), Error> {
+    let import = parse(muttrc)?;
+    let cfg = to_config(&import)?;
+    match output {
+        Some(output) => {
+            let mut f = File::create(output)
+                .with_context(|_| format!("Failed to create {}", output.display()))?;
+            f.write_all(cfg.as_bytes())?;
+        }
+        None => print!("{}", cfg),
+    }
+
+    eprintln!("Imported {} mailboxes:", import.mailboxes.len());
+    for entry in &import.mailboxes {
+        match entry.name {
+            Some(ref name) => eprintln!("    {} ({})", entry.path.display(), name),
+            None => eprintln!("    {}", entry.path.display()),
+        }
+    }
+    if !import.skipped.is_empty() {
+        eprintln!("Skipped {} entries:", import.skipped.len());
+        for skipped in &import.skipped {
+            eprintln!("    {}:{}: {} ({})", skipped.file.display(), skipped.line, skipped.reason,
+                      skipped.text.trim());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, TempDir};
+
+    #[test]
+    fn tokens() {
+        let ok: &[(&str, &[&str])] = &[
+            ("", &[]),
+            ("   # just a comment", &[]),
+            ("mailboxes a b  c", &["mailboxes", "a", "b", "c"]),
+            ("mailboxes a # b", &["mailboxes", "a"]),
+            ("a#b", &["a#b"]),
+            ("'single quoted' \"double quoted\"", &["single quoted", "double quoted"]),
+            ("'no \\escape'", &["no \\escape"]),
+            ("\"an \\\"escaped\\\" quote\"", &["an \"escaped\" quote"]),
+            ("'#not a comment'", &["#not a comment"]),
+            ("half' quoted'\"and more\"", &["half quotedand more"]),
+            ("a\\ b c\\#d", &["a b", "c#d"]),
+            ("''", &[""]),
+            ("\t=INBOX\t+Sent ", &["=INBOX", "+Sent"]),
+        ];
+        for (line, expected) in ok {
+            let expected = expected.iter().map(|token| token.to_string()).collect::<Vec<_>>();
+            assert_eq!(Ok(expected), tokenize(line), "{}", line);
+        }
+        let broken: &[(&str, &str)] = &[
+            ("a\\", "Trailing backslash"),
+            ("'unterminated", "Unterminated single quote"),
+            ("\"unterminated", "Unterminated double quote"),
+            ("\"ends in escape\\", "Unterminated double quote"),
+            ("mailboxes `ls`", "Backtick command substitution"),
+            ("\"in `quotes`\"", "Backtick command substitution"),
+        ];
+        for (line, expected) in broken {
+            assert_eq!(Err(expected.to_string()), tokenize(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn continuation() {
+        let cases: &[(&str, &[(usize, &str)])] = &[
+            ("a\nb\n", &[(1, "a"), (2, "b")]),
+            ("a \\\n  b\nc", &[(1, "a   b"), (3, "c")]),
+            ("a\\\nb\\\nc\nd", &[(1, "abc"), (4, "d")]),
+            // An escaped backslash doesn't continue the line
+            ("a\\\\\nb", &[(1, "a\\\\"), (2, "b")]),
+            ("dangling\\", &[(1, "dangling")]),
+            ("\n\nx", &[(1, ""), (2, ""), (3, "x")]),
+        ];
+        for (content, expected) in cases {
+            let expected = expected
+                .iter()
+                .map(|(line, text)| (*line, text.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(expected, logical_lines(content), "{:?}", content);
+        }
+    }
+
+    fn entry(path: &str, name: Option<&str>) -> Entry {
+        Entry {
+            path: PathBuf::from(path),
+            name: name.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn muttrc() {
+        let dir = TempDir::new();
+        let main = dir.path().join("muttrc");
+        fixtures::write(&main, "\
+            set folder = /mail\n\
+            mailboxes +INBOX =Lists/rust \"/other/with space\"\n\
+            mailboxes -label Work -notify /work \\\n    -poll /work/archive\n\
+            named-mailboxes Friends =friends Family '=family'\n\
+            mailboxes imaps://example.com/INBOX -bogus /mail/INBOX\n\
+            unmailboxes =Lists/rust\n\
+            source extra\n\
+        ");
+        fixtures::write(dir.path().join("extra"), "\
+            set sort=threads folder=/archive\n\
+            mailboxes +2018\n\
+            source muttrc\n\
+        ");
+        let import = parse(&main).unwrap();
+        let expected = vec![
+            entry("/mail/INBOX", None),
+            entry("/other/with space", None),
+            entry("/work", Some("Work")),
+            entry("/work/archive", None),
+            entry("/mail/friends", Some("Friends")),
+            entry("/mail/family", Some("Family")),
+            entry("/archive/2018", None),
+        ];
+        assert_eq!(expected, import.mailboxes);
+        let skipped = import
+            .skipped
+            .iter()
+            .map(|skipped| (skipped.line, skipped.reason.as_str()))
+            .collect::<Vec<_>>();
+        let expected = vec![
+            // The continued line counts as two
+            (6, "imaps://example.com/INBOX: Remote mailbox"),
+            (6, "Unknown flag -bogus"),
+            // The line in the other file
+            (3, "Recursive source"),
+        ];
+        assert_eq!(expected, skipped);
+    }
+
+    #[test]
+    fn muttrc_broken() {
+        let dir = TempDir::new();
+        let main = dir.path().join("muttrc");
+        fixtures::write(&main, "\
+            mailboxes +INBOX\n\
+            mailboxes relative\n\
+            named-mailboxes Lonely\n\
+            mailboxes \"unterminated\n\
+            color index red default \"unterminated\n\
+        ");
+        let import = parse(&main).unwrap();
+        assert!(import.mailboxes.is_empty());
+        let skipped = import
+            .skipped
+            .iter()
+            .map(|skipped| (skipped.line, skipped.reason.as_str()))
+            .collect::<Vec<_>>();
+        let expected = vec![
+            (1, "+INBOX: Relative to folder, but folder is not set"),
+            (2, "relative: Relative path"),
+            (3, "Label without a mailbox"),
+            (4, "Unterminated double quote"),
+        ];
+        assert_eq!(expected, skipped);
+        assert!(parse(&dir.path().join("missing")).is_err());
+    }
+}
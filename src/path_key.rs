@@ -0,0 +1,226 @@
+//! Canonical identity of a path.
+//!
+//! Whenever a path identifies a mailbox (deduplication, lookups by path, anything persisted), it
+//! should go through `PathKey`, so all the places agree on what is the same mailbox.
+//!
+//! The rules are:
+//! * Relative paths are taken relative to the current directory.
+//! * All symlinks are resolved, including the final component. A mailbox reachable through a
+//!   symlink is the same mailbox as its target.
+//! * If the path doesn't exist (yet or any more), the nearest existing ancestor is canonicalized
+//!   and the rest is appended, with `.` and `..` resolved lexically. Symlinks in the rest (the
+//!   dangling ones or those reached by going back up with `..`) are still followed, so the key
+//!   doesn't change once the path appears.
+//! * If even that fails (eg. permissions), the path is only made absolute and lexically cleaned.
+
+use std::env;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// How many symlinks in the non-existing part are followed, to not loop forever.
+///
+/// The same as the limit of Linux for resolving a path.
+const MAX_LINKS: usize = 40;
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+crate struct PathKey(PathBuf);
+
+/// Appends the rest to the base, following the symlinks on the way.
+///
+/// The base is canonical. Returns the path to resolve anew if a symlink is met.
+fn push_resolving(base: &mut PathBuf, rest: &Path, links: usize) -> Option<PathBuf> {
+    let mut components = rest.components();
+    while let Some(component) = components.next() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                base.pop();
+            }
+            other => {
+                base.push(other);
+                match fs::read_link(&base) {
+                    Ok(target) if links > 0 => {
+                        base.pop();
+                        return Some(base.join(target).join(components.as_path()));
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    None
+}
+
+fn push_lexical(base: &mut PathBuf, rest: &Path) {
+    for component in rest.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                base.pop();
+            }
+            other => base.push(other),
+        }
+    }
+}
+
+fn resolve(path: &Path, links: usize) -> PathBuf {
+    if let Ok(canonical) = fs::canonicalize(path) {
+        return canonical;
+    }
+
+    let absolute = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        env::current_dir().unwrap_or_default().join(path)
+    };
+    for ancestor in absolute.ancestors().skip(1) {
+        if let Ok(mut canonical) = fs::canonicalize(ancestor) {
+            let rest = absolute
+                .strip_prefix(ancestor)
+                .expect("Ancestor is a prefix");
+            return match push_resolving(&mut canonical, rest, links) {
+                Some(target) => resolve(&target, links - 1),
+                None => canonical,
+            };
+        }
+    }
+    let mut cleaned = PathBuf::new();
+    push_lexical(&mut cleaned, &absolute);
+    cleaned
+}
+
+impl PathKey {
+    crate fn new<P: AsRef<Path>>(path: P) -> Self {
+        PathKey(resolve(path.as_ref(), MAX_LINKS))
+    }
+
+    crate fn as_path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for PathKey {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a Path> for PathKey {
+    fn from(path: &'a Path) -> Self {
+        PathKey::new(path)
+    }
+}
+
+impl From<PathKey> for PathBuf {
+    fn from(key: PathKey) -> Self {
+        key.0
+    }
+}
+
+impl Display for PathKey {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        self.0.display().fmt(fmt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use crate::fixtures::{self, TempDir};
+    use super::*;
+
+    /// The path, relative to the current directory.
+    fn relative(path: &Path) -> PathBuf {
+        let cwd = env::current_dir().unwrap();
+        let mut relative = PathBuf::new();
+        for _ in cwd.components().skip(1) {
+            relative.push("..");
+        }
+        relative.join(path.strip_prefix("/").unwrap())
+    }
+
+    /// All the paths have the same key and it's the canonical form of the first one.
+    fn assert_same(paths: &[PathBuf]) {
+        let expected = PathKey::new(&paths[0]);
+        for path in paths {
+            assert_eq!(expected, PathKey::new(path), "{}", path.display());
+            assert_eq!(expected, PathKey::new(relative(path)), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn existing() {
+        let dir = TempDir::new();
+        let real = dir.path().join("real");
+        fixtures::write(real.join("mbox"), "");
+        symlink(&real, dir.path().join("link")).unwrap();
+        symlink("real/mbox", dir.path().join("mbox-link")).unwrap();
+        symlink("link", dir.path().join("link-link")).unwrap();
+
+        let canonical = fs::canonicalize(real.join("mbox")).unwrap();
+        assert_eq!(canonical, PathKey::new(real.join("mbox")).as_path());
+        assert_same(&[
+            real.join("mbox"),
+            dir.path().join("link").join("mbox"),
+            dir.path().join("mbox-link"),
+            dir.path().join("link-link").join("mbox"),
+            dir.path().join("link").join(".").join("mbox"),
+            dir.path().join("real").join("..").join("link").join("mbox"),
+            dir.path().join("link//mbox/"),
+        ]);
+    }
+
+    /// Paths that don't exist yet have the key they'll have once they do.
+    #[test]
+    fn missing() {
+        let dir = TempDir::new();
+        let real = dir.path().join("real");
+        fs::create_dir(&real).unwrap();
+        symlink(&real, dir.path().join("link")).unwrap();
+        // Dangling, the target appears later on
+        symlink("real/new", dir.path().join("dangling")).unwrap();
+        symlink("real/deep/new", dir.path().join("deep-dangling")).unwrap();
+
+        let paths = |name: &str| vec![
+            real.join(name),
+            dir.path().join("link").join(name),
+            dir.path().join("link").join("missing").join("..").join(name),
+            dir.path().join("missing").join("..").join("real").join(name),
+            dir.path().join("missing").join("more").join("..").join("..").join("link").join(name),
+        ];
+        let mut new = paths("new");
+        new.push(dir.path().join("dangling"));
+        let mut deep = paths("deep/new");
+        deep.push(dir.path().join("deep-dangling"));
+        deep.push(dir.path().join("dangling").join("..").join("deep").join("new"));
+
+        let before_new = PathKey::new(&new[0]);
+        let before_deep = PathKey::new(&deep[0]);
+        assert_same(&new);
+        assert_same(&deep);
+        fixtures::write(real.join("new"), "");
+        fixtures::write(real.join("deep").join("new"), "");
+        assert_eq!(before_new, PathKey::new(&new[0]));
+        assert_eq!(before_deep, PathKey::new(&deep[0]));
+        assert_same(&new);
+        assert_same(&deep);
+    }
+
+    /// A dangling symlink pointing (through others) to itself doesn't make it loop.
+    #[test]
+    fn link_loop() {
+        let dir = TempDir::new();
+        symlink("b", dir.path().join("a")).unwrap();
+        symlink("a", dir.path().join("b")).unwrap();
+        symlink("self/x", dir.path().join("self")).unwrap();
+        for name in &["a", "b", "self"] {
+            let key = PathKey::new(dir.path().join(name));
+            assert!(key.as_path().starts_with(fs::canonicalize(dir.path()).unwrap()));
+            assert_eq!(key, PathKey::new(dir.path().join(name)));
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! A map keyed by paths, able to answer "which stored path is the nearest ancestor of this one".
+//!
+//! Paths are compared component by component, exactly as `Path::components` yields them. That
+//! means trailing slashes and inner `.` components don't matter, but `..` and symlinks are taken
+//! literally and comparison is case sensitive. Canonicalize the paths before inserting and looking
+//! them up if that's what is needed.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+struct Node<V> {
+    value: Option<(PathBuf, V)>,
+    children: HashMap<OsString, Node<V>>,
+}
+
+impl<V> Default for Node<V> {
+    fn default() -> Self {
+        Node {
+            value: None,
+            children: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+crate struct PathTrie<V> {
+    root: Node<V>,
+}
+
+impl<V> Default for PathTrie<V> {
+    fn default() -> Self {
+        PathTrie {
+            root: Node::default(),
+        }
+    }
+}
+
+impl<V> PathTrie<V> {
+    crate fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a value, returning the previous one stored under that very path.
+    crate fn insert<P: Into<PathBuf>>(&mut self, path: P, value: V) -> Option<V> {
+        let path = path.into();
+        let mut node = &mut self.root;
+        for component in path.components() {
+            node = node
+                .children
+                .entry(component.as_os_str().to_owned())
+                .or_insert_with(Node::default);
+        }
+        node.value.replace((path, value)).map(|(_, old)| old)
+    }
+
+    /// Walks the stored nodes along the path, calling `f` on each value found on the way.
+    fn walk<'a, F: FnMut(&'a Path, &'a V)>(&'a self, path: &Path, mut f: F) {
+        let mut node = &self.root;
+        for component in path.components() {
+            if let Some((ref path, ref value)) = node.value {
+                f(path, value);
+            }
+            match node.children.get(component.as_os_str()) {
+                Some(child) => node = child,
+                None => return,
+            }
+        }
+        if let Some((ref path, ref value)) = node.value {
+            f(path, value);
+        }
+    }
+
+    /// The value stored exactly under this path.
+    crate fn get<P: AsRef<Path>>(&self, path: P) -> Option<&V> {
+        let mut node = &self.root;
+        for component in path.as_ref().components() {
+            node = node.children.get(component.as_os_str())?;
+        }
+        node.value.as_ref().map(|(_, value)| value)
+    }
+
+    crate fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.get(path).is_some()
+    }
+
+    /// The nearest stored ancestor of the path, including the path itself.
+    crate fn longest_prefix<P: AsRef<Path>>(&self, path: P) -> Option<(&Path, &V)> {
+        let mut found = None;
+        self.walk(path.as_ref(), |path, value| found = Some((path, value)));
+        found
+    }
+
+    /// Is any stored path a proper ancestor of this one?
+    crate fn is_descendant_of_any<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref();
+        let mut found = false;
+        let depth = path.components().count();
+        self.walk(path, |prefix, _| found |= prefix.components().count() < depth);
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test::Bencher;
+
+    use super::*;
+    use crate::fixtures::Rng;
+
+    /// The obvious, slow implementation the trie must agree with.
+    #[derive(Default)]
+    struct Naive(Vec<(PathBuf, usize)>);
+
+    impl Naive {
+        fn insert(&mut self, path: PathBuf, value: usize) -> Option<usize> {
+            // Paths compare by their components, like in the trie
+            match self.0.iter_mut().find(|(stored, _)| *stored == path) {
+                Some(entry) => Some(std::mem::replace(entry, (path, value)).1),
+                None => {
+                    self.0.push((path, value));
+                    None
+                }
+            }
+        }
+
+        fn get(&self, path: &Path) -> Option<&usize> {
+            self.0.iter().find(|(stored, _)| stored == path).map(|(_, value)| value)
+        }
+
+        fn longest_prefix(&self, path: &Path) -> Option<(&Path, &usize)> {
+            self.0
+                .iter()
+                .filter(|(stored, _)| path.starts_with(stored))
+                .max_by_key(|(stored, _)| stored.components().count())
+                .map(|(stored, value)| (stored.as_path(), value))
+        }
+
+        fn is_descendant_of_any(&self, path: &Path) -> bool {
+            self.0.iter().any(|(stored, _)| path.starts_with(stored) && stored != path)
+        }
+    }
+
+    /// A path from a small set of components, so they often share prefixes.
+    fn random_path(rng: &mut Rng, max_depth: usize) -> PathBuf {
+        const COMPONENTS: &[&str] = &["a", "b", "cur", "INBOX", ".", "a b", "ä"];
+        let mut path = if rng.chance(0.8) { PathBuf::from("/") } else { PathBuf::new() };
+        for _ in 0..rng.below(max_depth + 1) {
+            path.push(COMPONENTS[rng.below(COMPONENTS.len())]);
+        }
+        if rng.chance(0.1) {
+            // A trailing slash
+            path.push("");
+        }
+        path
+    }
+
+    #[test]
+    fn basic() {
+        let mut trie = PathTrie::new();
+        assert!(trie.insert("/mail", 1).is_none());
+        assert!(trie.insert("/mail/inner/box", 2).is_none());
+        assert_eq!(Some(1), trie.insert("/mail/", 3));
+        assert_eq!(Some(&3), trie.get("/mail"));
+        assert!(!trie.contains("/mail/inner"));
+        assert_eq!(Some((Path::new("/mail"), &3)), trie.longest_prefix("/mail/inner"));
+        assert_eq!(Some((Path::new("/mail/inner/box"), &2)),
+                   trie.longest_prefix("/mail/inner/box/cur"));
+        assert!(trie.longest_prefix("/other").is_none());
+        assert!(trie.is_descendant_of_any("/mail/inner/box"));
+        assert!(!trie.is_descendant_of_any("/mail"));
+    }
+
+    #[test]
+    fn same_as_naive() {
+        for seed in 1..=50 {
+            let mut rng = Rng::new(seed);
+            let mut trie = PathTrie::new();
+            let mut naive = Naive::default();
+            for value in 0..rng.below(40) {
+                let path = random_path(&mut rng, 4);
+                let expected = naive.insert(path.clone(), value);
+                assert_eq!(expected, trie.insert(path.clone(), value), "{:?}", path);
+            }
+            for _ in 0..200 {
+                let path = random_path(&mut rng, 6);
+                let ctx = || format!("seed {}, path {:?}", seed, path);
+                assert_eq!(naive.get(&path), trie.get(&path), "{}", ctx());
+                assert_eq!(naive.get(&path).is_some(), trie.contains(&path), "{}", ctx());
+                let expected = naive.longest_prefix(&path).map(|(_, value)| value);
+                let found = trie.longest_prefix(&path);
+                assert_eq!(expected, found.map(|(_, value)| value), "{}", ctx());
+                if let Some((prefix, _)) = found {
+                    assert!(path.starts_with(prefix), "{}", ctx());
+                }
+                let expected = naive.is_descendant_of_any(&path);
+                assert_eq!(expected, trie.is_descendant_of_any(&path), "{}", ctx());
+            }
+        }
+    }
+
+    /// Many mailboxes deep in the tree, queried for the nearest ancestor.
+    fn deep_tree() -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let mut rng = Rng::new(42);
+        let stored = (0..2000).map(|_| random_path(&mut rng, 12)).collect();
+        let queries = (0..200).map(|_| random_path(&mut rng, 14)).collect();
+        (stored, queries)
+    }
+
+    #[bench]
+    fn longest_prefix_trie(b: &mut Bencher) {
+        let (stored, queries) = deep_tree();
+        let mut trie = PathTrie::new();
+        for (value, path) in stored.into_iter().enumerate() {
+            trie.insert(path, value);
+        }
+        b.iter(|| queries.iter().filter(|query| trie.longest_prefix(query).is_some()).count());
+    }
+
+    #[bench]
+    fn longest_prefix_naive(b: &mut Bencher) {
+        let (stored, queries) = deep_tree();
+        let mut naive = Naive::default();
+        for (value, path) in stored.into_iter().enumerate() {
+            naive.insert(path, value);
+        }
+        b.iter(|| queries.iter().filter(|query| naive.longest_prefix(query).is_some()).count());
+    }
+}
@@ -0,0 +1,341 @@
+//! Hiding mailbox paths and names in the logs, so they can be shared.
+//!
+//! This happens in the logger, so no call site needs to care. Every occurrence of a known path or
+//! name is replaced by a token like `<path:1a2b3c4d>`. The tokens come from a hash with a random
+//! key, so the same path gets the same token on every line of one run (even when it is written
+//! Debug-escaped), but the tokens can't be matched between runs or guessed from a list of likely
+//! folder names.
+//!
+//! The known paths are the search roots and the registered mailboxes, plus the home directory.
+//! Anything continuing such a path (eg. a file inside a mailbox) is part of the token. Names are
+//! replaced only as whole words.
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fmt::{Arguments, Debug, Formatter, Result as FmtResult};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{Result as IoResult, Write};
+use std::path::Path;
+
+use env_logger::Builder;
+use once_cell::sync_lazy;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+
+use crate::config::Cfg;
+
+/// Characters that end a path continuing a known one.
+///
+/// Not a comma, maildir messages have them in their names (`:2,S`). A comma at the end is taken
+/// for punctuation, though.
+const PATH_END: &str = "\"'()[]{}";
+
+struct Redactor {
+    key: RandomState,
+    /// Sorted longest first, so the most specific one wins. Each path is there as written in the
+    /// logs and as it is hashed into the token (these differ for the Debug-escaped form).
+    paths: Vec<(String, String)>,
+    names: Vec<String>,
+}
+
+impl Redactor {
+    fn token(&self, kind: &str, value: &str) -> String {
+        let mut hasher = self.key.build_hasher();
+        value.hash(&mut hasher);
+        format!("<{}:{:08x}>", kind, hasher.finish() as u32)
+    }
+
+    fn new() -> Self {
+        Redactor {
+            key: RandomState::new(),
+            paths: Vec::new(),
+            names: Vec::new(),
+        }
+    }
+
+    fn add(list: &mut Vec<String>, value: String) {
+        if !value.is_empty() && !list.contains(&value) {
+            list.push(value);
+            list.sort_by(|a, b| b.len().cmp(&a.len()));
+        }
+    }
+
+    fn add_path(&mut self, path: &Path) {
+        let display = path.display().to_string();
+        // Debug formatting escapes some characters, so it needs to be known in that form too
+        let debug = format!("{:?}", path).trim_matches('"').to_owned();
+        for written in vec![debug, display.clone()] {
+            if !written.is_empty() && !self.paths.iter().any(|(known, _)| *known == written) {
+                self.paths.push((written, display.clone()));
+            }
+        }
+        self.paths.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+    }
+
+    /// How long a path starting at the beginning of `rest` is, if it starts with a known one.
+    ///
+    /// Also returns what to hash into the token, the known path in its canonical form followed by
+    /// the continuation.
+    fn path_len(&self, rest: &str) -> Option<(usize, String)> {
+        let (known, hashed) = self.paths.iter().find(|(path, _)| rest.starts_with(path.as_str()))?;
+        let end = rest[known.len()..]
+            .find(|c: char| c.is_whitespace() || PATH_END.contains(c))
+            .map_or(rest.len(), |end| known.len() + end);
+        // Colons, dots and commas right after a path are much more often punctuation than part
+        // of it
+        let trimmed = rest[..end].trim_end_matches(|c| c == ':' || c == '.' || c == ',').len();
+        let len = trimmed.max(known.len());
+        Some((len, format!("{}{}", hashed, &rest[known.len()..len])))
+    }
+
+    /// How long a name at the beginning of `rest` is, if it's a known one as a whole word.
+    fn name_len(&self, rest: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .find(|name| {
+                rest.starts_with(name.as_str())
+                    && !rest[name.len()..].chars().next().map_or(false, char::is_alphanumeric)
+            })
+            .map(|name| name.len())
+    }
+
+    fn redact(&self, msg: &str) -> String {
+        let mut out = String::with_capacity(msg.len());
+        let mut pos = 0;
+        let mut word_start = true;
+        while let Some(c) = msg[pos..].chars().next() {
+            let rest = &msg[pos..];
+            let found = match self.path_len(rest) {
+                Some((len, hashed)) => Some(("path", len, hashed)),
+                None if word_start => {
+                    self.name_len(rest).map(|len| ("name", len, rest[..len].to_owned()))
+                }
+                None => None,
+            };
+            match found {
+                Some((kind, len, hashed)) => {
+                    out.push_str(&self.token(kind, &hashed));
+                    word_start = false;
+                    pos += len;
+                }
+                None => {
+                    out.push(c);
+                    word_start = !c.is_alphanumeric();
+                    pos += c.len_utf8();
+                }
+            }
+        }
+        out
+    }
+}
+
+static REDACTOR: Lazy<RwLock<Option<Redactor>>> = sync_lazy!(RwLock::default());
+
+fn extend(redactor: &mut Option<Redactor>, cfg: &Cfg) {
+    if !cfg.log.redact {
+        return;
+    }
+    let redactor = redactor.get_or_insert_with(Redactor::new);
+    if let Some(home) = env::var_os("HOME") {
+        redactor.add_path(Path::new(&home));
+    }
+    for root in &cfg.storage.search {
+        redactor.add_path(&root.path);
+    }
+}
+
+/// Turns the redaction on if the configuration asks for it, making its search roots known.
+///
+/// Nothing known already is forgotten. A reload may still fail after loading the configuration
+/// and the old mailboxes stay in use, so their names keep showing up in the logs. Turning it off
+/// waits for `commit`.
+crate fn configure(cfg: &Cfg) {
+    extend(&mut REDACTOR.write(), cfg);
+}
+
+/// Turns the redaction off if the configuration, now fully in use, doesn't ask for it.
+crate fn commit(cfg: &Cfg) {
+    if !cfg.log.redact {
+        *REDACTOR.write() = None;
+    }
+}
+
+/// Makes the mailbox known, so it gets redacted from now on.
+crate fn mailbox(name: &str, path: &Path) {
+    if let Some(ref mut redactor) = *REDACTOR.write() {
+        redactor.add_path(path);
+        Redactor::add(&mut redactor.names, name.to_owned());
+    }
+}
+
+fn write_redacted(out: &mut dyn Write, args: &Arguments) -> IoResult<()> {
+    // Formatted before locking, Content locks too
+    let msg = args.to_string();
+    match *REDACTOR.read() {
+        Some(ref redactor) => writeln!(out, "{}", redactor.redact(&msg)),
+        None => writeln!(out, "{}", msg),
+    }
+}
+
+/// Sets up the logger (configured from the environment, as usual) to redact what it writes.
+crate fn init_logger() {
+    Builder::from_default_env()
+        .format(|buf, record| {
+            let level = record.level();
+            let level_style = buf.default_level_style(level);
+            write!(buf, "{:>5} {}: ", level_style.value(level), buf.timestamp())?;
+            if let Some(module_path) = record.module_path() {
+                write!(buf, "{}: ", module_path)?;
+            }
+            write_redacted(buf, record.args())
+        })
+        .init();
+}
+
+/// A piece of message content (a subject, a sender), written only as its length when redacting.
+crate struct Content<'a>(crate &'a Option<String>);
+
+impl<'a> Debug for Content<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match (REDACTOR.read().is_some(), self.0) {
+            (true, Some(content)) => write!(fmt, "<{} chars>", content.chars().count()),
+            (_, content) => content.fmt(fmt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::config;
+    use super::*;
+
+    const PATHS: &[&str] = &["/home/user/Mail", "/home/user/Mail/work", "/srv/we\"ird\nmail"];
+    const NAMES: &[&str] = &["work", "Práce", "inbox"];
+
+    fn redactor() -> Redactor {
+        let mut redactor = Redactor::new();
+        for path in PATHS {
+            redactor.add_path(Path::new(path));
+        }
+        for name in NAMES {
+            Redactor::add(&mut redactor.names, name.to_string());
+        }
+        redactor
+    }
+
+    /// Splits the output into the tokens and the rest.
+    fn tokens(out: &str) -> Vec<&str> {
+        out.match_indices('<')
+            .map(|(start, _)| &out[start..start + out[start..].find('>').unwrap() + 1])
+            .collect()
+    }
+
+    #[test]
+    fn same_token() {
+        let redactor = redactor();
+        let path = Path::new("/home/user/Mail/work/cur");
+        let msg = format!("{} then {} and {:?}, {}", path.display(), path.display(), path,
+                          "/home/user/Mail/other");
+        let out = redactor.redact(&msg);
+        let tokens = tokens(&out);
+        assert_eq!(4, tokens.len(), "{}", out);
+        assert_eq!(tokens[0], tokens[1]);
+        assert_eq!(tokens[0], tokens[2]);
+        assert_ne!(tokens[0], tokens[3]);
+        assert_eq!(out, redactor.redact(&msg));
+
+        let words = redactor.redact("work, work; workload work");
+        let tokens = self::tokens(&words);
+        assert_eq!(3, tokens.len(), "{}", words);
+        assert!(tokens.iter().all(|token| *token == tokens[0]));
+        assert!(tokens[0].starts_with("<name:"));
+        assert_ne!(tokens[0], redactor.redact("inbox"));
+    }
+
+    /// Another run (with another key) gives different tokens.
+    #[test]
+    fn tokens_per_run() {
+        let (a, b) = (redactor(), redactor());
+        assert_ne!(a.redact("/home/user/Mail inbox"), b.redact("/home/user/Mail inbox"));
+    }
+
+    #[test]
+    fn redact() {
+        let redactor = redactor();
+        let token = |kind: &str, value: &str| redactor.token(kind, value);
+        let mail = "/home/user/Mail";
+        let work = "/home/user/Mail/work";
+        let cases = vec![
+            (format!("Scanning {}:", mail), format!("Scanning {}:", token("path", mail))),
+            (format!("In {}.", work), format!("In {}.", token("path", work))),
+            (format!("In {}/cur.", work),
+             format!("In {}.", token("path", &format!("{}/cur", work)))),
+            (format!("File {}/.x.y:2,S: gone", work),
+             format!("File {}: gone", token("path", &format!("{}/.x.y:2,S", work)))),
+            (format!("Paths [{}, \"{}\"]", mail, work),
+             format!("Paths [{}, \"{}\"]", token("path", mail), token("path", work))),
+            (format!("{:?}", PathBuf::from("/srv/we\"ird\nmail/new")),
+             format!("\"{}\"", token("path", "/srv/we\"ird\nmail/new"))),
+            ("work".to_owned(), token("name", "work")),
+            ("network works (work)".to_owned(),
+             format!("network works ({})", token("name", "work"))),
+            ("Práce, práce".to_owned(), format!("{}, práce", token("name", "Práce"))),
+            ("inboxes inbox2 inbox".to_owned(),
+             format!("inboxes inbox2 {}", token("name", "inbox"))),
+            ("/home/user/Maildir".to_owned(), token("path", "/home/user/Maildir")),
+            ("/home/user/Mai".to_owned(), "/home/user/Mai".to_owned()),
+            ("nothing to see".to_owned(), "nothing to see".to_owned()),
+        ];
+        for (msg, expected) in cases {
+            assert_eq!(expected, redactor.redact(&msg), "{:?}", msg);
+        }
+    }
+
+    /// Whatever the message looks like, none of the known paths or names survives whole.
+    #[test]
+    fn no_leaks() {
+        let redactor = redactor();
+        let weird = Path::new(PATHS[2]);
+        let msgs = vec![
+            format!("{}{}{}", PATHS[0], PATHS[1], PATHS[0]),
+            format!("{:?} {} {:?}", weird, weird.display(), Path::new(PATHS[1])),
+            format!("x{}:{}:: {}...", NAMES[0], PATHS[1], NAMES[1]),
+            format!("{}/{}/{}", NAMES[2], NAMES[0], NAMES[1]),
+            format!("'{}' \"{}\" ({}) [{}]", PATHS[0], NAMES[0], PATHS[1], NAMES[1]),
+        ];
+        for msg in msgs {
+            let out = redactor.redact(&msg);
+            for path in PATHS {
+                assert!(!out.contains(path), "{:?} in {:?}", path, out);
+                let debug = format!("{:?}", Path::new(path));
+                assert!(!out.contains(debug.trim_matches('"')), "{:?} in {:?}", debug, out);
+            }
+            // Only as whole words, the first message has a name inside a word
+            for word in out.split(|c: char| !c.is_alphanumeric()) {
+                assert!(!NAMES.contains(&word), "{:?} in {:?}", word, out);
+            }
+        }
+    }
+
+    /// A configuration that doesn't get to be used doesn't make the known mailboxes forgotten.
+    #[test]
+    fn reconfigure() {
+        let cfg = |redact| {
+            config::parse(&format!("[log]\nredact = {}\n[storage]\nsearch = [\"/srv/mail\"]\n",
+                                   redact))
+        };
+        let mut redactor = None;
+        extend(&mut redactor, &cfg(false));
+        assert!(redactor.is_none());
+        extend(&mut redactor, &cfg(true));
+        Redactor::add(&mut redactor.as_mut().unwrap().names, "secret".to_owned());
+        let before = redactor.as_ref().unwrap().redact("/srv/mail/x secret");
+        assert!(!before.contains("srv") && !before.contains("secret"), "{}", before);
+        // Another reload, this one fails later on
+        extend(&mut redactor, &cfg(true));
+        extend(&mut redactor, &cfg(false));
+        assert_eq!(before, redactor.as_ref().unwrap().redact("/srv/mail/x secret"));
+    }
+}
@@ -0,0 +1,131 @@
+//! Picking up changes of the configuration without a restart.
+//!
+//! On SIGHUP, the configuration is loaded again, the scripts are run again and the search roots
+//! are scanned again. The mailboxes found again keep their caches.
+//!
+//! The same thread runs the post-scan callbacks of the scripts, once the mailboxes are read after
+//! the start and after each reload.
+
+use std::path::PathBuf;
+use std::thread;
+
+use failure::{Error, ResultExt};
+use log::{error, info, warn};
+use signal_hook::SIGHUP;
+use signal_hook::iterator::Signals;
+
+use crate::config::{self, Cfg, CmdLine};
+use crate::error::Kind;
+use crate::mailbox::{self, Handle, Poll, Watch};
+use crate::redact;
+
+/// Whatever notices changes of the registered mailboxes.
+///
+/// These work with the mailboxes registered when they are started, so they are replaced after
+/// each reload.
+struct Observers {
+    _watch: Option<Watch>,
+    _poll: Poll,
+}
+
+impl Observers {
+    fn start(cfg: &Cfg, pool: &Handle) -> Result<Self, Error> {
+        let watch = if cfg.watch {
+            Some(mailbox::watch(cfg, pool.clone())?)
+        } else {
+            None
+        };
+        Ok(Observers {
+            _watch: watch,
+            _poll: mailbox::poll(cfg, pool.clone())?,
+        })
+    }
+}
+
+/// The settings used only on startup, which a reload can't change.
+struct Fixed {
+    socket: PathBuf,
+    workers: usize,
+    normalize_names: bool,
+}
+
+impl Fixed {
+    fn new(cfg: &Cfg) -> Self {
+        Fixed {
+            socket: cfg.socket.clone(),
+            workers: cfg.workers,
+            normalize_names: cfg.normalize_names,
+        }
+    }
+
+    fn warn_changed(&self, cfg: &Cfg) {
+        if self.socket != cfg.socket || self.workers != cfg.workers
+            || self.normalize_names != cfg.normalize_names
+        {
+            warn!("Changes of socket, workers and normalize_names take effect only after restart");
+        }
+    }
+}
+
+fn log_error(msg: &str, e: &Error) {
+    error!("{}: {}", msg, e);
+    for cause in e.iter_causes() {
+        error!("Because: {}", cause);
+    }
+}
+
+fn restart(cfg: &Cfg, pool: &Handle, observers: &mut Observers) {
+    match Observers::start(cfg, pool) {
+        Ok(new) => *observers = new,
+        Err(e) => log_error("Failed to watch the reloaded mailboxes", &e),
+    }
+}
+
+fn post_scan(cfg: &Cfg, pool: &Handle, observers: &mut Observers) {
+    match mailbox::post_scan(cfg, pool) {
+        // The observers know the replaced mailboxes
+        Ok(true) => restart(cfg, pool, observers),
+        Ok(false) => (),
+        Err(e) => log_error("Post-scan callbacks failed", &e),
+    }
+}
+
+fn reload(cmd_line: &CmdLine, pool: &Handle) -> Result<Cfg, Error> {
+    let cfg = config::load(cmd_line).context(Kind::Config)?;
+    mailbox::rescan_roots(&cfg, pool)?;
+    redact::commit(&cfg);
+    Ok(cfg)
+}
+
+/// Starts watching the mailboxes and reloading the configuration on SIGHUP.
+///
+/// A reload that fails (eg. on a broken config file or script) is logged and everything keeps
+/// running with the old configuration.
+crate fn start(cmd_line: &CmdLine, cfg: &Cfg, pool: Handle) -> Result<(), Error> {
+    let signals = Signals::new(&[SIGHUP])?;
+    let mut observers = Observers::start(cfg, &pool)?;
+    let fixed = Fixed::new(cfg);
+    let cmd_line = cmd_line.clone();
+    let cfg = cfg.clone();
+    thread::Builder::new()
+        .name("reload".to_owned())
+        .spawn(move || {
+            // A SIGHUP coming in the meantime waits for this
+            post_scan(&cfg, &pool, &mut observers);
+            for _ in signals.forever() {
+                info!("Reloading the configuration");
+                let cfg = match reload(&cmd_line, &pool) {
+                    Ok(cfg) => cfg,
+                    Err(e) => {
+                        log_error("Failed to reload, keeping the old configuration", &e);
+                        continue;
+                    }
+                };
+                fixed.warn_changed(&cfg);
+                restart(&cfg, &pool, &mut observers);
+                info!("Reloaded the configuration");
+                post_scan(&cfg, &pool, &mut observers);
+            }
+        })?;
+    Ok(())
+}
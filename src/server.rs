@@ -0,0 +1,538 @@
+//! The unix socket clients talk to.
+//!
+//! The protocol is line based. Each command is a single line, the answer is zero or more lines of
+//! data followed by either `OK` or `ERR <message>`. After `SUBSCRIBE`, notifications arrive as
+//! `EVENT <json>` lines at any time between the answers.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Error as IoError, ErrorKind, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use failure::{bail, format_err, Error, ResultExt};
+use log::{debug, error, info, trace, warn};
+use parking_lot::Mutex;
+
+use crate::config::Cfg;
+use crate::error::Kind;
+use crate::mailbox::{self, Handle};
+use crate::version;
+
+/// Creates the listening socket.
+///
+/// A leftover socket of a previous instance is removed, but only if there's nobody listening on
+/// it any more.
+crate fn bind(cfg: &Cfg) -> Result<UnixListener, Error> {
+    let path = &cfg.socket;
+    if fs::symlink_metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            let err = format_err!("Another instance is listening on {}", path.display());
+            return Err(err.context(Kind::Bind).into());
+        }
+        debug!("Removing stale socket {}", path.display());
+        fs::remove_file(path)
+            .with_context(|_| format!("Failed to remove stale socket {}", path.display()))
+            .context(Kind::Bind)?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|_| format!("Failed to bind {}", path.display()))
+        .context(Kind::Bind)?;
+    info!("Listening on {}", path.display());
+    Ok(listener)
+}
+
+/// Removes the socket, so nobody tries to connect to an instance that is gone.
+crate fn unbind(cfg: &Cfg) {
+    if let Err(e) = fs::remove_file(&cfg.socket) {
+        warn!("Failed to remove socket {}: {}", cfg.socket.display(), e);
+    }
+}
+
+fn list(_: &mut Client, out: &mut Out, _: &str) -> Result<(), IoError> {
+    let report = mailbox::LAST_SCAN.lock().clone();
+    for mbox in mailbox::all() {
+        let shortcut = mbox
+            .shortcut()
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "-".to_owned());
+        // The mailbox may be stale, its root couldn't be scanned properly
+        let root = if report.in_failed_root(mbox.path()) { "failed-root" } else { "-" };
+        writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}", mbox.name(), mbox.path().display(), mbox.kind(),
+                 mbox.prio(), shortcut, root)?;
+    }
+    writeln!(out, "OK")
+}
+
+fn skipped(_: &mut Client, out: &mut Out, _: &str) -> Result<(), IoError> {
+    let report = mailbox::LAST_SCAN.lock().clone();
+    for root in &report.roots {
+        for skipped in &root.skipped {
+            let when = skipped
+                .when
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            writeln!(out, "{}\t{}\t{}", skipped.path.display(), skipped.reason, when)?;
+        }
+        if root.skipped_overflow > 0 {
+            writeln!(out, "{}\t{} more not listed", root.path.display(), root.skipped_overflow)?;
+        }
+    }
+    writeln!(out, "OK")
+}
+
+fn counts(client: &mut Client, out: &mut Out, name: &str) -> Result<(), IoError> {
+    match mailbox::lookup(name, client.normalize_names) {
+        Some(mbox) => {
+            let (messages, unseen) = mbox.counts();
+            let unseen = unseen
+                .map(|u| u.to_string())
+                .unwrap_or_else(|| "-".to_owned());
+            writeln!(out, "{}\t{}\t{}", mbox.name(), messages, unseen)?;
+            writeln!(out, "OK")
+        }
+        None => writeln!(out, "ERR No mailbox {}", name),
+    }
+}
+
+fn lua_stats(_: &mut Client, out: &mut Out, _: &str) -> Result<(), IoError> {
+    for stats in mailbox::lua_stats() {
+        let millis = stats.total.as_secs() * 1000 + u64::from(stats.total.subsec_millis());
+        writeln!(out, "{}\t{}\t{}", stats.description, stats.calls, millis)?;
+    }
+    writeln!(out, "OK")
+}
+
+/// Splits the optional `FULL` off the mailbox name.
+fn rescan_args(args: &str) -> (bool, &str) {
+    match args.find(char::is_whitespace) {
+        Some(pos) if args[..pos].eq_ignore_ascii_case("FULL") => (true, args[pos..].trim()),
+        _ => (false, args),
+    }
+}
+
+fn rescan(client: &mut Client, out: &mut Out, args: &str) -> Result<(), IoError> {
+    let (full, name) = rescan_args(args);
+    match mailbox::lookup(name, client.normalize_names) {
+        Some(mbox) => {
+            if full {
+                client.pool.full_rescan(mbox);
+            } else {
+                client.pool.rescan(mbox);
+            }
+            writeln!(out, "OK")
+        }
+        None => writeln!(out, "ERR No mailbox {}", name),
+    }
+}
+
+type Out = BufWriter<UnixStream>;
+type Output = Arc<Mutex<Out>>;
+
+/// Writes the notifications to a subscribed client.
+fn forward(events: Receiver<String>, out: &Output) -> Result<(), IoError> {
+    for event in events {
+        let mut out = out.lock();
+        writeln!(out, "EVENT {}", event)?;
+        out.flush()?;
+    }
+    // The other side went away, which happens only if we didn't keep up
+    let mut out = out.lock();
+    writeln!(out, "ERR Unsubscribed for not keeping up with the events")?;
+    out.flush()?;
+    out.get_ref().shutdown(Shutdown::Both)
+}
+
+fn subscribe(client: &mut Client, out: &mut Out, _: &str) -> Result<(), IoError> {
+    if client.subscribed {
+        return writeln!(out, "ERR Already subscribed");
+    }
+    client.subscribed = true;
+    let events = mailbox::subscribe();
+    let output = Arc::clone(&client.output);
+    thread::Builder::new()
+        .name("subscriber".to_owned())
+        .spawn(move || {
+            if let Err(e) = forward(events, &output) {
+                debug!("Subscriber gone: {}", e);
+            }
+        })?;
+    // The forwarding thread waits for the lock we hold, so this comes before any event.
+    writeln!(out, "OK")
+}
+
+fn version(_: &mut Client, out: &mut Out, _: &str) -> Result<(), IoError> {
+    writeln!(out, "{}", version::to_json())?;
+    writeln!(out, "OK")
+}
+
+struct Client {
+    output: Output,
+    subscribed: bool,
+    normalize_names: bool,
+    pool: Handle,
+}
+
+fn help(_: &mut Client, out: &mut Out, name: &str) -> Result<(), IoError> {
+    let describe = |out: &mut Out, handler: &Handler| {
+        writeln!(out, "{}\t{}", handler.syntax, handler.description)
+    };
+    if name.is_empty() {
+        for handler in COMMANDS {
+            describe(out, handler)?;
+        }
+    } else {
+        match find(&name.to_ascii_uppercase()) {
+            Some(handler) => describe(out, handler)?,
+            None => return unknown(out, name),
+        }
+    }
+    writeln!(out, "OK")
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Args {
+    None,
+    Optional,
+    Required,
+}
+
+/// A command the clients can send.
+struct Handler {
+    name: &'static str,
+    syntax: &'static str,
+    description: &'static str,
+    args: Args,
+    /// Gets the rest of the line after the command name, without the surrounding whitespace.
+    run: fn(&mut Client, &mut Out, &str) -> Result<(), IoError>,
+}
+
+const COMMANDS: &[Handler] = &[
+    Handler {
+        name: "COUNTS",
+        syntax: "COUNTS <mailbox>",
+        description: "Message and unseen counts of a mailbox",
+        args: Args::Required,
+        run: counts,
+    },
+    Handler {
+        name: "HELP",
+        syntax: "HELP [<command>]",
+        description: "Describe all the commands, or just one",
+        args: Args::Optional,
+        run: help,
+    },
+    Handler {
+        name: "LIST",
+        syntax: "LIST",
+        description: "All mailboxes with their path, kind, prio, shortcut and if their root failed",
+        args: Args::None,
+        run: list,
+    },
+    Handler {
+        name: "LUA-STATS",
+        syntax: "LUA-STATS",
+        description: "Calls of each lua callback and their total milliseconds, since the last scan",
+        args: Args::None,
+        run: lua_stats,
+    },
+    Handler {
+        name: "RESCAN",
+        syntax: "RESCAN [FULL] <mailbox>",
+        description: "Queue a rescan of the mailbox, a FULL one ignores the caches",
+        args: Args::Required,
+        run: rescan,
+    },
+    Handler {
+        name: "SKIPPED",
+        syntax: "SKIPPED",
+        description: "Mailbox candidates the last scan didn't register, and why",
+        args: Args::None,
+        run: skipped,
+    },
+    Handler {
+        name: "SUBSCRIBE",
+        syntax: "SUBSCRIBE",
+        description: "Receive notifications as EVENT lines from now on",
+        args: Args::None,
+        run: subscribe,
+    },
+    Handler {
+        name: "VERSION",
+        syntax: "VERSION",
+        description: "How the running instance was built, as JSON",
+        args: Args::None,
+        run: version,
+    },
+];
+
+fn find(name: &str) -> Option<&'static Handler> {
+    COMMANDS.iter().find(|handler| handler.name == name)
+}
+
+/// The Levenshtein distance of the two strings.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitute.min(prev[j + 1] + 1).min(current[j] + 1));
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
+
+fn unknown(out: &mut Out, name: &str) -> Result<(), IoError> {
+    let name = name.to_ascii_uppercase();
+    // Only suggest something reasonably close, not just the least bad one
+    let suggestion = COMMANDS
+        .iter()
+        .map(|handler| (distance(&name, handler.name), handler.name))
+        .filter(|(dist, _)| *dist <= 2)
+        .min();
+    match suggestion {
+        Some((_, suggestion)) => {
+            writeln!(out, "ERR Unknown command {}, did you mean {}?", name, suggestion)
+        }
+        None => writeln!(out, "ERR Unknown command {} (see HELP)", name),
+    }
+}
+
+fn command(line: &str, client: &mut Client) -> Result<(), IoError> {
+    let (name, args) = match line.find(char::is_whitespace) {
+        Some(pos) => (&line[..pos], line[pos..].trim()),
+        None => (line, ""),
+    };
+    // Empty lines are simply ignored
+    if name.is_empty() {
+        return Ok(());
+    }
+    let output = Arc::clone(&client.output);
+    let mut out = output.lock();
+    match find(&name.to_ascii_uppercase()) {
+        None => unknown(&mut out, name),
+        Some(handler) if handler.args == Args::None && !args.is_empty() => {
+            writeln!(out, "ERR {} takes no arguments", handler.name)
+        }
+        Some(handler) if handler.args == Args::Required && args.is_empty() => {
+            writeln!(out, "ERR Usage: {}", handler.syntax)
+        }
+        Some(handler) => (handler.run)(client, &mut out, args),
+    }?;
+    out.flush()
+}
+
+fn client(stream: UnixStream, normalize_names: bool, pool: Handle) -> Result<(), Error> {
+    let mut client = Client {
+        output: Arc::new(Mutex::new(BufWriter::new(stream.try_clone()?))),
+        subscribed: false,
+        normalize_names,
+        pool,
+    };
+    for line in BufReader::new(stream).split(b'\n') {
+        let line = line?;
+        match String::from_utf8(line) {
+            Ok(line) => {
+                trace!("Command {:?}", line);
+                command(line.trim(), &mut client)?;
+            }
+            Err(_) => {
+                let mut out = client.output.lock();
+                writeln!(out, "ERR Command is not valid UTF-8")?;
+                out.flush()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Accepts the clients and serves each of them in its own thread.
+///
+/// Returns only if accepting fails. The rescans the clients ask for go to the pool.
+crate fn run(listener: UnixListener, normalize_names: bool, pool: Handle) -> Result<(), Error> {
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            // Interrupted by a signal and similar, just try again
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => bail!("Failed to accept a client: {}", e),
+        };
+        debug!("New client");
+        let pool = pool.clone();
+        thread::Builder::new()
+            .name("client".to_owned())
+            .spawn(move || {
+                match client(stream, normalize_names, pool) {
+                    Ok(()) => debug!("Client disconnected"),
+                    Err(e) => error!("Client failed: {}", e),
+                }
+            })?;
+    }
+}
+
+/// Sends a single command to a running instance and prints the answer.
+///
+/// Fails if the instance answers with an error.
+crate fn query(socket: &Path, cmd: &str) -> Result<(), Error> {
+    let stream = UnixStream::connect(socket)
+        .with_context(|_| format!("Failed to connect to {}", socket.display()))?;
+    let mut out = BufWriter::new(stream.try_clone()?);
+    writeln!(out, "{}", cmd)?;
+    out.flush()?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "OK" {
+            return Ok(());
+        } else if line.starts_with("ERR ") {
+            bail!("{}", &line[4..]);
+        }
+        writeln!(stdout, "{}", line)?;
+    }
+    bail!("The connection closed before the answer was complete")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fixtures::{MaildirSpec, Rng, TempDir};
+    use crate::mailbox::{MailboxBuilder, Pool, RootReport, ScanReport, MAILBOXES};
+
+    #[test]
+    fn rescan_full() {
+        assert_eq!((true, "INBOX"), rescan_args("FULL INBOX"));
+        assert_eq!((true, "my box"), rescan_args("full  my box"));
+        assert_eq!((false, "INBOX"), rescan_args("INBOX"));
+        // A mailbox may be called that too
+        assert_eq!((false, "FULL"), rescan_args("FULL"));
+        assert_eq!((false, "FULLER box"), rescan_args("FULLER box"));
+    }
+
+    /// Runs the commands as a client and returns what the client would read.
+    fn session(pool: &Pool, commands: &[&str]) -> Vec<String> {
+        let (stream, other) = UnixStream::pair().unwrap();
+        let mut client = Client {
+            output: Arc::new(Mutex::new(BufWriter::new(stream))),
+            subscribed: false,
+            normalize_names: false,
+            pool: pool.handle(),
+        };
+        for cmd in commands {
+            command(cmd, &mut client).unwrap();
+        }
+        drop(client);
+        BufReader::new(other).lines().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn list_failed_roots() {
+        let names = ["server-list-broken", "server-list-fine"];
+        for (name, root) in names.iter().zip(&["broken", "fine"]) {
+            let mbox = MailboxBuilder::new(format!("/nonexistent/mix/{}/{}", root, name))
+                .with_shortcut(if *root == "fine" { "f" } else { "b" })
+                .build()
+                .unwrap();
+            MAILBOXES.lock().insert(name.to_string(), Arc::new(mbox));
+        }
+        let mut failed = RootReport::new(PathBuf::from("/nonexistent/mix/broken"));
+        failed.failed = true;
+        *mailbox::LAST_SCAN.lock() = ScanReport {
+            roots: vec![failed, RootReport::new(PathBuf::from("/nonexistent/mix/fine"))],
+        };
+
+        let pool = Pool::empty(1).unwrap();
+        let answers = session(&pool, &["LIST"]);
+        *mailbox::LAST_SCAN.lock() = ScanReport::default();
+        for name in &names {
+            MAILBOXES.lock().remove(*name);
+        }
+        pool.shutdown(Duration::from_secs(1));
+
+        // Other tests may register their own mailboxes meanwhile
+        let ours = answers
+            .into_iter()
+            .filter(|line| names.iter().any(|name| line.starts_with(name)))
+            .collect::<Vec<_>>();
+        let expected = vec![
+            "server-list-broken\t/nonexistent/mix/broken/server-list-broken\tmbox\t0\tb\t\
+             failed-root",
+            "server-list-fine\t/nonexistent/mix/fine/server-list-fine\tmbox\t0\tf\t-",
+        ];
+        assert_eq!(expected, ours);
+    }
+
+    #[test]
+    fn version_command() {
+        let pool = Pool::empty(1).unwrap();
+        let answers = session(&pool, &["VERSION", "version x"]);
+        pool.shutdown(Duration::from_secs(1));
+        assert_eq!(3, answers.len(), "{:?}", answers);
+        let info = serde_json::from_str::<serde_json::Value>(&answers[0]).unwrap();
+        assert_eq!(version::to_json(), info);
+        assert_eq!(env!("CARGO_PKG_VERSION"), info["version"]);
+        assert_eq!("OK", answers[1]);
+        assert_eq!("ERR VERSION takes no arguments", answers[2]);
+    }
+
+    #[test]
+    fn lua_stats_command() {
+        let pool = Pool::empty(1).unwrap();
+        let answers = session(&pool, &["LUA-STATS", "lua-stats now"]);
+        pool.shutdown(Duration::from_secs(1));
+        let (last, stats) = answers.split_last().unwrap();
+        assert_eq!("ERR LUA-STATS takes no arguments", last);
+        assert_eq!("OK", stats.last().unwrap());
+        for line in &stats[..stats.len() - 1] {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            assert_eq!(3, fields.len(), "{}", line);
+            assert!(fields[1].parse::<usize>().is_ok(), "{}", line);
+            assert!(fields[2].parse::<u64>().is_ok(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rescan_command() {
+        let dir = TempDir::new();
+        let manifest = MaildirSpec::default().build(&dir.path().join("mdir"), &mut Rng::new(6));
+        // Unique among the tests, as the registered mailboxes are global
+        let name = "server-rescan-command";
+        let mbox = MailboxBuilder::new(&manifest.path)
+            .with_name(name)
+            .with_kind("maildir")
+            .with_counts(0, Some(0))
+            .build()
+            .map(Arc::new)
+            .unwrap();
+        MAILBOXES.lock().insert(name.to_owned(), Arc::clone(&mbox));
+
+        let pool = Pool::empty(1).unwrap();
+        let answers = session(&pool, &[
+            &format!("RESCAN FULL {}", name),
+            "RESCAN nonexistent-mailbox",
+            "RESCAN",
+        ]);
+        pool.handle().wait_idle();
+        MAILBOXES.lock().remove(name);
+        pool.shutdown(Duration::from_secs(1));
+
+        let expected = vec![
+            "OK".to_owned(),
+            "ERR No mailbox nonexistent-mailbox".to_owned(),
+            "ERR Usage: RESCAN [FULL] <mailbox>".to_owned(),
+        ];
+        assert_eq!(expected, answers);
+        assert_eq!((manifest.messages, Some(manifest.unseen)), mbox.counts());
+    }
+}
@@ -0,0 +1,42 @@
+//! Stopping the daemon on SIGINT and SIGTERM.
+
+use std::process;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use failure::Error;
+use log::{error, info};
+use signal_hook::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::error::EXIT_OTHER;
+
+/// Where the parts of the daemon report that it should stop, and why.
+///
+/// It's either a termination signal (`Ok`) or a failure of something essential.
+crate type Stop = Sender<Result<(), Error>>;
+
+/// Starts listening for the termination signals.
+///
+/// The first signal is sent through the returned channel. Any further one exits the process right
+/// away, for when the shutdown takes too long.
+crate fn listen() -> Result<(Stop, Receiver<Result<(), Error>>), Error> {
+    let signals = Signals::new(&[SIGINT, SIGTERM])?;
+    let (stop, stopped) = mpsc::channel();
+    let first = stop.clone();
+    thread::Builder::new()
+        .name("signals".to_owned())
+        .spawn(move || {
+            let mut signals = signals.forever();
+            if signals.next().is_some() {
+                info!("Shutting down");
+                // The daemon may be failing already, then nobody listens any more
+                let _ = first.send(Ok(()));
+            }
+            if signals.next().is_some() {
+                error!("Terminating without waiting for the shutdown to finish");
+                process::exit(EXIT_OTHER);
+            }
+        })?;
+    Ok((stop, stopped))
+}
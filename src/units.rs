@@ -0,0 +1,202 @@
+//! Quantities in the config that people like to write in a human-friendly way.
+//!
+//! Each of them accepts a bare number as well as a string with a unit.
+
+use std::fmt::{Formatter, Result as FmtResult};
+use std::time::Duration;
+
+use serde::de::{Deserialize, Deserializer, Error as DeError, Visitor};
+
+/// Splits a string like `1.5h` into the number and the unit.
+fn split_unit(s: &str) -> Result<(f64, &str), String> {
+    let s = s.trim();
+    let pos = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or_else(|| s.len());
+    let (num, unit) = s.split_at(pos);
+    let num = num
+        .parse::<f64>()
+        .map_err(|_| format!("{:?} doesn't start with a number", s))?;
+    Ok((num, unit.trim()))
+}
+
+/// Deserializes from a number or a string, by the given conversions.
+macro_rules! unit_visitor {
+    ($name: ident, $expecting: expr, $from_num: expr, $from_str: expr) => {
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct V;
+                impl<'de> Visitor<'de> for V {
+                    type Value = $name;
+                    fn expecting(&self, fmt: &mut Formatter) -> FmtResult {
+                        fmt.write_str($expecting)
+                    }
+                    fn visit_f64<E: DeError>(self, v: f64) -> Result<$name, E> {
+                        $from_num(v).map_err(E::custom)
+                    }
+                    fn visit_i64<E: DeError>(self, v: i64) -> Result<$name, E> {
+                        self.visit_f64(v as f64)
+                    }
+                    fn visit_u64<E: DeError>(self, v: u64) -> Result<$name, E> {
+                        self.visit_f64(v as f64)
+                    }
+                    fn visit_str<E: DeError>(self, v: &str) -> Result<$name, E> {
+                        $from_str(v).map_err(E::custom)
+                    }
+                }
+                deserializer.deserialize_any(V)
+            }
+        }
+    }
+}
+
+/// A duration. A bare number is in seconds, a string may use the `ms`, `s`, `m`, `h` and `d`
+/// units (eg. `90s` or `1.5h`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+crate struct DurationSpec(crate Duration);
+
+impl DurationSpec {
+    fn from_secs(secs: f64) -> Result<Self, String> {
+        // Anything from 2^64 up would saturate (or overflow when the nanoseconds carry over)
+        if secs < 0.0 || !secs.is_finite() || secs >= u64::max_value() as f64 {
+            return Err(format!("{} is not a valid duration", secs));
+        }
+        let whole = secs.trunc();
+        let nanos = ((secs - whole) * 1e9).round() as u32;
+        // Close to the next second, the rounding may carry over into it
+        let secs = whole as u64;
+        let (secs, nanos) = if nanos >= 1_000_000_000 { (secs + 1, 0) } else { (secs, nanos) };
+        Ok(DurationSpec(Duration::new(secs, nanos)))
+    }
+
+    crate fn parse(s: &str) -> Result<Self, String> {
+        let (num, unit) = split_unit(s)?;
+        let mult = match unit {
+            "ms" => 0.001,
+            "" | "s" => 1.0,
+            "m" | "min" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            _ => return Err(format!("Unknown duration unit {:?} in {:?}", unit, s)),
+        };
+        DurationSpec::from_secs(num * mult)
+    }
+}
+
+unit_visitor!(DurationSpec, "a duration, like 30 or \"90s\"", DurationSpec::from_secs,
+              DurationSpec::parse);
+
+/// A fraction between 0 and 1. It may be written either as a number (`0.3`) or in percent
+/// (`"30%"`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+crate struct Fraction(crate f64);
+
+impl Fraction {
+    fn new(v: f64) -> Result<Self, String> {
+        if v >= 0.0 && v <= 1.0 {
+            Ok(Fraction(v))
+        } else {
+            Err(format!("{} is not between 0 and 1", v))
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        match split_unit(s)? {
+            (num, "") => Fraction::new(num),
+            (num, "%") => Fraction::new(num / 100.0),
+            (_, unit) => Err(format!("Unknown fraction unit {:?} in {:?}", unit, s)),
+        }
+    }
+}
+
+unit_visitor!(Fraction, "a fraction, like 0.3 or \"30%\"", Fraction::new, Fraction::parse);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        let ok = &[
+            ("0", 0, 0),
+            ("30", 30, 0),
+            ("1.5", 1, 500_000_000),
+            (".5", 0, 500_000_000),
+            ("2.", 2, 0),
+            ("250ms", 0, 250_000_000),
+            ("1500ms", 1, 500_000_000),
+            ("90s", 90, 0),
+            ("2m", 120, 0),
+            ("2min", 120, 0),
+            ("1.5h", 5400, 0),
+            ("1d", 86400, 0),
+            (" 10 s ", 10, 0),
+            ("\t3h\n", 10800, 0),
+            ("0.000000001", 0, 1),
+            ("0.0000000004", 0, 0),
+            ("0.9999999999", 1, 0),
+            ("41.9999999999", 42, 0),
+        ];
+        for &(s, secs, nanos) in ok {
+            let expected = DurationSpec(Duration::new(secs, nanos));
+            assert_eq!(Ok(expected), DurationSpec::parse(s), "{}", s);
+        }
+        let bad = &[
+            "", "s", "-1", "-1s", "+1", "1e3", "1E3", "NaN", "inf", "1.5x", "1.2.3", "1 2", "1hh",
+            "1 H", "30%", "1e30d", "99999999999999999999",
+        ];
+        for s in bad {
+            assert!(DurationSpec::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn duration_secs() {
+        assert_eq!(Ok(DurationSpec(Duration::new(1, 0))), DurationSpec::from_secs(0.9999999999));
+        assert_eq!(Ok(DurationSpec(Duration::new(0, 999_999_999))),
+                   DurationSpec::from_secs(0.999999999));
+        for &secs in &[-1.0, -0.0000001, std::f64::NAN, std::f64::INFINITY, 1.8446744073709552e19] {
+            assert!(DurationSpec::from_secs(secs).is_err(), "{}", secs);
+        }
+    }
+
+    #[test]
+    fn fractions() {
+        let ok = &[
+            ("0", 0.0),
+            ("1", 1.0),
+            ("0.3", 0.3),
+            (".25", 0.25),
+            ("30%", 0.3),
+            ("30 %", 0.3),
+            (" 100% ", 1.0),
+            ("0%", 0.0),
+            ("12.5%", 0.125),
+        ];
+        for &(s, expected) in ok {
+            assert_eq!(Ok(Fraction(expected)), Fraction::parse(s), "{}", s);
+        }
+        let bad = &["", "%", "-1", "-10%", "1.5", "150%", "1e3", "NaN", "1.5x", "30%%", "0.3 x"];
+        for s in bad {
+            assert!(Fraction::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    /// Bare numbers in the config don't go through the parsing, but get the same checks.
+    #[test]
+    fn deserialize() {
+        let duration = |json| serde_json::from_str::<DurationSpec>(json).map_err(|_| ());
+        assert_eq!(Ok(DurationSpec(Duration::new(30, 0))), duration("30"));
+        assert_eq!(Ok(DurationSpec(Duration::new(1, 500_000_000))), duration("1.5"));
+        assert_eq!(Ok(DurationSpec(Duration::new(90, 0))), duration("\"90s\""));
+        assert!(duration("-1").is_err());
+        assert!(duration("true").is_err());
+
+        let fraction = |json| serde_json::from_str::<Fraction>(json).map_err(|_| ());
+        assert_eq!(Ok(Fraction(0.5)), fraction("0.5"));
+        assert_eq!(Ok(Fraction(1.0)), fraction("1"));
+        assert_eq!(Ok(Fraction(0.3)), fraction("\"30%\""));
+        assert!(fraction("2").is_err());
+        assert!(fraction("-0.5").is_err());
+    }
+}
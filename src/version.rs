@@ -0,0 +1,82 @@
+//! Information about how this binary was built.
+
+use serde_json::{json, Map, Value};
+
+/// The cargo features that can be turned on or off.
+const FEATURES: &[(&str, bool)] = &[
+    ("lua", cfg!(feature = "lua")),
+    ("xz", cfg!(feature = "xz")),
+    ("bzip2", cfg!(feature = "bzip2")),
+    ("zstd", cfg!(feature = "zstd")),
+];
+
+/// The build details as JSON, for the VERSION command.
+///
+/// The features are an object of name to whether it is on. The git description, rustc version
+/// and profile are `unknown` if the build couldn't find them out.
+crate fn to_json() -> Value {
+    let features = FEATURES
+        .iter()
+        .map(|(name, on)| (name.to_string(), Value::Bool(*on)))
+        .collect::<Map<_, _>>();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": features,
+        "git": env!("MIX_BUILD_GIT"),
+        "rustc": env!("MIX_BUILD_RUSTC"),
+        "profile": env!("MIX_BUILD_PROFILE"),
+    })
+}
+
+/// Prints the version, with the build details if asked for.
+crate fn print(verbose: bool) {
+    println!("mix {}", env!("CARGO_PKG_VERSION"));
+    if !verbose {
+        return;
+    }
+    let features = FEATURES
+        .iter()
+        .map(|(name, on)| format!("{}{}", if *on { '+' } else { '-' }, name))
+        .collect::<Vec<_>>();
+    println!("features: {}", features.join(" "));
+    println!("git: {}", env!("MIX_BUILD_GIT"));
+    println!("rustc: {}", env!("MIX_BUILD_RUSTC"));
+    println!("profile: {}", env!("MIX_BUILD_PROFILE"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn populated() {
+        let info = to_json();
+        assert_eq!(env!("CARGO_PKG_VERSION"), info["version"]);
+        assert!(!info["version"].as_str().unwrap().is_empty());
+        for (name, on) in FEATURES {
+            assert_eq!(*on, info["features"][name], "{}", name);
+        }
+        assert_eq!(cfg!(feature = "lua"), info["features"]["lua"]);
+        // These may be unknown, but never missing or empty
+        for field in &["git", "rustc", "profile"] {
+            assert!(!info[field].as_str().unwrap().is_empty(), "{}", field);
+        }
+        assert!(info["profile"] == "debug" || info["profile"] == "release", "{}", info);
+    }
+
+    /// The keys come sorted, so the output doesn't change between runs or builds.
+    #[test]
+    fn stable() {
+        let info = to_json();
+        let field = |name: &str| serde_json::to_string(&info[name]).unwrap();
+        let expected = format!(
+            "{{\"features\":{{\"bzip2\":{},\"lua\":{},\"xz\":{},\"zstd\":{}}},\"git\":{},\
+             \"profile\":{},\"rustc\":{},\"version\":{}}}",
+            cfg!(feature = "bzip2"), cfg!(feature = "lua"), cfg!(feature = "xz"),
+            cfg!(feature = "zstd"), field("git"), field("profile"), field("rustc"),
+            field("version"),
+        );
+        assert_eq!(expected, info.to_string());
+        assert_eq!(info.to_string(), to_json().to_string());
+    }
+}